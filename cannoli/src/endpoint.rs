@@ -0,0 +1,333 @@
+//! Addresses and streams used for the metadata connection between the jitter
+//! running inside of QEMU and the Cannoli server
+
+use std::io::{Read, Write, ErrorKind};
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// Address used by both the server and the jitter when nothing else was
+/// requested
+pub const DEFAULT_SERVER: &str = "127.0.0.1:11458";
+
+/// Environment variable holding the [`Endpoint`] to use, in the format
+/// accepted by [`Endpoint::parse`]
+pub const SERVER_ENV: &str = "CANNOLI_SERVER";
+
+/// Prefix used to select a Unix domain socket when parsing an [`Endpoint`]
+const UNIX_PREFIX: &str = "unix:";
+
+/// Address of a Cannoli server
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    /// A TCP `host:port` address
+    Tcp(String),
+
+    /// Path to a Unix domain socket
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// Parse an endpoint from a string. Strings starting with `unix:` are
+    /// treated as a path to a Unix domain socket, anything else is treated as
+    /// a TCP `host:port`
+    pub fn parse(endpoint: &str) -> Self {
+        match endpoint.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Endpoint::Unix(path.into()),
+            None       => Endpoint::Tcp(endpoint.into()),
+        }
+    }
+
+    /// Get the endpoint from the [`SERVER_ENV`] environment variable, falling
+    /// back to [`DEFAULT_SERVER`] if it is not set
+    pub fn from_env() -> Self {
+        std::env::var(SERVER_ENV).map(|x| Self::parse(&x))
+            .unwrap_or_else(|_| Self::parse(DEFAULT_SERVER))
+    }
+
+    /// Connect to a server listening on this endpoint
+    pub fn connect(&self) -> std::io::Result<Stream> {
+        Ok(match self {
+            Endpoint::Tcp(addr)  => Stream::Tcp(TcpStream::connect(addr)?),
+            Endpoint::Unix(path) => Stream::Unix(UnixStream::connect(path)?),
+        })
+    }
+
+    /// Start listening on this endpoint
+    ///
+    /// For Unix domain sockets, a stale socket left behind at the path by a
+    /// previous server is removed first. Anything at the path which is not a
+    /// socket is left alone, and binding will fail.
+    pub(crate) fn bind(&self) -> std::io::Result<Listener> {
+        Ok(match self {
+            Endpoint::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+            Endpoint::Unix(path) => {
+                // Remove an old socket if there is one
+                if std::fs::symlink_metadata(path)
+                        .map(|x| x.file_type().is_socket()).unwrap_or(false) {
+                    std::fs::remove_file(path)?;
+                }
+
+                Listener::Unix(UnixListener::bind(path)?)
+            }
+        })
+    }
+}
+
+impl From<&str> for Endpoint {
+    fn from(val: &str) -> Self {
+        Self::parse(val)
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(addr)  => write!(f, "{addr}"),
+            Endpoint::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+/// A listening socket for either kind of [`Endpoint`]
+pub(crate) enum Listener {
+    /// Listening on TCP
+    Tcp(TcpListener),
+
+    /// Listening on a Unix domain socket
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Set the listener to nonblocking mode
+    pub(crate) fn set_nonblocking(&self, nonblocking: bool)
+            -> std::io::Result<()> {
+        match self {
+            Listener::Tcp(listener)  => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Wait for a new client to connect
+    pub(crate) fn accept(&self) -> std::io::Result<Stream> {
+        Ok(match self {
+            Listener::Tcp(listener)  => Stream::Tcp(listener.accept()?.0),
+            Listener::Unix(listener) => Stream::Unix(listener.accept()?.0),
+        })
+    }
+}
+
+/// A connected stream between the jitter and the server
+#[derive(Debug)]
+pub enum Stream {
+    /// Connected over TCP
+    Tcp(TcpStream),
+
+    /// Connected over a Unix domain socket
+    Unix(UnixStream),
+}
+
+impl Stream {
+    /// Create a new handle to the same underlying socket
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(match self {
+            Stream::Tcp(stream)  => Stream::Tcp(stream.try_clone()?),
+            Stream::Unix(stream) => Stream::Unix(stream.try_clone()?),
+        })
+    }
+
+    /// Set the socket to nonblocking mode
+    pub fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream)  => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Set how long reads on the socket may block before failing with
+    /// [`ErrorKind::WouldBlock`] or [`ErrorKind::TimedOut`], `None` blocks
+    /// forever
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>)
+            -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream)  => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    /// Check if the other side is still connected, without blocking. The
+    /// stream must be in nonblocking mode. Anything the other side sent is
+    /// discarded, and a reset connection counts as disconnected.
+    pub(crate) fn is_connected(&self) -> bool {
+        // Scratch buffer to check for socket close
+        let mut scratch_buffer = [0u8; 1];
+
+        // Attempt to read, `Read` is implemented for shared references to
+        // sockets so we can check from multiple threads at once
+        let result = match self {
+            Stream::Tcp(stream) => {
+                let mut stream: &TcpStream = stream;
+                stream.read(&mut scratch_buffer)
+            }
+            Stream::Unix(stream) => {
+                let mut stream: &UnixStream = stream;
+                stream.read(&mut scratch_buffer)
+            }
+        };
+
+        match result {
+            Ok(bytes) => bytes != 0,
+            Err(err)  => matches!(err.kind(),
+                ErrorKind::WouldBlock | ErrorKind::Interrupted),
+        }
+    }
+
+    /// Send all of `data`, passing a duplicate of `fd` to the other side
+    /// along with it using `SCM_RIGHTS`. This is only possible on Unix domain
+    /// sockets.
+    pub fn send_with_fd(&mut self, data: &[u8], fd: RawFd)
+            -> std::io::Result<()> {
+        // File descriptors can only be passed over Unix domain sockets
+        let Stream::Unix(stream) = self else {
+            return Err(std::io::Error::new(ErrorKind::Unsupported,
+                "File descriptors can only be sent over Unix sockets"));
+        };
+
+        // Storage for the control message, aligned for a `cmsghdr`
+        let mut control = [0u64; 4];
+
+        // Point the message at our data and control message
+        let mut iov = libc::iovec {
+            iov_base: data.as_ptr() as *mut _,
+            iov_len:  data.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov        = &mut iov;
+        msg.msg_iovlen     = 1;
+        msg.msg_control    = control.as_mut_ptr() as *mut _;
+        msg.msg_controllen =
+            unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) } as _;
+
+        // Fill in the `SCM_RIGHTS` control message holding the FD
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type  = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len   =
+                libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+            (libc::CMSG_DATA(cmsg) as *mut RawFd).write_unaligned(fd);
+        }
+
+        // Send the message, the FD goes along with the first byte
+        let sent = unsafe {
+            libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+        };
+        if sent < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        // Send whatever didn't fit in the first message
+        stream.write_all(&data[sent as usize..])
+    }
+
+    /// Fill all of `buf`, returning the file descriptor if one was passed to
+    /// us with the data via [`Stream::send_with_fd`]. File descriptors are
+    /// only ever received on Unix domain sockets.
+    pub(crate) fn recv_with_fd(&mut self, buf: &mut [u8])
+            -> std::io::Result<Option<OwnedFd>> {
+        // Nothing can be passed over TCP, just read the data
+        let stream = match self {
+            Stream::Tcp(stream) => return stream.read_exact(buf).map(|_| None),
+            Stream::Unix(stream) => stream,
+        };
+
+        // Storage for the control message, aligned for a `cmsghdr`
+        let mut control = [0u64; 4];
+
+        // Point the message at our buffer and control message storage
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut _,
+            iov_len:  buf.len(),
+        };
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_iov        = &mut iov;
+        msg.msg_iovlen     = 1;
+        msg.msg_control    = control.as_mut_ptr() as *mut _;
+        msg.msg_controllen = std::mem::size_of_val(&control) as _;
+
+        // Receive the first part of the message, which carries the FD
+        let received = unsafe {
+            libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC)
+        };
+        if received < 0 {
+            return Err(std::io::Error::last_os_error());
+        } else if received == 0 && !buf.is_empty() {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+
+        // Take ownership of all FDs we got, so none of them leak, keeping
+        // only the first one
+        let mut fd = None;
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET &&
+                        (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    // Get the number of FDs in this message
+                    let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                    let num_fds = ((*cmsg).cmsg_len as usize -
+                        (data as usize - cmsg as usize)) / size_of::<RawFd>();
+
+                    for ii in 0..num_fds {
+                        let owned =
+                            OwnedFd::from_raw_fd(data.add(ii).read_unaligned());
+                        fd.get_or_insert(owned);
+                    }
+                }
+
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+
+        // If the control message didn't fit, the kernel dropped the FDs
+        // which didn't fit in it
+        if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(std::io::Error::new(ErrorKind::InvalidData,
+                "Control message was truncated, a file descriptor was lost"));
+        }
+
+        // Read the rest of the data
+        stream.read_exact(&mut buf[received as usize..])?;
+
+        Ok(fd)
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream)  => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream)  => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream)  => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
@@ -3,16 +3,40 @@
 
 #![feature(array_chunks, once_cell)]
 
-use std::io::Read;
-use std::any::Any;
+use std::any::{Any, TypeId};
 use std::ffi::CStr;
-use std::mem::{size_of, MaybeUninit};
-use std::net::{TcpListener, TcpStream};
+use std::mem::size_of;
+use std::os::unix::io::{IntoRawFd, OwnedFd};
 use std::sync::{Arc, Mutex, LazyLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Instant, Duration};
+use std::path::Path;
 use std::collections::HashMap;
 use mempipe::RecvPipe;
 
+mod endpoint;
+mod server;
+mod merge;
+pub mod wire;
+pub mod record;
+pub mod regs;
+#[cfg(target_os = "linux")]
+pub mod loopback;
+
+pub use endpoint::{Endpoint, Stream, DEFAULT_SERVER, SERVER_ENV};
+pub use server::{CannoliServer, ErrorCallback, ServerHandle, SessionResult};
+pub use server::SessionCallback;
+
+use record::{Block, Recorder};
+use merge::Merger;
+
+pub use record::TraceReader;
+pub use regs::{RegisterFile, RegisterGroup, RegisterSections, RegisterDeltas};
+#[cfg(target_os = "linux")]
+pub use record::replay;
+
+use wire::{Event, EventIter};
+
 /// Wrapper around [`Error`]
 type Result<T> = std::result::Result<T, Error>;
 
@@ -28,12 +52,61 @@ pub enum Error {
     /// Failed to clone a socket for multiple threads
     CloneSocket(std::io::Error),
 
+    /// Failed to accept a new connection from a client
+    Accept(std::io::Error),
+
+    /// Failed to perform the initial handshake with a client, it either
+    /// disconnected or sent something which isn't a Cannoli handshake
+    Handshake(std::io::Error),
+
+    /// The client did not start the handshake with [`HANDSHAKE_MAGIC`], it's
+    /// not a Cannoli client or is too old to send a [`Hello`]
+    BadMagic(u32),
+
+    /// The client speaks a different version of the protocol than
+    /// [`PROTOCOL_VERSION`], the jitter and the server have to be rebuilt
+    /// from the same version of Cannoli
+    ProtocolVersion(u32),
+
+    /// The client's [`ClientConn`] was a different size than ours
+    HeaderLength(u32),
+
+    /// The client requested capabilities the server doesn't support, holds
+    /// the unsupported capability bits
+    UnsupportedCapabilities(u32),
+
+    /// The client reported an architecture ID we don't know about, without
+    /// giving its name
+    UnknownArchitecture(i32),
+
     /// Failed to open IPC pipe with QEMU
     OpenPipe(mempipe::Error),
 
+    /// The client said it sent the pipe's file descriptor over the socket,
+    /// but no file descriptor was received
+    MissingPipeFd,
+
+    /// The client requested a [`Transport`] we don't know about
+    InvalidTransport(u32),
+
     /// Failed to `join()` with a thread we created
     JoinThread,
 
+    /// Failed to record a trace to disk
+    Record(std::io::Error),
+
+    /// A file is not a trace file in a format we understand
+    TraceFormat,
+
+    /// Failed to set up the pipe for a [`loopback::Loopback`] session
+    Loopback(std::io::Error),
+
+    /// A chunk was larger than the pipe's chunks, holds the chunk's size
+    ChunkTooLarge(usize),
+
+    /// The session a chunk was sent to already ended
+    SessionEnded,
+
     /// A buffer received through IPC didn't have as many bytes in it as were
     /// needed to deserialize it
     BufferTruncated,
@@ -41,6 +114,13 @@ pub enum Error {
     /// An invalid opcode was encountered in the data stream
     InvalidOpcode(u8),
 
+    /// A register groups event held a section of an unknown group
+    InvalidRegisterGroup(u8),
+
+    /// A register delta event didn't apply to the register state of its
+    /// chunk, or came before the snapshot
+    InvalidRegisterDelta,
+
     /// Getting the path for mmap() did not contain valid UTF-8 characters
     PathEncoding(std::str::Utf8Error),
 }
@@ -51,7 +131,137 @@ const CHUNK_SIZE: usize = 256 * 1024;
 /// Number of chunks to use with IPC
 const NUM_BUFFERS: usize = 16;
 
-/// Header sent when a client connects
+/// Magic value starting every handshake, `b"CNLI"` in little endian
+pub const HANDSHAKE_MAGIC: u32 = u32::from_le_bytes(*b"CNLI");
+
+/// Version of the protocol spoken between the jitter and the server, this
+/// covers the handshake and the format of the trace. Bump this whenever
+/// either of them changes in an incompatible way.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+/// Capability bit indicating the client may emit memory access opcodes
+pub const CAP_MEMORY: u32 = 1 << 0;
+
+/// Capability bit indicating the client may emit register opcodes
+pub const CAP_REGISTERS: u32 = 1 << 1;
+
+/// Capability bit indicating the client may emit `mmap()` and `munmap()`
+/// opcodes
+pub const CAP_MAPPINGS: u32 = 1 << 2;
+
+/// Capability bit indicating the client may emit host timestamp opcodes
+pub const CAP_TIMESTAMPS: u32 = 1 << 3;
+
+/// Capability bit indicating the client stamps its chunks with epoch opcodes
+pub const CAP_EPOCHS: u32 = 1 << 4;
+
+/// Capability bit indicating the client may emit `clone()` and `execve()`
+/// opcodes
+pub const CAP_PROCESSES: u32 = 1 << 5;
+
+/// Capability bit indicating the client may emit syscall opcodes
+pub const CAP_SYSCALLS: u32 = 1 << 6;
+
+/// Capability bit indicating the client may emit signal and `sigreturn()`
+/// opcodes
+pub const CAP_SIGNALS: u32 = 1 << 7;
+
+/// Capability bit indicating the client may emit `mprotect()` and `brk()`
+/// opcodes
+pub const CAP_ADDRESS_SPACE: u32 = 1 << 8;
+
+/// Capability bit indicating the client sends a snapshot of the mappings of
+/// its process when the process connects
+pub const CAP_MAP_SNAPSHOTS: u32 = 1 << 9;
+
+/// Capability bit indicating the client may emit register groups opcodes
+pub const CAP_REGISTER_GROUPS: u32 = 1 << 10;
+
+/// Capability bit indicating the client may emit register delta opcodes
+pub const CAP_REGISTER_DELTAS: u32 = 1 << 11;
+
+/// Capability bit indicating the client may emit syscall entry opcodes
+pub const CAP_SYSCALL_ENTRIES: u32 = 1 << 12;
+
+/// Capability bit indicating the client emits a host timestamp opcode right
+/// before every register and memory opcode
+pub const CAP_EVENT_TIMESTAMPS: u32 = 1 << 13;
+
+/// All capabilities this version of the server understands
+pub const SUPPORTED_CAPABILITIES: u32 = CAP_MEMORY | CAP_REGISTERS |
+    CAP_MAPPINGS | CAP_TIMESTAMPS | CAP_EPOCHS | CAP_PROCESSES | CAP_SYSCALLS |
+    CAP_SIGNALS | CAP_ADDRESS_SPACE | CAP_MAP_SNAPSHOTS | CAP_REGISTER_GROUPS |
+    CAP_REGISTER_DELTAS | CAP_SYSCALL_ENTRIES | CAP_EVENT_TIMESTAMPS;
+
+/// Environment variable which, when set, makes the jitter stamp every chunk
+/// of trace with the host's TSC, see [`Cannoli::time`]
+pub const TIMESTAMPS_ENV: &str = "CANNOLI_TIMESTAMPS";
+
+/// Environment variable which, when set, makes the jitter stamp every register
+/// and memory event with the host's TSC, see [`Cannoli::time`]
+pub const EVENT_TIMESTAMPS_ENV: &str = "CANNOLI_EVENT_TIMESTAMPS";
+
+/// First message sent when a client connects, followed by a [`ClientConn`]
+///
+/// Must only contain plain-old-data, and the fields before `capabilities`
+/// must never change, so mismatched clients and servers can always detect
+/// each other
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct Hello {
+    /// Always [`HANDSHAKE_MAGIC`]
+    pub magic: u32,
+
+    /// The [`PROTOCOL_VERSION`] the client speaks
+    pub version: u32,
+
+    /// Size of the [`ClientConn`] following this message, in bytes
+    pub header_len: u32,
+
+    /// `CAP_*` bits describing what the client may emit in its trace
+    pub capabilities: u32,
+
+    /// The `CANNOLI_VERSION` from the FFI header the jitter was built with,
+    /// identifies the QEMU patches it works with
+    pub cannoli_version: u64,
+}
+
+impl Hello {
+    /// Create a new hello for this version of the protocol
+    pub fn new(capabilities: u32, cannoli_version: u64) -> Self {
+        Self {
+            magic:      HANDSHAKE_MAGIC,
+            version:    PROTOCOL_VERSION,
+            header_len: size_of::<ClientConn>() as u32,
+            capabilities,
+            cannoli_version,
+        }
+    }
+
+    /// Make sure a client sending this hello is compatible with us
+    pub fn validate(&self) -> Result<()> {
+        if self.magic != HANDSHAKE_MAGIC {
+            return Err(Error::BadMagic(self.magic));
+        }
+
+        if self.version != PROTOCOL_VERSION {
+            return Err(Error::ProtocolVersion(self.version));
+        }
+
+        if self.header_len as usize != size_of::<ClientConn>() {
+            return Err(Error::HeaderLength(self.header_len));
+        }
+
+        let unsupported = self.capabilities & !SUPPORTED_CAPABILITIES;
+        if unsupported != 0 {
+            return Err(Error::UnsupportedCapabilities(unsupported));
+        }
+
+        Ok(())
+    }
+}
+
+/// Header sent when a client connects, after the [`Hello`]
 ///
 /// Must only contain plain-old-data otherwise you will break the unsafe
 /// serialization and deserialization we use :)
@@ -63,7 +273,7 @@ pub struct ClientConn {
     /// UID for the pipe
     pub uid: u64,
 
-    /// Architecture
+    /// Architecture, its [`Architecture::id`]
     pub arch: i32,
 
     /// Big endian flag
@@ -83,21 +293,98 @@ pub struct ClientConn {
 
     /// Length of the comm (in bytes)
     pub comm_len: u32,
+
+    /// How the pipe is shared with the server, a [`Transport`] as a `u32`
+    pub transport: u32,
+
+    /// The process's epoch when the thread connected
+    pub epoch: u64,
+
+    /// Thread ID of the thread which created this thread or process, zero if
+    /// it's not known
+    pub parent_tid: i32,
+
+    /// Length of the path of the guest's executable (in bytes)
+    pub exe_len: u32,
+
+    /// Length of the guest's arguments (in bytes), each is terminated by a
+    /// NUL byte
+    pub argv_len: u32,
+
+    /// Length of the guest's environment (in bytes), each variable is
+    /// terminated by a NUL byte
+    pub envp_len: u32,
+
+    /// `AT_ENTRY` from the guest's auxiliary vector
+    pub at_entry: u64,
+
+    /// `AT_PHDR` from the guest's auxiliary vector
+    pub at_phdr: u64,
+
+    /// `AT_BASE` from the guest's auxiliary vector
+    pub at_base: u64,
+
+    /// Width of each general purpose register of the guest (in bytes)
+    pub gpr_width: u32,
+
+    /// Number of general purpose registers sent with register hooks
+    pub num_gprs: u32,
+
+    /// Byte offset of the general purpose registers in QEMU's CPU state
+    pub gpr_offset: u64,
+
+    /// Length of the architecture's `UNAME_MACHINE` (in bytes), which names
+    /// it if its ID is one we don't know about
+    pub arch_len: u32,
+
+    /// Unused, keeps the header free of padding bytes
+    pub reserved: u32,
+}
+
+/// The ways a client can share its IPC pipe with the server
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    /// The pipe lives in named shared memory, which the server opens using
+    /// [`ClientConn::uid`]
+    NamedShm = 0,
+
+    /// The pipe is an anonymous `memfd_create` file, whose file descriptor is
+    /// passed along with the [`ClientConn`] over a Unix domain socket using
+    /// `SCM_RIGHTS`. This is used automatically when the [`Endpoint`] is a
+    /// Unix domain socket.
+    Memfd = 1,
+}
+
+impl TryFrom<u32> for Transport {
+    type Error = Error;
+
+    fn try_from(val: u32) -> Result<Self> {
+        match val {
+            0 => Ok(Self::NamedShm),
+            1 => Ok(Self::Memfd),
+            _ => Err(Error::InvalidTransport(val)),
+        }
+    }
 }
 
 /// Different QEMU target architectures
-#[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+///
+/// Targets Cannoli doesn't know about are [`Architecture::Unknown`], with the
+/// `UNAME_MACHINE` QEMU reported for them, and have no metadata
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Architecture {
     Aarch64,
     Aarch64be,
     Alpha,
     Armv5teb,
     Armv5tel,
+    Avr,
     Cris,
     Hexagon,
     I386,
     I686,
+    Loongarch64,
     M68k,
     Microblaze,
     Mips,
@@ -110,290 +397,265 @@ pub enum Architecture {
     Ppc64le,
     Riscv32,
     Riscv64,
+    Rx,
     S390x,
     Sh4,
+    Sh4eb,
     Sparc,
     Sparc64,
+    Tricore,
     X86_64,
     Xtensa,
+
+    /// An architecture we don't know about, holding its `UNAME_MACHINE`
+    Unknown(String),
 }
 
-impl From<i32> for Architecture {
-    fn from(val: i32) -> Self {
-        match val {
-             0 => Self::Aarch64,
-             1 => Self::Aarch64be,
-             2 => Self::Alpha,
-             3 => Self::Armv5teb,
-             4 => Self::Armv5tel,
-             5 => Self::Cris,
-             6 => Self::Hexagon,
-             7 => Self::I386,
-             8 => Self::I686,
-             9 => Self::M68k,
-            10 => Self::Microblaze,
-            11 => Self::Mips,
-            12 => Self::Mips64,
-            13 => Self::Nios2,
-            14 => Self::Openrisc,
-            15 => Self::Parisc,
-            16 => Self::Ppc,
-            17 => Self::Ppc64,
-            18 => Self::Ppc64le,
-            19 => Self::Riscv32,
-            20 => Self::Riscv64,
-            21 => Self::S390x,
-            22 => Self::Sh4,
-            23 => Self::Sparc,
-            24 => Self::Sparc64,
-            25 => Self::X86_64,
-            26 => Self::Xtensa,
-            _  => panic!("Cannoli: Unhandled architecture ID {}", val),
-        }
+/// Metadata about a known [`Architecture`]
+struct ArchInfo {
+    /// ID of the architecture in the handshake
+    id: i32,
+
+    /// `UNAME_MACHINE` QEMU reports for the architecture
+    name: &'static str,
+
+    /// Width of a pointer, in bytes
+    pointer_width: usize,
+
+    /// Set if the architecture is big-endian by default
+    big_endian: bool,
+
+    /// Alignment of instructions, in bytes
+    inst_align: usize,
+}
+
+impl TryFrom<i32> for Architecture {
+    type Error = Error;
+
+    fn try_from(val: i32) -> Result<Self> {
+        Self::KNOWN.iter().find(|x| x.id() == val).cloned()
+            .ok_or(Error::UnknownArchitecture(val))
     }
 }
 
 impl Architecture {
+    /// All the architectures we know about
+    pub const KNOWN: &'static [Self] = &[
+        Self::Aarch64, Self::Aarch64be, Self::Alpha, Self::Armv5teb,
+        Self::Armv5tel, Self::Avr, Self::Cris, Self::Hexagon, Self::I386,
+        Self::I686, Self::Loongarch64, Self::M68k, Self::Microblaze,
+        Self::Mips, Self::Mips64, Self::Nios2, Self::Openrisc, Self::Parisc,
+        Self::Ppc, Self::Ppc64, Self::Ppc64le, Self::Riscv32, Self::Riscv64,
+        Self::Rx, Self::S390x, Self::Sh4, Self::Sh4eb, Self::Sparc,
+        Self::Sparc64, Self::Tricore, Self::X86_64, Self::Xtensa,
+    ];
+
+    /// Get the metadata of the architecture, `None` if it's unknown
+    fn info(&self) -> Option<ArchInfo> {
+        // Helper to build the metadata
+        let info = |id, name, pointer_width, big_endian, inst_align| {
+            Some(ArchInfo { id, name, pointer_width, big_endian, inst_align })
+        };
+
+        // IDs are part of the protocol, new architectures get new IDs
+        match self {
+            Self::Aarch64     => info( 0, "aarch64",     8, false, 4),
+            Self::Aarch64be   => info( 1, "aarch64_be",  8, true,  4),
+            Self::Alpha       => info( 2, "alpha",       8, false, 4),
+            Self::Armv5teb    => info( 3, "armv5teb",    4, true,  2),
+            Self::Armv5tel    => info( 4, "armv5tel",    4, false, 2),
+            Self::Cris        => info( 5, "cris",        4, false, 2),
+            Self::Hexagon     => info( 6, "hexagon",     4, false, 4),
+            Self::I386        => info( 7, "i386",        4, false, 1),
+            Self::I686        => info( 8, "i686",        4, false, 1),
+            Self::M68k        => info( 9, "m68k",        4, true,  2),
+            Self::Microblaze  => info(10, "microblaze",  4, true,  4),
+            Self::Mips        => info(11, "mips",        4, true,  2),
+            Self::Mips64      => info(12, "mips64",      8, true,  2),
+            Self::Nios2       => info(13, "nios2",       4, false, 4),
+            Self::Openrisc    => info(14, "openrisc",    4, true,  4),
+            Self::Parisc      => info(15, "parisc",      4, true,  4),
+            Self::Ppc         => info(16, "ppc",         4, true,  4),
+            Self::Ppc64       => info(17, "ppc64",       8, true,  4),
+            Self::Ppc64le     => info(18, "ppc64le",     8, false, 4),
+            Self::Riscv32     => info(19, "riscv32",     4, false, 2),
+            Self::Riscv64     => info(20, "riscv64",     8, false, 2),
+            Self::S390x       => info(21, "s390x",       8, true,  2),
+            Self::Sh4         => info(22, "sh4",         4, false, 2),
+            Self::Sparc       => info(23, "sparc",       4, true,  4),
+            Self::Sparc64     => info(24, "sparc64",     8, true,  4),
+            Self::X86_64      => info(25, "x86_64",      8, false, 1),
+            Self::Xtensa      => info(26, "xtensa",      4, false, 1),
+            Self::Avr         => info(27, "avr",         2, false, 2),
+            Self::Loongarch64 => info(28, "loongarch64", 8, false, 4),
+            Self::Rx          => info(29, "rx",          4, false, 1),
+            Self::Sh4eb       => info(30, "sh4eb",       4, true,  2),
+            Self::Tricore     => info(31, "tricore",     4, false, 2),
+            Self::Unknown(_)  => None,
+        }
+    }
+
+    /// Get the [`Architecture`] for a `UNAME_MACHINE`, which is
+    /// [`Architecture::Unknown`] if we don't know about it
+    pub fn from_name(name: &str) -> Self {
+        Self::KNOWN.iter().find(|x| x.name() == name).cloned()
+            .unwrap_or_else(|| Self::Unknown(name.to_string()))
+    }
+
     /// Convert a `UNAME_MACHINE` C-string to an [`Architecture`]
     ///
     /// # Safety
     ///
     /// Expects a pointer to a valid null-terminatedf string
     pub unsafe fn from_cstr(arch: *const i8) -> Self {
-        let arch = CStr::from_ptr(arch).to_str().expect(
-            "Cannoli: Invalid string passed to Architecture::from_cstr()");
-        match arch {
-            "aarch64"    => Architecture::Aarch64,
-            "aarch64_be" => Architecture::Aarch64be,
-            "alpha"      => Architecture::Alpha,
-            "armv5teb"   => Architecture::Armv5teb,
-            "armv5tel"   => Architecture::Armv5tel,
-            "cris"       => Architecture::Cris,
-            "hexagon"    => Architecture::Hexagon,
-            "i386"       => Architecture::I386,
-            "i686"       => Architecture::I686,
-            "m68k"       => Architecture::M68k,
-            "microblaze" => Architecture::Microblaze,
-            "mips"       => Architecture::Mips,
-            "mips64"     => Architecture::Mips64,
-            "nios2"      => Architecture::Nios2,
-            "openrisc"   => Architecture::Openrisc,
-            "parisc"     => Architecture::Parisc,
-            "ppc"        => Architecture::Ppc,
-            "ppc64"      => Architecture::Ppc64,
-            "ppc64le"    => Architecture::Ppc64le,
-            "riscv32"    => Architecture::Riscv32,
-            "riscv64"    => Architecture::Riscv64,
-            "s390x"      => Architecture::S390x,
-            "sh4"        => Architecture::Sh4,
-            "sparc"      => Architecture::Sparc,
-            "sparc64"    => Architecture::Sparc64,
-            "x86_64"     => Architecture::X86_64,
-            "xtensa"     => Architecture::Xtensa,
-            _ => panic!("Cannoli: Unhandled architecture name {}", arch),
-        }
+        Self::from_name(&CStr::from_ptr(arch).to_string_lossy())
     }
-}
 
-/// Gross macro to deserialize multiple plain-old-data types into a tuple
-/// with only one length check.
-///
-/// This is only safe if you pass in plain-old-data types.
-macro_rules! consume {
-    ($payload:expr, $($ty:ty),+) => {{
-        // Get the size of the payload to deserialize, in bytes
-        const OP_SIZE: usize = $(
-            size_of::<$ty>() +
-        )+ 0;
-
-        // Get pointer to payload
-        let ptr = $payload.as_ptr();
-
-        // Advance payload pointer, also performs the length check
-        $payload = $payload.get(OP_SIZE..)
-            .ok_or_else(|| Error::BufferTruncated)?;
-
-        // Create a temporary value tracking how many bytes we've read so
-        // far
-        let mut _tmp = 0;
-
-        // Ignore clippy here, I don't know if there's a better way to
-        // express what we're doing to the compiler in this case
-        #[allow(clippy::mixed_read_write_in_expression)]
-        unsafe {
-            (
-                // For each requested type, read it!
-                $(
-                    core::ptr::read_unaligned(
-                        ptr.offset({
-                            // Save off the current offset
-                            let x = _tmp;
-
-                            // Advance the index
-                            _tmp += size_of::<$ty>() as isize;
-
-                            // Return the offset to read from
-                            x
-                        }) as *const $ty),
-                )+
-            )
+    /// ID of the architecture in the handshake, `-1` if it's unknown
+    pub fn id(&self) -> i32 {
+        self.info().map_or(-1, |x| x.id)
+    }
+
+    /// `UNAME_MACHINE` of the architecture
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Unknown(name) => name,
+            _ => self.info().map_or("", |x| x.name),
         }
-    }}
+    }
+
+    /// Width of a pointer, in bytes
+    pub fn pointer_width(&self) -> Option<usize> {
+        self.info().map(|x| x.pointer_width)
+    }
+
+    /// Returns `Some(true)` if the architecture is big-endian by default.
+    /// Some targets can be built either way, [`ClientInfo::big_endian`] is
+    /// what the client is actually running
+    pub fn big_endian(&self) -> Option<bool> {
+        self.info().map(|x| x.big_endian)
+    }
+
+    /// Alignment of instructions, in bytes. This is the smallest alignment
+    /// an instruction can have, like 2 for ARM with Thumb or RISC-V with
+    /// compressed instructions
+    pub fn inst_align(&self) -> Option<usize> {
+        self.info().map(|x| x.inst_align)
+    }
 }
 
 /// Given a payload of bytes that came from the IPC channel, deserialize it and
-/// invoke callbacks based on the payload
+/// invoke callbacks based on the payload. The TIDs of the threads the payload
+/// reports creating are put in `threads`
 fn parse_payload<T: Cannoli>(pid: &T::PidContext, tid: &T::TidContext,
-        trace: &mut Vec<T::Trace>, mut payload: &[u8]) -> Result<()> {
-    // Clear the trace
+        trace: &mut Vec<T::Trace>, threads: &mut Vec<i32>, payload: &[u8])
+        -> Result<Option<u64>> {
+    // Clear the trace and the threads
     trace.clear();
-
-    // Parse the payload while there's more data
-    while !payload.is_empty() {
-        // Get the opcode
-        let op: u8 = consume!(payload, u8).0;
-
-        // Handle each opcode
-        match op {
-            0x00 => { // Exec32
-                T::exec(pid, tid, consume!(payload, u32).0 as u64, trace)
-            },
-            0x80 => { // Exec64
-                T::exec(pid, tid, consume!(payload, u64).0, trace)
-            },
-
-            0x01 => { // Regs32
-                let size = consume!(payload, u32).0;
-                let pc   = consume!(payload, u32).0 as u64;
-                let regs = &payload[..size as usize];
-                payload = &payload[size as usize..];
-                T::regs(pid, tid, pc, regs, trace)
-            },
-            0x81 => { // Regs64
-                let size = consume!(payload, u32).0;
-                let pc   = consume!(payload, u64).0;
-                let regs = &payload[..size as usize];
-                payload = &payload[size as usize..];
-                T::regs(pid, tid, pc, regs, trace)
-            },
-
-            0x30 => { // Mmap32
-                let (addr, len, anon, read, write, exec, path_len, offset) =
-                    consume!(payload, u32, u32, u8, u8, u8, u8, u32, u32);
-                let path = core::str::from_utf8(
-                    payload.get(..path_len as usize)
-                    .ok_or(Error::BufferTruncated)?)
-                    .map_err(Error::PathEncoding)?;
-                payload = &payload[path_len as usize..];
-
-                T::mmap(pid, tid, addr as u64, len as u64, anon != 0, read != 0,
-                    write != 0, exec != 0, path, offset as u64, trace)
-            },
-            0x31 => { // Munmap32
-                let (addr, len) = consume!(payload, u32, u32);
-                T::munmap(pid, tid, addr as u64, len as u64, trace)
-            },
-            0xb0 => { // Mmap64
-                let (addr, len, anon, read, write, exec, path_len, offset) =
-                    consume!(payload, u64, u64, u8, u8, u8, u8, u32, u64);
-                let path = core::str::from_utf8(
-                    payload.get(..path_len as usize)
-                    .ok_or(Error::BufferTruncated)?)
-                    .map_err(Error::PathEncoding)?;
-                payload = &payload[path_len as usize..];
-
-                T::mmap(pid, tid, addr, len, anon != 0, read != 0,
-                    write != 0, exec != 0, path, offset, trace)
-            },
-            0xb1 => { // Munmap64
-                let (addr, len) = consume!(payload, u64, u64);
-                T::munmap(pid, tid, addr, len, trace)
-            },
-
-            0x11 => { // Read8_32
-                let (addr, val, pc) = consume!(payload, u32, u8, u32);
-                T::read(pid, tid, pc as u64, addr as u64, val as u64, 1, trace)
-            },
-            0x12 => { // Read16_32
-                let (addr, val, pc) = consume!(payload, u32, u16, u32);
-                T::read(pid, tid, pc as u64, addr as u64, val as u64, 2, trace)
-            },
-            0x14 => { // Read32_32
-                let (addr, val, pc) = consume!(payload, u32, u32, u32);
-                T::read(pid, tid, pc as u64, addr as u64, val as u64, 4, trace)
-            },
-            0x18 => { // Read64_32
-                let (addr, val, pc) = consume!(payload, u32, u64, u32);
-                T::read(pid, tid, pc as u64, addr as u64, val as u64, 8, trace)
-            },
-
-            0x21 => { // Write8_32
-                let (addr, val, pc) = consume!(payload, u32, u8, u32);
-                T::write(pid, tid, pc as u64, addr as u64,
-                    val as u64, 1, trace)
-            },
-            0x22 => { // Write16_32
-                let (addr, val, pc) = consume!(payload, u32, u16, u32);
-                T::write(pid, tid, pc as u64, addr as u64,
-                    val as u64, 2, trace)
-            },
-            0x24 => { // Write32_32
-                let (addr, val, pc) = consume!(payload, u32, u32, u32);
-                T::write(pid, tid, pc as u64, addr as u64,
-                    val as u64, 4, trace)
-            },
-            0x28 => { // Write64_32
-                let (addr, val, pc) = consume!(payload, u32, u64, u32);
-                T::write(pid, tid, pc as u64, addr as u64,
-                    val as u64, 8, trace)
-            },
-
-            0x91 => { // Read8_64
-                let (addr, val, pc) = consume!(payload, u64, u8, u64);
-                T::read(pid, tid, pc as u64, addr as u64, val as u64, 1, trace)
-            },
-            0x92 => { // Read16_64
-                let (addr, val, pc) = consume!(payload, u64, u16, u64);
-                T::read(pid, tid, pc as u64, addr as u64, val as u64, 2, trace)
-            },
-            0x94 => { // Read32_64
-                let (addr, val, pc) = consume!(payload, u64, u32, u64);
-                T::read(pid, tid, pc as u64, addr as u64, val as u64, 4, trace)
-            },
-            0x98 => { // Read64_64
-                let (addr, val, pc) = consume!(payload, u64, u64, u64);
-                T::read(pid, tid, pc as u64, addr as u64, val as u64, 8, trace)
-            },
-
-            0xa1 => { // Write8_64
-                let (addr, val, pc) = consume!(payload, u64, u8, u64);
-                T::write(pid, tid, pc as u64, addr as u64,
-                    val as u64, 1, trace)
-            },
-            0xa2 => { // Write16_64
-                let (addr, val, pc) = consume!(payload, u64, u16, u64);
-                T::write(pid, tid, pc as u64, addr as u64,
-                    val as u64, 2, trace)
-            },
-            0xa4 => { // Write32_64
-                let (addr, val, pc) = consume!(payload, u64, u32, u64);
-                T::write(pid, tid, pc as u64, addr as u64,
-                    val as u64, 4, trace)
-            },
-            0xa8 => { // Write64_64
-                let (addr, val, pc) = consume!(payload, u64, u64, u64);
-                T::write(pid, tid, pc as u64, addr as u64,
-                    val as u64, 8, trace)
-            },
-
-            _ => {
-                // Invalid opcode
-                return Err(Error::InvalidOpcode(op));
-            },
+    threads.clear();
+
+    // Epoch of the chunk, from its first epoch event
+    let mut chunk_epoch = None;
+
+    // Mappings of the initial snapshot in the chunk
+    let mut maps = Vec::new();
+
+    // Register state rebuilt from the register deltas in the chunk
+    let mut deltas = RegisterDeltas::new();
+
+    // Dispatch each event to the user
+    for event in EventIter::new(payload) {
+        match event? {
+            Event::Epoch { epoch } => {
+                chunk_epoch = chunk_epoch.or(Some(epoch));
+            }
+            Event::Exec { pc } => T::exec(pid, tid, pc, trace),
+            Event::Regs { pc, regs } => T::regs(pid, tid, pc, regs, trace),
+            Event::RegsDelta { pc, mask, regs } =>
+                T::regs(pid, tid, pc, deltas.apply(mask, regs)?, trace),
+            Event::RegisterGroups { pc, groups } =>
+                T::register_groups(pid, tid, pc, &groups, trace),
+            Event::Read { pc, addr, val, sz } =>
+                T::read(pid, tid, pc, addr, val, sz, trace),
+            Event::Write { pc, addr, val, sz } =>
+                T::write(pid, tid, pc, addr, val, sz, trace),
+            Event::Mmap { base, len, anon, read, write, exec, path, offset } =>
+                T::mmap(pid, tid, base, len, anon, read, write, exec, path,
+                    offset, trace),
+            Event::Munmap { base, len } =>
+                T::munmap(pid, tid, base, len, trace),
+            Event::Mprotect { base, len, read, write, exec } =>
+                T::mprotect(pid, tid, base, len, read, write, exec, trace),
+            Event::Brk { old, new } => T::brk(pid, tid, old, new, trace),
+            Event::Map { base, len, read, write, exec, path, offset } =>
+                maps.push(
+                    Mapping { base, len, read, write, exec, path, offset }),
+            Event::Clone { child, flags } => {
+                if flags & libc::CLONE_THREAD as u64 != 0 {
+                    threads.push(child);
+                }
+                T::cloned(pid, tid, child, flags, trace);
+            }
+            Event::Execve { path } => T::execve(pid, tid, path, trace),
+            Event::SyscallEntry { pc, num, ref args } =>
+                T::syscall_entry(pid, tid, pc, num, args, trace),
+            Event::Syscall { pc, num, ref args, ret } =>
+                T::syscall(pid, tid, pc, num, args, ret, trace),
+            Event::Signal { sig, pc, handler } =>
+                T::signal(pid, tid, sig, pc, handler, trace),
+            Event::Sigreturn { pc } => T::sigreturn(pid, tid, pc, trace),
+            Event::Time { tsc } => T::time(pid, tid, tsc, trace),
         }
     }
 
-    Ok(())
+    // Report the snapshot, the jitter sends it in chunks of its own
+    if !maps.is_empty() {
+        T::initial_maps(pid, tid, &maps, trace);
+    }
+
+    Ok(chunk_epoch)
+}
+
+/// Information about a chunk of trace, reported along with the trace built
+/// from it to [`Cannoli::trace`] and [`Cannoli::merged`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkInfo {
+    /// Sequence number of the chunk in the thread's pipe, the chunks of a
+    /// thread are always reported in this order
+    pub seq: u64,
+
+    /// The process's epoch when the jitter started the chunk. Every chunk of
+    /// every thread of a process takes the next epoch, which orders chunks
+    /// across the threads of the process. `None` if the chunk didn't have an
+    /// epoch, for clients without [`CAP_EPOCHS`]
+    pub epoch: Option<u64>,
+}
+
+/// A mapping which the target's process already had when it connected,
+/// reported to [`Cannoli::initial_maps`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping<'a> {
+    /// Base address of the mapping
+    pub base: u64,
+
+    /// Length of the mapping in bytes
+    pub len: u64,
+
+    /// Set if the mapping is readable
+    pub read: bool,
+
+    /// Set if the mapping is writable
+    pub write: bool,
+
+    /// Set if the mapping is executable
+    pub exec: bool,
+
+    /// Path of the mapped file. Empty for anonymous mappings, and `[stack]`
+    /// for the stack of the main thread
+    pub path: &'a str,
+
+    /// Offset of the mapping in the file
+    pub offset: u64,
 }
 
 /// Information about a newly connected client
@@ -417,106 +679,414 @@ pub struct ClientInfo {
     /// Thread ID
     pub tid: i32,
 
+    /// Thread ID of the thread which created this thread, or this process if
+    /// this is the first thread of a forked process. The creating thread
+    /// reported the creation with a [`Cannoli::cloned`] in its trace. `None`
+    /// if it's not known, like for the first thread of a process QEMU was
+    /// started for
+    pub parent_tid: Option<i32>,
+
+    /// `CAP_*` bits describing what the client may emit in its trace
+    pub capabilities: u32,
+
+    /// The `CANNOLI_VERSION` the client's jitter was built with
+    pub cannoli_version: u64,
+
+    /// The process's epoch when the thread connected. Epochs are counted by
+    /// all threads of a process, which allows ordering the threads' starts
+    /// with respect to the chunks of the other threads, see [`ChunkInfo`]
+    pub epoch: u64,
+
     /// Parent comm, `/proc/ppid/comm`, this is the raw value read from `comm`
     /// and may include weird stuff like newlines
     pub pcomm: Option<String>,
 
     /// comm, `/proc/pid/comm`, this is the raw value read from `comm`
     /// and may include weird stuff like newlines
+    ///
+    /// For QEMU these are the names of the QEMU binaries, the guest is
+    /// described by [`ClientInfo::exe`] and [`ClientInfo::argv`]
     pub comm: Option<String>,
+
+    /// Path of the guest's executable, as it was given to QEMU
+    pub exe: Option<String>,
+
+    /// Arguments of the guest, starting with its `argv[0]`
+    pub argv: Vec<String>,
+
+    /// Environment of the guest, as `NAME=value` strings
+    pub envp: Vec<String>,
+
+    /// Entry point of the guest's executable, `AT_ENTRY` from its auxiliary
+    /// vector
+    pub at_entry: u64,
+
+    /// Address of the program headers of the guest's executable, `AT_PHDR`
+    /// from its auxiliary vector. Along with the `e_phoff` of the executable
+    /// this gives the address it was loaded at
+    pub at_phdr: u64,
+
+    /// Address the guest's interpreter was loaded at, `AT_BASE` from its
+    /// auxiliary vector. Zero for static executables
+    pub at_base: u64,
+
+    /// Width of each general purpose register of the guest, in bytes. The
+    /// `regs` passed to [`Cannoli::regs`] are [`ClientInfo::num_gprs`]
+    /// registers of this width
+    pub gpr_width: usize,
+
+    /// Number of general purpose registers passed to [`Cannoli::regs`]
+    pub num_gprs: usize,
+
+    /// Byte offset of the general purpose registers in QEMU's CPU state
+    pub gpr_offset: usize,
+}
+
+/// Split the next `len` bytes of the variable-length part of the handshake
+/// off of `bytes`
+fn take<'a>(bytes: &mut &'a [u8], len: u32) -> Result<&'a [u8]> {
+    let taken = bytes.get(..len as usize).ok_or(Error::BufferTruncated)?;
+    *bytes = &bytes[len as usize..];
+    Ok(taken)
+}
+
+/// Split NUL-terminated strings like the guest's arguments, replacing
+/// invalid UTF-8
+fn split_strings(bytes: &[u8]) -> Vec<String> {
+    // Drop the last terminator, so it doesn't split off an empty string
+    let bytes = bytes.strip_suffix(&[0]).unwrap_or(bytes);
+    if bytes.is_empty() {
+        return Vec::new();
+    }
+
+    bytes.split(|&x| x == 0)
+        .map(|x| String::from_utf8_lossy(x).into_owned()).collect()
+}
+
+/// Join strings like the guest's arguments, terminating each with a NUL
+fn join_strings(strings: &[String]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for string in strings {
+        bytes.extend_from_slice(string.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
+impl ClientInfo {
+    /// Construct client information from the parts of the handshake.
+    /// `strings` holds the pcomm, the comm, the exe, the argv, the envp, and
+    /// the architecture's name, with the lengths from `header`
+    fn from_handshake(hello: &Hello, header: &ClientConn, mut strings: &[u8])
+            -> Result<Self> {
+        // Split up the strings
+        let pcomm = take(&mut strings, header.pcomm_len)?;
+        let comm  = take(&mut strings, header.comm_len)?;
+        let exe   = take(&mut strings, header.exe_len)?;
+        let argv  = take(&mut strings, header.argv_len)?;
+        let envp  = take(&mut strings, header.envp_len)?;
+        let arch  = take(&mut strings, header.arch_len)?;
+
+        // Look up the architecture, falling back to the name the client gave
+        // us if it's one we don't know about
+        let arch = Architecture::try_from(header.arch).or_else(|err| {
+            if arch.is_empty() {
+                return Err(err);
+            }
+            Ok(Architecture::Unknown(
+                String::from_utf8_lossy(arch).into_owned()))
+        })?;
+
+        Ok(Self {
+            // IPC pipe UID
+            uid: header.uid,
+
+            // Architecture
+            arch,
+
+            // Endianness
+            big_endian: header.big_endian != 0,
+
+            // PIDs
+            ppid: header.ppid,
+            pid:  header.pid,
+            tid:  header.tid,
+
+            // Creator of the thread
+            parent_tid: (header.parent_tid != 0).then_some(header.parent_tid),
+
+            // Negotiated protocol details
+            capabilities:    hello.capabilities,
+            cannoli_version: hello.cannoli_version,
+
+            // Ordering
+            epoch: header.epoch,
+
+            pcomm: std::str::from_utf8(pcomm).ok().map(|x| x.to_string()),
+            comm:  std::str::from_utf8(comm).ok().map(|x| x.to_string()),
+
+            // The guest
+            exe:  (!exe.is_empty()).then(||
+                String::from_utf8_lossy(exe).into_owned()),
+            argv: split_strings(argv),
+            envp: split_strings(envp),
+
+            // Auxiliary vector
+            at_entry: header.at_entry,
+            at_phdr:  header.at_phdr,
+            at_base:  header.at_base,
+
+            // Register layout
+            gpr_width:  header.gpr_width  as usize,
+            num_gprs:   header.num_gprs   as usize,
+            gpr_offset: header.gpr_offset as usize,
+        })
+    }
+
+    /// Serialize the client information in the same format as the
+    /// handshake, a [`Hello`], a [`ClientConn`], the pcomm, the comm, the
+    /// exe, the argv, the envp, and the architecture's name
+    pub fn to_bytes(&self) -> Vec<u8> {
+        // Get the strings
+        let pcomm = self.pcomm.as_deref().unwrap_or("").as_bytes();
+        let comm  = self.comm.as_deref().unwrap_or("").as_bytes();
+        let exe   = self.exe.as_deref().unwrap_or("").as_bytes();
+        let argv  = join_strings(&self.argv);
+        let envp  = join_strings(&self.envp);
+        let arch  = self.arch.name().as_bytes();
+
+        // Reconstruct the handshake
+        let hello = Hello::new(self.capabilities, self.cannoli_version);
+        let header = ClientConn {
+            uid:        self.uid,
+            arch:       self.arch.id(),
+            big_endian: self.big_endian as i32,
+            ppid:       self.ppid,
+            pid:        self.pid,
+            tid:        self.tid,
+            pcomm_len:  pcomm.len() as u32,
+            comm_len:   comm.len()  as u32,
+            transport:  Transport::NamedShm as u32,
+            epoch:      self.epoch,
+            parent_tid: self.parent_tid.unwrap_or(0),
+            exe_len:    exe.len()   as u32,
+            argv_len:   argv.len()  as u32,
+            envp_len:   envp.len()  as u32,
+            at_entry:   self.at_entry,
+            at_phdr:    self.at_phdr,
+            at_base:    self.at_base,
+            gpr_width:  self.gpr_width  as u32,
+            num_gprs:   self.num_gprs   as u32,
+            gpr_offset: self.gpr_offset as u64,
+            arch_len:   arch.len()  as u32,
+            reserved:   0,
+        };
+
+        // Serialize it
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(
+                &hello as *const Hello as *const u8, size_of::<Hello>())
+        });
+        bytes.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(
+                &header as *const ClientConn as *const u8,
+                size_of::<ClientConn>())
+        });
+        bytes.extend_from_slice(pcomm);
+        bytes.extend_from_slice(comm);
+        bytes.extend_from_slice(exe);
+        bytes.extend_from_slice(&argv);
+        bytes.extend_from_slice(&envp);
+        bytes.extend_from_slice(arch);
+        bytes
+    }
+
+    /// Deserialize client information produced by [`ClientInfo::to_bytes`]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        // Get the hello and make sure we understand it
+        let hello = bytes.get(..size_of::<Hello>())
+            .ok_or(Error::BufferTruncated)?;
+        let hello: Hello = unsafe {
+            core::ptr::read_unaligned(hello.as_ptr() as *const Hello)
+        };
+        hello.validate()?;
+
+        // Get the header
+        let bytes = &bytes[size_of::<Hello>()..];
+        let header = bytes.get(..size_of::<ClientConn>())
+            .ok_or(Error::BufferTruncated)?;
+        let header: ClientConn = unsafe {
+            core::ptr::read_unaligned(header.as_ptr() as *const ClientConn)
+        };
+
+        // Everything else is the strings
+        Self::from_handshake(&hello, &header,
+            &bytes[size_of::<ClientConn>()..])
+    }
+}
+
+/// Open the IPC pipe described by a client's `header`. `fd` is the file
+/// descriptor which was received along with the header, if any
+fn open_pipe(header: &ClientConn, fd: Option<OwnedFd>)
+        -> Result<RecvPipe<CHUNK_SIZE, NUM_BUFFERS>> {
+    match Transport::try_from(header.transport)? {
+        Transport::NamedShm => RecvPipe::open(header.uid),
+        Transport::Memfd => {
+            let fd = fd.ok_or(Error::MissingPipeFd)?;
+            RecvPipe::open_fd(fd.into_raw_fd(), header.uid)
+        }
+    }.map_err(Error::OpenPipe)
 }
 
 /// Handle a newly connected client. This is run on a new thread each time a
-/// new TCP connection comes in.
+/// new connection comes in.
 fn handle_client<T>(
-        stream: TcpStream, num_threads: usize, ci: &ClientInfo) -> Result<()>
+        stream: Stream, pipe: RecvPipe<CHUNK_SIZE, NUM_BUFFERS>,
+        num_threads: usize, ci: &ClientInfo, record: Option<&Path>)
+        -> Result<()>
             where T: Cannoli + 'static,
                   T::PidContext: Send + Sync + 'static {
-    /// Storage for PID contexts, keyed by target process ID
-    static PID_CONTEXTS: LazyLock<Mutex<
-            HashMap<i32, Arc<dyn Any + Send + Sync>>>> =
+    // Make the stream nonblocking
+    stream.set_nonblocking(true)
+        .map_err(Error::SetNonblocking)?;
+
+    // Process the trace until the client disconnects. This allows us to
+    // check if the remote process died, our IPC mechanism doesn't have a way
+    // of checking that
+    run_session::<T>(&pipe, num_threads, ci, record, &|| {},
+        &|| stream.is_connected())
+}
+
+/// Process the trace coming in over `pipe` from the client `ci` until
+/// `connected` returns `false`, and everything the client sent before that
+/// has been reported to the user. If `record` is set, the trace is also
+/// recorded to a file in that directory. `joined_process` is invoked once the
+/// session is part of its process, and of the merge of its traces
+fn run_session<T>(
+        pipe: &RecvPipe<CHUNK_SIZE, NUM_BUFFERS>, num_threads: usize,
+        ci: &ClientInfo, record: Option<&Path>, joined_process: &dyn Fn(),
+        connected: &(dyn Fn() -> bool + Sync)) -> Result<()>
+            where T: Cannoli + 'static,
+                  T::PidContext: Send + Sync + 'static {
+    /// Storage for the state of processes, keyed by the user's type, and
+    /// target process ID. The type is part of the key so servers for
+    /// different types can't mix up their contexts
+    static PID_CONTEXTS: LazyLock<Mutex<PidContexts>> =
         LazyLock::new(|| Mutex::new(HashMap::new()));
 
+    /// Type-erased process states, along with the number of threads of the
+    /// process which are connected
+    type PidContexts =
+        HashMap<(TypeId, i32), (usize, Arc<dyn Any + Send + Sync>)>;
+
+    /// Ticks whenever a session joins or leaves its process, with
+    /// [`PID_CONTEXTS`] locked. Recordings keep the ticks of their session,
+    /// so replaying them can tell which sessions were part of the same
+    /// process, and in which order they connected
+    static SESSION_CLOCK: AtomicU64 = AtomicU64::new(0);
+
+    // Key for this client's PID context
+    let pid_key = (TypeId::of::<T>(), ci.pid);
+
     /// Storage for the mini state-machine we use to sequence traces
     struct State<T: Cannoli + 'static> {
         /// Next sequence number we are looking for to report traces
         next_seq: u64,
 
-        /// Vector of traces, maintained sorted, with a sequence identifer in
-        /// the first part of the tuple
-        traces: Vec<(u64, Vec<T::Trace>)>,
+        /// Vector of traces, maintained sorted by the sequence number in
+        /// the chunk information
+        traces: Vec<(ChunkInfo, Vec<T::Trace>)>,
 
         /// User's [`Cannoli`]-implementing type
         user: T,
     }
 
-    // Create the IPC connection to the UID we got
-    let pipe = RecvPipe::<CHUNK_SIZE, NUM_BUFFERS>::open(ci.uid)
-        .map_err(Error::OpenPipe)?;
-
-    // Make the stream nonblocking
-    stream.set_nonblocking(true)
-        .map_err(Error::SetNonblocking)?;
-
-    // Scratch buffer to check for socket close
-    let mut scratch_buffer = [0u8; 1];
+    /// State shared by all threads of a process
+    struct Process<T: Cannoli + 'static> {
+        /// The user's context for the process
+        context: Arc<T::PidContext>,
 
-    // Get a reference to the pipe so we can `move` the reference into the
-    // threads we create
-    let pipe = &pipe;
+        /// Merges the traces of all threads, if the user asked for it
+        merger: Mutex<Merger<T>>,
+    }
 
-    // Get the PID context
-    let any_pid_context: Arc<dyn Any + Send + Sync> = {
+    // Get the process
+    let (any_process, joined): (Arc<dyn Any + Send + Sync>, u64) = {
         // Get the contexts
         let mut contexts = PID_CONTEXTS.lock().unwrap();
 
-        // Either get the existing context or create a new one
-        contexts.entry(ci.pid).or_insert_with(|| {
-            T::init_pid(ci)
-        }).clone()
+        // Either get the existing context or create a new one, and count
+        // this thread as part of it
+        let entry = contexts.entry(pid_key).or_insert_with(|| {
+            (0, Arc::new(Process::<T> {
+                context: T::init_pid(ci),
+                merger:  Mutex::new(Merger::new()),
+            }))
+        });
+        entry.0 += 1;
+        (entry.1.clone(), SESSION_CLOCK.fetch_add(1, Ordering::Relaxed))
+    };
+
+    // Start recording if requested. If that fails the session still has to
+    // leave its process, so the error is only returned at the end
+    let (recorder, result) = match record
+            .map(|dir| Recorder::create(dir, ci, joined)).transpose() {
+        Ok(recorder) => (recorder, Ok(())),
+        Err(err)     => (None, Err(err)),
     };
 
-    // Get the PID context with the correct type
-    let pid_context = any_pid_context.downcast_ref::<T::PidContext>().unwrap();
+    // Get the process with the correct type
+    let process = any_process.downcast_ref::<Process<T>>().unwrap();
+    let pid_context = &*process.context;
+
+    // Take part in merging the traces of the process if requested
+    let merge_id = T::MERGE_THREADS
+        .then(|| process.merger.lock().unwrap().join(ci));
+    joined_process();
 
     // Create a new instance of the user's structure
-    let (user_type, user_ctxt) = T::init_tid(&*pid_context, ci);
-    let user_ctxt = &user_ctxt;
+    let (user_type, tid_context) = T::init_tid(pid_context, ci);
+    let user_ctxt = &tid_context;
 
     // Create the sequencing state machine
-    let state = Mutex::new(State {
+    let sequencer = Mutex::new(State {
         next_seq: 0,
         traces:   Vec::new(),
         user:     user_type,
     });
-    let state = &state;
+    let state = &sequencer;
 
     // Create a thread scope
-    std::thread::scope(|s| -> Result<()> {
+    let result = result.and_then(|()| std::thread::scope(|s| -> Result<()> {
         // Holds the handles to the threads we create
         let mut threads = Vec::new();
 
         // Create the number of threads requested
         for _ in 0..num_threads {
-            // Clone the stream so we can checked for a closed socked on
-            // multiple threads
-            let mut stream = stream.try_clone().map_err(Error::CloneSocket)?;
+            // Get our own handle to the recorder
+            let recorder = recorder.as_ref().map(Recorder::sender);
 
             // Create the IPC reader thread!
             threads.push(s.spawn(move || -> Result<()> {
                 // Buffer for trace results
                 let mut trace = Vec::new();
 
+                // Buffer for the threads created in a chunk
+                let mut created = Vec::new();
+
                 // Current ticket for getting a trace
                 let mut ticket = Some(pipe.request_ticket());
 
                 // The last time this thread read data
                 let mut last_data = Instant::now();
 
-                // Loop forever while the socket is open. This allows us to
-                // check if the remote process died, our IPC mechanism doesn't
-                // have a way of checking that
-                while !matches!(stream.read(&mut scratch_buffer), Ok(0)) {
+                // Set once the client has disconnected, at which point we
+                // only drain what it sent before that
+                let mut disconnected = false;
+
+                // Loop forever while the client is connected
+                loop {
                     // If we haven't gotten any data recent, sleep a bit before
                     // hot polling. This prevents us completely eating 100% CPU
                     // when there are threads connected to us but not streaming
@@ -533,19 +1103,49 @@ fn handle_client<T>(
 
                         // Attempt to get a payload from the pipe, parse it if
                         // there was one
+                        let seq = ticket.as_ref().unwrap().seq();
+                        let mut recorded = None;
                         let (new_ticket, payload) = pipe.try_recv(
-                            ticket.take().unwrap(),
-                            |x| parse_payload::<T>(
-                                &*pid_context, user_ctxt, &mut trace, x));
+                            ticket.take().unwrap(), |x| {
+                                // Copy the chunk to be recorded before it
+                                // goes back to the client
+                                if recorder.is_some() {
+                                    recorded = Some(x.to_vec());
+                                }
+
+                                parse_payload::<T>(pid_context, user_ctxt,
+                                    &mut trace, &mut created, x)
+                            });
 
                         // Replace the ticket with the new ticket
                         ticket = Some(new_ticket);
 
+                        // Compress and record the chunk now that the client
+                        // has its buffer back, so waiting on the recorder
+                        // never holds up the client. If the recorder failed
+                        // we'll hear about it when finishing it.
+                        if let (Some(recorder), Some(chunk)) =
+                                (&recorder, recorded) {
+                            let _ = recorder.send(Block::new(seq, &chunk));
+                        }
+
                         // Process the result if we parsed a payload
                         if let Some(payload) = payload {
                             // It's possible payload parsing failed, so check
                             // the error
-                            let (seq, _) = payload?;
+                            let (seq, epoch) = payload?;
+
+                            // Have the merge wait on the threads created in
+                            // this chunk before the chunk can be merged, so
+                            // younger chunks of other threads can't be merged
+                            // before their first chunk
+                            if merge_id.is_some() && !created.is_empty() {
+                                let mut merger =
+                                    process.merger.lock().unwrap();
+                                for &tid in &created {
+                                    merger.expect(tid);
+                                }
+                            }
 
                             // Refresh hot polling
                             hot_poll = 10000;
@@ -563,27 +1163,35 @@ fn handle_client<T>(
 
                             // Find the correct trace index
                             let idx = match state.traces
-                                    .binary_search_by_key(&seq, |x| x.0) {
+                                    .binary_search_by_key(&seq, |x| x.0.seq) {
                                 Ok(idx) | Err(idx) => idx,
                             };
 
                             // Insert the trace!
                             let cap = trace.capacity();
-                            state.traces.insert(idx, (seq, trace));
+                            state.traces.insert(idx,
+                                (ChunkInfo { seq, epoch }, trace));
 
                             // Report traces in order
                             while !state.traces.is_empty() &&
-                                    state.next_seq == state.traces[0].0 {
+                                    state.next_seq == state.traces[0].0.seq {
                                 // Update the reporting sequence
                                 state.next_seq =
                                     state.next_seq.wrapping_add(1);
 
                                 // Remove the entry from traces
-                                let trace = state.traces.remove(0).1;
+                                let (chunk, trace) = state.traces.remove(0);
 
                                 // Report the trace
-                                state.user.trace(&*pid_context,
-                                    user_ctxt, &trace);
+                                state.user.trace(pid_context,
+                                    user_ctxt, &chunk, &trace);
+
+                                // Hand it over to be merged with the other
+                                // threads of the process
+                                if let Some(id) = merge_id {
+                                    process.merger.lock().unwrap()
+                                        .push(pid_context, id, chunk, trace);
+                                }
                             }
 
                             // Drop the lock and re-allocate the trace buffer
@@ -591,6 +1199,18 @@ fn handle_client<T>(
                             trace = Vec::with_capacity(cap);
                         }
                     }
+
+                    // Once the client is gone, we're done when our ticket is
+                    // for a chunk which was never sent. Every chunk which was
+                    // sent has been or will be picked up by another thread.
+                    if disconnected &&
+                            ticket.as_ref().unwrap().seq() >= pipe.sent() {
+                        break;
+                    }
+
+                    // Check if the client is still connected, everything it
+                    // sent before this is drained on the next iterations
+                    disconnected = disconnected || !connected();
                 }
 
                 Ok(())
@@ -603,24 +1223,51 @@ fn handle_client<T>(
         }
 
         Ok(())
-    })?;
+    }));
+
+    // Stop waiting on this thread to merge the traces of the process
+    if let Some(id) = merge_id {
+        process.merger.lock().unwrap().leave(pid_context, id);
+    }
+
+    // Every trace for this thread has been reported, let the user know the
+    // thread is gone. If a callback panicked, the user's state may be
+    // inconsistent so we leave it alone.
+    if let Ok(state) = sequencer.into_inner() {
+        state.user.fini_tid(pid_context, tid_context);
+    }
 
     // Potentially delete the PID from the global database, we have to detect
     // when all threads are exited, this is kinda gross but whatever
-    {
-        // Drop the PID context
-        drop(any_pid_context);
-
-        // Get access to contexts to see if we've dropped all references to
-        // this pid.
+    let (last_thread, left) = {
+        // Get access to contexts and leave the process. Threads only join and
+        // leave with this lock held, so the last one to leave is the only
+        // one which sees the count drop to zero
         let mut contexts = PID_CONTEXTS.lock().unwrap();
-        if Arc::strong_count(&contexts[&ci.pid]) == 1 {
-            contexts.remove(&ci.pid);
+        let entry = contexts.get_mut(&pid_key)
+            .expect("Cannoli: Process left twice");
+        entry.0 -= 1;
+        let left = SESSION_CLOCK.fetch_add(1, Ordering::Relaxed);
+        if entry.0 == 0 {
+            contexts.remove(&pid_key);
+            (true, left)
+        } else {
+            (false, left)
         }
+    };
+
+    // Wait for the recording to make it to disk
+    let result = match recorder {
+        Some(recorder) => result.and(recorder.finish(left)),
+        None           => result,
+    };
+
+    // If we were the last thread, the process is gone
+    if last_thread {
+        T::fini_pid(pid_context, ci);
     }
 
-    // We did everything we wanted!
-    Ok(())
+    result
 }
 
 /// Create a new Cannoli server. This will spin up the required processing
@@ -629,75 +1276,13 @@ fn handle_client<T>(
 ///
 /// Create `threads` number of threads for every connection that comes in.
 /// These threads will handle all Cannoli parsing and callbacks
+///
+/// This is shorthand for `CannoliServer::new(threads).run::<T>()`, see
+/// [`CannoliServer`] for more options
 pub fn create_cannoli<T>(threads: usize) -> Result<()>
         where T: Cannoli + 'static,
               T::PidContext: Send + Sync + 'static {
-    // Create socket, waiting for clients to connect and inform us about some
-    // memory regions
-    let listener = TcpListener::bind("127.0.0.1:11458")
-        .map_err(Error::Bind)?;
-
-    // Create a new thread scope for handling connections
-    std::thread::scope(|scope| {
-        // Wait for connections
-        for stream in listener.incoming() {
-            // Spawn a thread on new connections
-            scope.spawn(move || {
-                // Get access to the stream
-                let mut stream = stream.expect("Failed to get TCP stream");
-
-                // Get the header
-                let mut header: MaybeUninit<ClientConn> =
-                    MaybeUninit::uninit();
-                stream.read_exact(unsafe {
-                    core::slice::from_raw_parts_mut(
-                        header.as_mut_ptr() as *mut u8,
-                        core::mem::size_of_val(&header))
-                }).expect("Failed to get client header");
-
-                // Get the actual header now that it's initialized
-                let header: ClientConn = unsafe { header.assume_init() };
-
-                // Get the pcomm and comm
-                let mut comm =
-                    vec![0u8; header.pcomm_len as usize +
-                              header.comm_len  as usize];
-                stream.read_exact(&mut comm)
-                    .expect("Failed to get client pcomm and comm");
-
-                // Construct client information
-                let ci = ClientInfo {
-                    // IPC pipe UID
-                    uid: header.uid,
-
-                    // Architecture
-                    arch: Architecture::from(header.arch),
-
-                    // Endianness
-                    big_endian: header.big_endian != 0,
-
-                    // PIDs
-                    ppid: header.ppid,
-                    pid:  header.pid,
-                    tid:  header.tid,
-
-                    pcomm: std::str::from_utf8(
-                        &comm[..header.pcomm_len as usize])
-                        .ok().map(|x| x.to_string()),
-                    comm: std::str::from_utf8(
-                        &comm[header.pcomm_len as usize..])
-                        .ok().map(|x| x.to_string()),
-                };
-
-                // Handle the client
-                handle_client::<T>(stream, threads, &ci)
-                    .expect("Failed to handle client");
-            });
-        }
-
-        // All done!
-        Ok(())
-    })
+    CannoliServer::new(threads).run::<T>().map(|_| ())
 }
 
 /// Trait which must be implemented by a user to implement their hooks and
@@ -734,6 +1319,18 @@ pub trait Cannoli: Send + Sync {
     fn init_tid(pid: &Self::PidContext, ci: &ClientInfo)
         -> (Self, Self::TidContext) where Self: Sized;
 
+    /// Invoked when a QEMU thread disconnects, after the final
+    /// [`Cannoli::trace`] for that thread has been delivered. This consumes
+    /// the thread's state, making it the place to flush per-thread results
+    fn fini_tid(self, _pid: &Self::PidContext, _tid: Self::TidContext)
+        where Self: Sized {}
+
+    /// Invoked when the last thread of a target process disconnects, after
+    /// [`Cannoli::fini_tid`] was invoked for that thread. `ci` describes the
+    /// last thread which disconnected. This is the place to flush results
+    /// which are collected for the whole process
+    fn fini_pid(_pid: &Self::PidContext, _ci: &ClientInfo) where Self: Sized {}
+
     /// Invoked when a PC execution opcode was lifted from the trace
     ///
     /// Executed on multiple threads
@@ -754,6 +1351,10 @@ pub trait Cannoli: Send + Sync {
 
     /// Invoked when execution of an instruction with register tracing occurs
     ///
+    /// `regs` is the raw register state of the guest, a [`RegisterFile`]
+    /// decodes it into named registers. For register delta hooks, this is
+    /// the full register state rebuilt from the deltas.
+    ///
     /// Executed on multiple threads
     ///
     /// This is a high-performance parallel callback, and is a prime location
@@ -771,6 +1372,21 @@ pub trait Cannoli: Send + Sync {
             _pc: u64, _regs: &[u8],
             _trace: &mut Vec<Self::Trace>) {}
 
+    /// Invoked when execution of an instruction whose hook captured groups of
+    /// registers occurs, like its floating point or vector registers
+    ///
+    /// By default, the general purpose registers, if they were captured, are
+    /// passed on to [`Cannoli::regs`]
+    ///
+    /// Executed on multiple threads, like [`Cannoli::regs`]
+    fn register_groups(pid: &Self::PidContext, tid: &Self::TidContext,
+            pc: u64, groups: &RegisterSections,
+            trace: &mut Vec<Self::Trace>) {
+        if let Some(gprs) = groups.get(RegisterGroup::Gpr) {
+            Self::regs(pid, tid, pc, gprs.bytes, trace);
+        }
+    }
+
     /// Invoked when a memory load was lifted from the trace with a given
     /// access size in bytes
     ///
@@ -818,8 +1434,43 @@ pub trait Cannoli: Send + Sync {
     ///
     /// Executed serially. Maybe in different threads, but only one at a time
     /// (hence, mutable access to self)
+    ///
+    /// `chunk` describes the chunk of trace the events came from, its epoch
+    /// orders it with respect to the chunks of the other threads of the
+    /// process
     fn trace(&mut self, _pid: &Self::PidContext,
-        _tid: &Self::TidContext, _trace: &[Self::Trace]) {}
+        _tid: &Self::TidContext, _chunk: &ChunkInfo,
+        _trace: &[Self::Trace]) {}
+
+    /// Set to have the traces of all threads of a process merged, and
+    /// delivered to [`Cannoli::merged`]
+    const MERGE_THREADS: bool = false;
+
+    /// When [`Cannoli::MERGE_THREADS`] is set, this is invoked with the trace
+    /// of every chunk of every thread of a process, after it was delivered to
+    /// [`Cannoli::trace`], in the order of the chunks' epochs. `ci` describes
+    /// the thread the chunk came from
+    ///
+    /// Executed serially for each process. Chunks are held until every other
+    /// connected thread of the process has reported a chunk, since an idle
+    /// thread could still send an older one. To bound the memory this uses,
+    /// if a thread stays idle for long enough, chunks are delivered anyway,
+    /// and a late chunk from that thread can then come out of order, which
+    /// can be told from its epoch.
+    fn merged(_pid: &Self::PidContext, _ci: &ClientInfo, _chunk: &ChunkInfo,
+        _trace: &[Self::Trace]) where Self: Sized {}
+
+    /// Invoked with the mappings the target's process already had when it
+    /// connected, like the main executable, the interpreter, and the stack,
+    /// which [`Cannoli::mmap`] is never invoked for. This is invoked on the
+    /// first thread of every process to connect, before anything else is
+    /// reported for the process. Threads created later share these mappings,
+    /// and don't get a snapshot of their own
+    ///
+    /// A large snapshot is sent in multiple chunks, this is invoked once for
+    /// each of them, with the mappings in that chunk
+    fn initial_maps(_pid: &Self::PidContext, _tid: &Self::TidContext,
+            _maps: &[Mapping], _trace: &mut Vec<Self::Trace>) {}
 
     /// Invoked after a _successful_ mmap() in the target application, provides
     /// the base address, length, anon state, read, write, and exec flags
@@ -832,5 +1483,86 @@ pub trait Cannoli: Send + Sync {
     fn munmap(_pid: &Self::PidContext, _tid: &Self::TidContext,
               _base: u64, _len: u64,
               _trace: &mut Vec<Self::Trace>) {}
+
+    /// Invoked after a _successful_ `mprotect()` in the target application,
+    /// provides the base address, length, and the new read, write, and exec
+    /// flags of the region. This is where memory the target unpacked or
+    /// generated becomes executable
+    #[allow(clippy::too_many_arguments)]
+    fn mprotect(_pid: &Self::PidContext, _tid: &Self::TidContext,
+            _base: u64, _len: u64, _read: bool, _write: bool, _exec: bool,
+            _trace: &mut Vec<Self::Trace>) {}
+
+    /// Invoked when the target's program break moved from `old` to `new` with
+    /// `brk()`. The heap grew if `new` is above `old`, and shrank otherwise
+    fn brk(_pid: &Self::PidContext, _tid: &Self::TidContext, _old: u64,
+            _new: u64, _trace: &mut Vec<Self::Trace>) {}
+
+    /// Invoked in the parent after the target successfully created a new
+    /// thread or process with `clone()`, `fork()`, or `vfork()`. `child` is
+    /// the TID of the new thread or the PID of the new process, and `flags`
+    /// are the `CLONE_*` flags, with `CLONE_THREAD` set for new threads
+    ///
+    /// The child's [`ClientInfo::parent_tid`] links it back to this thread.
+    /// Pushing this to the trace gives the point in the parent's trace where
+    /// the child started.
+    fn cloned(_pid: &Self::PidContext, _tid: &Self::TidContext, _child: i32,
+            _flags: u64, _trace: &mut Vec<Self::Trace>) {}
+
+    /// Invoked when the target is about to `execve()` the executable at
+    /// `path`. If it succeeds, the process is replaced, and its threads
+    /// disconnect and reconnect under the same PID
+    fn execve(_pid: &Self::PidContext, _tid: &Self::TidContext, _path: &str,
+            _trace: &mut Vec<Self::Trace>) {}
+
+    /// Invoked when the target makes a syscall, with the syscall number and
+    /// its arguments, before QEMU handles it. `pc` is the PC QEMU had for the
+    /// target when it made the syscall, depending on the architecture this
+    /// is the syscall instruction or the one after it. Only the syscalls the
+    /// jitter's `hook_syscall` asked for are reported, including the ones
+    /// which never return, like `exit()` or a successful `execve()`
+    fn syscall_entry(_pid: &Self::PidContext, _tid: &Self::TidContext,
+            _pc: u64, _num: i32, _args: &[u64; 8],
+            _trace: &mut Vec<Self::Trace>) {}
+
+    /// Invoked when a syscall of the target returned, with the syscall
+    /// number, its arguments, and its return value. `pc` is the same PC as
+    /// the matching [`Cannoli::syscall_entry`] got, the one QEMU had for the
+    /// target when it made the syscall. Only the syscalls the jitter's
+    /// `hook_syscall` asked for are reported, and syscalls which never
+    /// return, like `exit()`, are only reported to
+    /// [`Cannoli::syscall_entry`]
+    fn syscall(_pid: &Self::PidContext, _tid: &Self::TidContext, _pc: u64,
+            _num: i32, _args: &[u64; 8], _ret: i64,
+            _trace: &mut Vec<Self::Trace>) {}
+
+    /// Invoked when signal `sig` is delivered to the target's `handler`,
+    /// interrupting it at `pc`. Execution continues in the handler, and
+    /// returns to `pc` on the matching [`Cannoli::sigreturn`]. Signals which
+    /// are ignored or take their default action aren't reported
+    fn signal(_pid: &Self::PidContext, _tid: &Self::TidContext, _sig: i32,
+            _pc: u64, _handler: u64, _trace: &mut Vec<Self::Trace>) {}
+
+    /// Invoked when a signal handler of the target returned with
+    /// `sigreturn()`, and the target resumes at `pc`
+    fn sigreturn(_pid: &Self::PidContext, _tid: &Self::TidContext, _pc: u64,
+            _trace: &mut Vec<Self::Trace>) {}
+
+    /// Invoked with the host's time stamp counter when the jitter started
+    /// filling a chunk of trace, and again when it flushed it, for clients
+    /// run with [`TIMESTAMPS_ENV`] set. Clients run with
+    /// [`EVENT_TIMESTAMPS_ENV`] set also invoke it right before every
+    /// register and memory event, with the TSC at the time the event was
+    /// recorded. Clients run with neither never invoke it
+    ///
+    /// Stamping chunks is nearly free, but only bounds when the events of a
+    /// chunk happened. Stamping events costs an `rdtsc` per event
+    ///
+    /// Executed on multiple threads
+    ///
+    /// The TSC is shared by all target threads, so pushing it to the trace
+    /// allows building timelines across threads in [`Cannoli::trace`]
+    fn time(_pid: &Self::PidContext, _tid: &Self::TidContext, _tsc: u64,
+            _trace: &mut Vec<Self::Trace>) {}
 }
 
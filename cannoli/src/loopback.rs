@@ -0,0 +1,320 @@
+//! In-process harness which drives a [`Cannoli`] implementation without QEMU
+//!
+//! A [`Loopback`] creates an anonymous pipe in this process, and processes
+//! the chunks pushed into it with the same machinery the server uses for real
+//! clients, including the parallel processing and sequencing of traces. This
+//! makes it possible to test analyses with `cargo test` by feeding them
+//! chunks built with an [`Encoder`].
+//!
+//! [`Encoder`]: crate::wire::Encoder
+
+use std::marker::PhantomData;
+use std::thread::JoinHandle;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::os::unix::io::{BorrowedFd, IntoRawFd};
+use mempipe::{SendPipe, RecvPipe};
+use crate::{Cannoli, ClientInfo, Architecture, SUPPORTED_CAPABILITIES};
+use crate::{Error, Result, CHUNK_SIZE, NUM_BUFFERS, run_session};
+
+/// Fake PIDs handed out to loopback clients, above the largest PID Linux will
+/// ever use so they can't collide with a real process
+static NEXT_PID: AtomicI32 = AtomicI32::new(0x4000_0000);
+
+/// A client session running in this process, see the [module docs](self)
+pub struct Loopback<T> {
+    /// Sending side of the pipe the session reads from
+    pipe: SendPipe<CHUNK_SIZE, NUM_BUFFERS>,
+
+    /// Set when the fake client disconnects
+    disconnected: Arc<AtomicBool>,
+
+    /// Thread processing the session
+    thread: JoinHandle<Result<()>>,
+
+    /// The user's type
+    _user: PhantomData<fn() -> T>,
+}
+
+impl<T> Loopback<T>
+        where T: Cannoli + 'static,
+              T::PidContext: Send + Sync + 'static {
+    /// Start a session for a made up little-endian x86_64 client, which is
+    /// its own process, processing the trace with `threads` threads
+    pub fn new(threads: usize) -> Result<Self> {
+        Self::with_client(Self::client_info(), threads)
+    }
+
+    /// Get the information for a made up little-endian x86_64 client, with a
+    /// unique PID. This can be tweaked and passed to
+    /// [`Loopback::with_client`], for example to start multiple threads of
+    /// the same process by reusing the PID
+    pub fn client_info() -> ClientInfo {
+        let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+
+        ClientInfo {
+            uid:             pid as u64,
+            arch:            Architecture::X86_64,
+            big_endian:      false,
+            ppid:            std::process::id() as i32,
+            pid,
+            tid:             pid,
+            parent_tid:      None,
+            capabilities:    SUPPORTED_CAPABILITIES,
+            cannoli_version: 0,
+            epoch:           0,
+            pcomm:           None,
+            comm:            Some("loopback\n".into()),
+            exe:             None,
+            argv:            Vec::new(),
+            envp:            Vec::new(),
+            at_entry:        0,
+            at_phdr:         0,
+            at_base:         0,
+            gpr_width:       8,
+            num_gprs:        16,
+            gpr_offset:      0,
+        }
+    }
+
+    /// Start a session for the client described by `ci`, processing the
+    /// trace with `threads` threads
+    ///
+    /// Returns once the session joined its process, so when starting multiple
+    /// threads of a process for [`Cannoli::MERGE_THREADS`], the chunks of
+    /// each thread are merged with those of the threads started before it
+    pub fn with_client(ci: ClientInfo, threads: usize) -> Result<Self> {
+        // Create the pipe, it only exists in our process
+        let pipe = SendPipe::<CHUNK_SIZE, NUM_BUFFERS>::create_memfd()
+            .map_err(Error::OpenPipe)?;
+
+        // Open the receiving side with its own file descriptor, like the
+        // server would have gotten it over a socket
+        let fd = unsafe { BorrowedFd::borrow_raw(pipe.fd().unwrap()) }
+            .try_clone_to_owned().map_err(Error::Loopback)?;
+        let recv = RecvPipe::<CHUNK_SIZE, NUM_BUFFERS>::open_fd(
+            fd.into_raw_fd(), pipe.uid()).map_err(Error::OpenPipe)?;
+
+        // Process the session in the background
+        let disconnected = Arc::new(AtomicBool::new(false));
+        let (joined, joined_rx) = std::sync::mpsc::channel();
+        let thread = {
+            let disconnected = disconnected.clone();
+            std::thread::spawn(move || {
+                run_session::<T>(&recv, threads, &ci, None,
+                    &|| { let _ = joined.send(()); },
+                    &|| !disconnected.load(Ordering::Acquire))
+            })
+        };
+
+        // Wait for the session to join its process. If it failed before
+        // that, the error is returned by `finish`
+        let _ = joined_rx.recv();
+
+        Ok(Self { pipe, disconnected, thread, _user: PhantomData })
+    }
+
+    /// Send a chunk of trace, like the jitter does when its buffer fills up
+    /// or it leaves the JIT. Chunks are processed in parallel, but reported
+    /// to [`Cannoli::trace`] in the order they were sent.
+    ///
+    /// Fails if the chunk is too large for the pipe, or if the session
+    /// already ended due to an error, in which case [`Loopback::finish`]
+    /// returns the error.
+    pub fn send(&mut self, chunk: &[u8]) -> Result<()> {
+        // Make sure the chunk fits
+        if chunk.len() > CHUNK_SIZE {
+            return Err(Error::ChunkTooLarge(chunk.len()));
+        }
+
+        // Wait for a buffer, as long as someone is around to free one up
+        loop {
+            if let Some(buffer) = self.pipe.try_alloc_buffer(false) {
+                buffer.send(chunk);
+                return Ok(());
+            }
+
+            if self.thread.is_finished() {
+                return Err(Error::SessionEnded);
+            }
+
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Disconnect the client, and wait for every chunk which was sent to be
+    /// reported to the user, including [`Cannoli::fini_tid`] and, if this
+    /// was the last thread of the process, [`Cannoli::fini_pid`]
+    pub fn finish(self) -> Result<()> {
+        self.disconnected.store(true, Ordering::Release);
+        self.thread.join().map_err(|_| Error::JoinThread)?
+    }
+}
+
+/// Processes reported to [`PcRecorder::fini_pid`], as the PID, the number of
+/// threads of the process, and the PCs it executed
+#[cfg(test)]
+static RECORDED_PCS: std::sync::Mutex<Vec<(i32, usize, Vec<u64>)>> =
+    std::sync::Mutex::new(Vec::new());
+
+/// Test [`Cannoli`] which records every executed PC of a process, in the
+/// order they were reported, along with the number of threads of the process
+#[cfg(test)]
+pub(crate) struct PcRecorder;
+
+#[cfg(test)]
+impl PcRecorder {
+    /// Get the number of threads and the PCs of every process with `pid`
+    /// which finished so far, in the order they finished
+    pub(crate) fn results(pid: i32) -> Vec<(usize, Vec<u64>)> {
+        RECORDED_PCS.lock().unwrap().iter().filter(|x| x.0 == pid)
+            .map(|x| (x.1, x.2.clone())).collect()
+    }
+}
+
+#[cfg(test)]
+impl Cannoli for PcRecorder {
+    type Trace = u64;
+    type PidContext = std::sync::Mutex<(usize, Vec<u64>)>;
+    type TidContext = ();
+
+    fn init_pid(_ci: &ClientInfo) -> Arc<Self::PidContext> {
+        Arc::new(std::sync::Mutex::new((0, Vec::new())))
+    }
+
+    fn init_tid(pid: &Self::PidContext, _ci: &ClientInfo)
+            -> (Self, Self::TidContext) {
+        pid.lock().unwrap().0 += 1;
+        (Self, ())
+    }
+
+    fn fini_pid(pid: &Self::PidContext, ci: &ClientInfo) {
+        let (threads, pcs) = std::mem::take(&mut *pid.lock().unwrap());
+        RECORDED_PCS.lock().unwrap().push((ci.pid, threads, pcs));
+    }
+
+    fn exec(_pid: &Self::PidContext, _tid: &Self::TidContext, pc: u64,
+            trace: &mut Vec<Self::Trace>) {
+        trace.push(pc);
+    }
+
+    fn trace(&mut self, pid: &Self::PidContext, _tid: &Self::TidContext,
+            _chunk: &crate::ChunkInfo, trace: &[Self::Trace]) {
+        pid.lock().unwrap().1.extend_from_slice(trace);
+    }
+}
+
+#[test]
+fn loopback_sequencing() -> Result<()> {
+    use crate::wire::Encoder;
+
+    // Send many more chunks than there are buffers, with more threads than
+    // one so they get processed out of order
+    let ci = Loopback::<PcRecorder>::client_info();
+    let mut session = Loopback::<PcRecorder>::with_client(ci.clone(), 4)?;
+    let mut expected = Vec::new();
+    for chunk in 0..1000u64 {
+        let mut enc = Encoder::new64();
+        for pc in chunk * 100..chunk * 100 + (chunk % 7) * 10 {
+            enc.exec(pc);
+            expected.push(pc);
+        }
+        session.send(enc.as_bytes())?;
+    }
+    session.finish()?;
+
+    // Everything must have been reported, in order
+    let results = PcRecorder::results(ci.pid);
+    assert!(results.len() == 1, "fini_pid was not invoked once");
+    assert!(results[0].1 == expected, "Trace was not reported in order");
+    Ok(())
+}
+
+#[test]
+fn loopback_merged() -> Result<()> {
+    use std::sync::Mutex;
+    use crate::ChunkInfo;
+    use crate::wire::Encoder;
+
+    /// Epochs and TIDs reported to `merged`, keyed by PID
+    static MERGED: Mutex<Vec<(i32, u64, i32)>> = Mutex::new(Vec::new());
+
+    /// Records the order chunks are merged in
+    struct Merged;
+
+    impl Cannoli for Merged {
+        type Trace = ();
+        type PidContext = ();
+        type TidContext = ();
+
+        const MERGE_THREADS: bool = true;
+
+        fn init_pid(_ci: &ClientInfo) -> Arc<Self::PidContext> {
+            Arc::new(())
+        }
+
+        fn init_tid(_pid: &Self::PidContext, _ci: &ClientInfo)
+                -> (Self, Self::TidContext) {
+            (Self, ())
+        }
+
+        fn merged(_pid: &Self::PidContext, ci: &ClientInfo,
+                chunk: &ChunkInfo, _trace: &[Self::Trace]) {
+            MERGED.lock().unwrap()
+                .push((ci.pid, chunk.epoch.unwrap(), ci.tid));
+        }
+    }
+
+    // Start three threads of the same process, and hand out the epochs
+    // round-robin, like the threads were running concurrently
+    let process = Loopback::<Merged>::client_info();
+    let mut threads = (0..3).map(|tid| {
+        Loopback::<Merged>::with_client(
+            ClientInfo { tid: process.pid + tid, ..process.clone() }, 2)
+    }).collect::<Result<Vec<_>>>()?;
+    for epoch in 0..300u64 {
+        let thread = &mut threads[epoch as usize % 3];
+        thread.send(Encoder::new64().epoch(epoch).exec(0x1000).as_bytes())?;
+    }
+    for thread in threads {
+        thread.finish()?;
+    }
+
+    // Every chunk must have been merged, in the order of the epochs
+    let merged = MERGED.lock().unwrap().iter()
+        .filter(|x| x.0 == process.pid).map(|x| (x.1, x.2))
+        .collect::<Vec<_>>();
+    let expected = (0..300u64)
+        .map(|x| (x, process.pid + (x % 3) as i32)).collect::<Vec<_>>();
+    assert!(merged == expected, "Chunks were not merged in order");
+
+    // A thread is waited on from when its parent reported creating it, even
+    // if it connects after younger chunks of other threads were sent
+    let process = Loopback::<Merged>::client_info();
+    let child = ClientInfo {
+        tid:        process.pid + 2,
+        parent_tid: Some(process.pid),
+        ..process.clone()
+    };
+    let mut parent = Loopback::<Merged>::with_client(process.clone(), 2)?;
+    let mut other = Loopback::<Merged>::with_client(
+        ClientInfo { tid: process.pid + 1, ..process.clone() }, 2)?;
+    parent.send(Encoder::new64().epoch(0)
+        .clone_event(child.tid, libc::CLONE_THREAD as u64).as_bytes())?;
+    parent.send(Encoder::new64().epoch(3).exec(0x1000).as_bytes())?;
+    other.send(Encoder::new64().epoch(2).exec(0x1000).as_bytes())?;
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let mut child = Loopback::<Merged>::with_client(child, 2)?;
+    child.send(Encoder::new64().epoch(1).exec(0x1000).as_bytes())?;
+    for thread in [parent, other, child] {
+        thread.finish()?;
+    }
+
+    // The child's chunk must have been merged before the younger ones
+    let merged = MERGED.lock().unwrap().iter()
+        .filter(|x| x.0 == process.pid).map(|x| (x.1, x.2 - x.0))
+        .collect::<Vec<_>>();
+    assert!(merged == [(0, 0), (1, 2), (2, 1), (3, 0)],
+        "Chunks of a new thread were not merged in order");
+    Ok(())
+}
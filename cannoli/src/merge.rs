@@ -0,0 +1,172 @@
+//! Merging of the sequential traces of all threads of a process into one,
+//! ordered by the epochs the jitter stamps chunks with
+//!
+//! Each thread of a process reports its chunks in order, and the epochs of a
+//! thread's chunks only go up. Thus the oldest chunk of the process is always
+//! at the front of one of the threads' queues, and it can be delivered once
+//! every thread which is still connected has something queued, as any chunk
+//! they send later will be younger.
+//!
+//! A new thread only gets epochs after its parent reported creating it, so
+//! once the parent's clone event was seen, the new thread is waited on too,
+//! even if it didn't connect yet. Otherwise younger chunks of other threads
+//! could be delivered before the new thread's first chunk.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use crate::{Cannoli, ChunkInfo, ClientInfo};
+
+/// Most chunks held for a process while waiting on a thread which has nothing
+/// queued. Past this, the oldest chunk is delivered even though the thread
+/// we're waiting on could still send an older one, so a thread blocked in a
+/// syscall can't make us buffer the whole trace of the others.
+const MERGE_WINDOW: usize = 1024;
+
+/// A thread taking part in the merge
+struct Thread<T: Cannoli> {
+    /// Information about the thread, `None` until a thread whose creation
+    /// was reported connects
+    ci: Option<ClientInfo>,
+
+    /// Chunks reported for the thread, in order, which weren't merged yet
+    queue: VecDeque<(ChunkInfo, Vec<T::Trace>)>,
+
+    /// Set once the thread disconnected, and won't report anything else
+    finished: bool,
+}
+
+/// Merges the traces of all threads of a process for [`Cannoli::merged`]
+pub(crate) struct Merger<T: Cannoli> {
+    /// Threads of the process, keyed by the handle from [`Merger::join`]
+    threads: BTreeMap<u64, Thread<T>>,
+
+    /// Handle to give to the next thread which joins
+    next_id: u64,
+
+    /// Handles of threads whose creation was reported, but which didn't join
+    /// yet, keyed by TID
+    expected: HashMap<i32, u64>,
+
+    /// TIDs of threads which joined before their creation was reported
+    unexpected: HashSet<i32>,
+
+    /// Number of chunks queued across all threads
+    held: usize,
+}
+
+impl<T: Cannoli> Merger<T> {
+    /// Create a merger for a process without any threads
+    pub(crate) fn new() -> Self {
+        Self {
+            threads:    BTreeMap::new(),
+            next_id:    0,
+            expected:   HashMap::new(),
+            unexpected: HashSet::new(),
+            held:       0,
+        }
+    }
+
+    /// Add the thread described by `ci` to the merge, returning the handle
+    /// to report its chunks with
+    pub(crate) fn join(&mut self, ci: &ClientInfo) -> u64 {
+        // Take the place of the thread if its creation was already reported
+        if let Some(id) = self.expected.remove(&ci.tid) {
+            self.threads.get_mut(&id).unwrap().ci = Some(ci.clone());
+            return id;
+        }
+
+        // Otherwise, if this is a new thread of the process, remember that
+        // it's here so its creation isn't waited on
+        if ci.parent_tid.is_some() && ci.tid != ci.pid {
+            self.unexpected.insert(ci.tid);
+        }
+
+        self.add(Some(ci.clone()))
+    }
+
+    /// Wait on the thread `tid`, which the thread reporting it just created,
+    /// as if it had already joined
+    pub(crate) fn expect(&mut self, tid: i32) {
+        // Nothing to do if the thread beat its parent to it
+        if self.unexpected.remove(&tid) {
+            return;
+        }
+
+        let id = self.add(None);
+        self.expected.insert(tid, id);
+    }
+
+    /// Add a thread with nothing queued to the merge, returning its handle
+    fn add(&mut self, ci: Option<ClientInfo>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.threads.insert(id, Thread {
+            ci,
+            queue:    VecDeque::new(),
+            finished: false,
+        });
+        id
+    }
+
+    /// Queue the next chunk of the thread `id`, delivering everything which
+    /// can be delivered to [`Cannoli::merged`]
+    pub(crate) fn push(&mut self, pid: &T::PidContext, id: u64,
+            chunk: ChunkInfo, trace: Vec<T::Trace>) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.queue.push_back((chunk, trace));
+            self.held += 1;
+        }
+
+        self.drain(pid);
+    }
+
+    /// Mark the thread `id` as disconnected, delivering everything which
+    /// can be delivered to [`Cannoli::merged`]
+    pub(crate) fn leave(&mut self, pid: &T::PidContext, id: u64) {
+        if let Some(thread) = self.threads.get_mut(&id) {
+            thread.finished = true;
+            if thread.queue.is_empty() {
+                self.threads.remove(&id);
+            }
+        }
+
+        self.drain(pid);
+    }
+
+    /// Deliver chunks in epoch order, until one of the threads we'd have to
+    /// wait on has nothing queued
+    fn drain(&mut self, pid: &T::PidContext) {
+        loop {
+            // Find the oldest chunk, and whether a thread could still send
+            // something older
+            let mut oldest: Option<(u64, Option<u64>)> = None;
+            let mut waiting = false;
+            for (&id, thread) in &self.threads {
+                match (thread.queue.front(), oldest) {
+                    (Some((chunk, _)), Some((_, epoch)))
+                        if epoch <= chunk.epoch => {}
+                    (Some((chunk, _)), _) => oldest = Some((id, chunk.epoch)),
+                    (None, _) => waiting |= !thread.finished,
+                }
+            }
+
+            // Wait for the other threads, unless we're holding too much
+            if waiting && self.held < MERGE_WINDOW {
+                break;
+            }
+
+            // Deliver the oldest chunk, only threads which joined have
+            // anything queued
+            let Some((id, _)) = oldest else { break };
+            let thread = self.threads.get_mut(&id).unwrap();
+            let (chunk, trace) = thread.queue.pop_front().unwrap();
+            self.held -= 1;
+            T::merged(pid, thread.ci.as_ref().unwrap(), &chunk, &trace);
+
+            // Forget about threads which are done
+            if thread.finished && thread.queue.is_empty() {
+                self.threads.remove(&id);
+            }
+        }
+    }
+}
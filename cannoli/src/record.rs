@@ -0,0 +1,845 @@
+//! Recording of the raw trace streamed by clients, for offline analysis
+//!
+//! When recording is enabled with [`CannoliServer::record`], every session
+//! gets its own trace file holding every chunk the client sent, in the order
+//! it sent them. Each chunk is compressed with LZ4 as its own block, and an
+//! index of the blocks at the end of the file allows finding a block, or the
+//! block holding a given event, without decompressing anything else.
+//!
+//! The file starts with a fixed size header:
+//!
+//! | Field        | Type                                                    |
+//! |--------------|---------------------------------------------------------|
+//! | Magic        | [`TRACE_MAGIC`]                                         |
+//! | Version      | `u32`, [`TRACE_VERSION`]                                |
+//! | Client size  | `u32`, size of the client following the header          |
+//! | Architecture | `i32`, the client's [`Architecture::id`]                |
+//! | Flags        | `u32`, bit 0 is set for big endian targets              |
+//! | Regs size    | `u32`, size of the register state in register events    |
+//! | Reserved     | `u32`                                                   |
+//! | Index offset | `u64`, zero if the recording was never finished         |
+//! | Blocks       | `u64`, number of blocks                                 |
+//! | Events       | `u64`, number of events in all blocks                   |
+//! | Joined       | `u64`, session clock when the session joined its        |
+//! |              | process                                                 |
+//! | Left         | `u64`, session clock when the session left its process, |
+//! |              | zero if the recording was never finished                |
+//!
+//! The session clock ticks whenever a session of the server joins or leaves
+//! its process, which is what starts and ends a process for [`Cannoli`].
+//! Sessions with the same PID whose lifetimes overlapped were part of the
+//! same process, while a session which joined after all of them left is part
+//! of a new process with the same PID, as created by `execve()`. The clock is
+//! shared by every server in a process, so the traces of one recording
+//! directory should come from a single run.
+//!
+//! The header is followed by the session's [`ClientInfo::to_bytes`], and then
+//! the blocks. Each block is its chunk's `u64` sequence number, the `u64`
+//! index of the first event in the block, the `u32` number of events, the
+//! `u32` size of the chunk, the `u32` size of the compressed chunk, and then
+//! the compressed chunk. The chunk itself is in the [`wire`](crate::wire)
+//! format.
+//!
+//! The index is a [`BlockInfo`] for every block, each as the `u64` sequence
+//! number, `u64` first event, `u32` events, `u32` size of the chunk, and the
+//! `u64` offset of the block in the file. All integers are little endian.
+//!
+//! If the recording was cut short, the index is missing, and it is rebuilt
+//! by walking the blocks when the file is opened.
+//!
+//! Recorded traces can be read back with a [`TraceReader`], or run through
+//! any [`Cannoli`] implementation with [`replay`].
+//!
+//! [`CannoliServer::record`]: crate::CannoliServer::record
+
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::thread::JoinHandle;
+use std::sync::mpsc::{self, SyncSender};
+use crate::{Cannoli, ClientInfo, Architecture, Error, Result, SessionResult};
+use crate::CHUNK_SIZE;
+use crate::wire::{Event, EventIter};
+use crate::regs::RegisterGroup;
+
+/// Magic at the start of every trace file
+pub const TRACE_MAGIC: [u8; 8] = *b"CNLTRACE";
+
+/// Version of the trace file format
+pub const TRACE_VERSION: u32 = 3;
+
+/// Size of the fixed header at the start of the file
+const HEADER_SIZE: usize = 72;
+
+/// Size of the header before each block
+const BLOCK_HEADER_SIZE: usize = 28;
+
+/// Size of an entry in the index
+const INDEX_ENTRY_SIZE: usize = 32;
+
+/// Number of chunks which can be waiting to be written before recording a
+/// chunk blocks, which slows the processing threads of the session down to
+/// the speed of the disk rather than buffering the trace in memory
+const RECORD_QUEUE_LEN: usize = 16;
+
+/// Flag set in the header for big endian targets
+const FLAG_BIG_ENDIAN: u32 = 1 << 0;
+
+/// Largest serialized [`ClientInfo`] we accept from a trace file, anything
+/// larger is corrupt
+const MAX_CLIENT_LEN: u32 = 64 * 1024;
+
+/// Get the path of the trace file for the session with client `ci` when
+/// recording into `dir`
+pub fn trace_path(dir: &Path, ci: &ClientInfo) -> PathBuf {
+    dir.join(format!("cannoli_{}_{}_{:016x}.trace", ci.pid, ci.tid, ci.uid))
+}
+
+/// Read a little endian `u32` at `offset` in `bytes`
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// Read a little endian `u64` at `offset` in `bytes`
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Information about a block in a trace file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockInfo {
+    /// Sequence number of the chunk held in the block
+    pub seq: u64,
+
+    /// Index of the first event in the block, counting from the start of the
+    /// trace
+    pub first_event: u64,
+
+    /// Number of events in the block
+    pub events: u32,
+
+    /// Size of the chunk once decompressed
+    pub len: u32,
+
+    /// Offset of the block in the file
+    pub offset: u64,
+}
+
+/// Summary of a trace, kept in the header
+#[derive(Clone, Copy, Debug, Default)]
+struct Summary {
+    /// Size of the register state in register events
+    regs_size: u32,
+
+    /// Offset of the index
+    index_offset: u64,
+
+    /// Number of blocks
+    blocks: u64,
+
+    /// Number of events
+    events: u64,
+
+    /// Session clock when the session joined its process
+    joined: u64,
+
+    /// Session clock when the session left its process, zero until then
+    left: u64,
+}
+
+/// Serialize the fixed header of a trace file
+fn header(ci: &ClientInfo, client_len: u32, summary: &Summary)
+        -> [u8; HEADER_SIZE] {
+    let flags = if ci.big_endian { FLAG_BIG_ENDIAN } else { 0 };
+
+    let mut header = [0u8; HEADER_SIZE];
+    header[ 0.. 8].copy_from_slice(&TRACE_MAGIC);
+    header[ 8..12].copy_from_slice(&TRACE_VERSION.to_le_bytes());
+    header[12..16].copy_from_slice(&client_len.to_le_bytes());
+    header[16..20].copy_from_slice(&ci.arch.id().to_le_bytes());
+    header[20..24].copy_from_slice(&flags.to_le_bytes());
+    header[24..28].copy_from_slice(&summary.regs_size.to_le_bytes());
+    header[32..40].copy_from_slice(&summary.index_offset.to_le_bytes());
+    header[40..48].copy_from_slice(&summary.blocks.to_le_bytes());
+    header[48..56].copy_from_slice(&summary.events.to_le_bytes());
+    header[56..64].copy_from_slice(&summary.joined.to_le_bytes());
+    header[64..72].copy_from_slice(&summary.left.to_le_bytes());
+    header
+}
+
+/// A chunk compressed into a block, ready to be written. Blocks are built by
+/// the threads processing the session, so the writer thread is left with only
+/// putting them in order and writing them out
+pub(crate) struct Block {
+    /// Sequence number of the chunk
+    seq: u64,
+
+    /// Number of events in the chunk
+    events: u32,
+
+    /// Size of the register state in the chunk's register events, zero if it
+    /// has none
+    regs_size: u32,
+
+    /// Size of the chunk
+    len: u32,
+
+    /// The chunk, compressed with LZ4
+    compressed: Vec<u8>,
+}
+
+impl Block {
+    /// Count the events of `chunk`, which has sequence number `seq`, and
+    /// compress it
+    pub(crate) fn new(seq: u64, chunk: &[u8]) -> Self {
+        // Count the events, and learn the size of the register state
+        let mut events    = 0u32;
+        let mut regs_size = 0u32;
+        for event in EventIter::new(chunk) {
+            match event {
+                Ok(Event::Regs { regs, .. } | Event::RegsDelta { regs, .. })
+                        if regs_size == 0 =>
+                    regs_size = regs.len() as u32,
+                Ok(Event::RegisterGroups { groups, .. }) if regs_size == 0 => {
+                    if let Some(gprs) = groups.get(RegisterGroup::Gpr) {
+                        regs_size = gprs.bytes.len() as u32;
+                    }
+                }
+                Ok(_)  => {}
+                Err(_) => break,
+            }
+            events += 1;
+        }
+
+        Self {
+            seq,
+            events,
+            regs_size,
+            len:        chunk.len() as u32,
+            compressed: lz4_flex::block::compress(chunk),
+        }
+    }
+}
+
+/// Writes the blocks of a trace file, in order
+struct TraceWriter {
+    /// The trace file
+    file: BufWriter<File>,
+
+    /// Client the trace is for
+    client: ClientInfo,
+
+    /// Size of the serialized client
+    client_len: u32,
+
+    /// Offset in the file of the next block
+    offset: u64,
+
+    /// Index of all blocks written so far
+    index: Vec<BlockInfo>,
+
+    /// Summary of everything written so far
+    summary: Summary,
+}
+
+impl TraceWriter {
+    /// Create the trace file at `path` for a session which joined its
+    /// process at session clock `joined`, writing its header
+    fn create(path: &Path, client: &ClientInfo, joined: u64)
+            -> std::io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        // Write the header, we'll fill in the rest of the summary once we're
+        // done
+        let summary = Summary { joined, ..Summary::default() };
+        let bytes = client.to_bytes();
+        let client_len = bytes.len() as u32;
+        file.write_all(&header(client, client_len, &summary))?;
+        file.write_all(&bytes)?;
+
+        Ok(Self {
+            file,
+            client_len,
+            summary,
+            client:  client.clone(),
+            offset:  (HEADER_SIZE + bytes.len()) as u64,
+            index:   Vec::new(),
+        })
+    }
+
+    /// Write a block after all blocks written so far
+    fn write_block(&mut self, block: &Block) -> std::io::Result<()> {
+        // Learn the size of the register state from the first block which
+        // has one
+        if self.summary.regs_size == 0 {
+            self.summary.regs_size = block.regs_size;
+        }
+
+        // Write the block
+        let mut header = [0u8; BLOCK_HEADER_SIZE];
+        header[ 0.. 8].copy_from_slice(&block.seq.to_le_bytes());
+        header[ 8..16].copy_from_slice(&self.summary.events.to_le_bytes());
+        header[16..20].copy_from_slice(&block.events.to_le_bytes());
+        header[20..24].copy_from_slice(&block.len.to_le_bytes());
+        header[24..28].copy_from_slice(
+            &(block.compressed.len() as u32).to_le_bytes());
+        self.file.write_all(&header)?;
+        self.file.write_all(&block.compressed)?;
+
+        // Track it
+        self.index.push(BlockInfo {
+            seq:         block.seq,
+            first_event: self.summary.events,
+            events:      block.events,
+            len:         block.len,
+            offset:      self.offset,
+        });
+        self.offset += (BLOCK_HEADER_SIZE + block.compressed.len()) as u64;
+        self.summary.blocks += 1;
+        self.summary.events += block.events as u64;
+        Ok(())
+    }
+
+    /// Write the index and the summary, for a session which left its process
+    /// at session clock `left`
+    fn finish(mut self, left: u64) -> std::io::Result<()> {
+        // Write the index
+        for block in &self.index {
+            let mut entry = [0u8; INDEX_ENTRY_SIZE];
+            entry[ 0.. 8].copy_from_slice(&block.seq.to_le_bytes());
+            entry[ 8..16].copy_from_slice(&block.first_event.to_le_bytes());
+            entry[16..20].copy_from_slice(&block.events.to_le_bytes());
+            entry[20..24].copy_from_slice(&block.len.to_le_bytes());
+            entry[24..32].copy_from_slice(&block.offset.to_le_bytes());
+            self.file.write_all(&entry)?;
+        }
+
+        // Now that everything is there, fill in the header
+        self.summary.index_offset = self.offset;
+        self.summary.left = left;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(
+            &header(&self.client, self.client_len, &self.summary))?;
+        self.file.flush()
+    }
+}
+
+/// Records the chunks of a session to a trace file
+///
+/// The threads processing the session turn chunks into [`Block`]s, and a
+/// background thread writes them out in order. Handing over a block only
+/// blocks if [`RECORD_QUEUE_LEN`] blocks are already waiting to be written,
+/// in which case the threads processing the session wait on the disk. Chunks
+/// are copied out of the pipe first, so the client's buffers are never held
+/// while waiting, the client only slows down once the processing threads fall
+/// behind on its pipe as well
+pub(crate) struct Recorder {
+    /// Sends blocks to the writer thread
+    tx: SyncSender<Block>,
+
+    /// Thread writing blocks to the file, returns the file once every block
+    /// was written
+    thread: JoinHandle<std::io::Result<TraceWriter>>,
+}
+
+impl Recorder {
+    /// Create the trace file for the session with client `ci` in `dir`,
+    /// which joined its process at session clock `joined`
+    pub(crate) fn create(dir: &Path, ci: &ClientInfo, joined: u64)
+            -> Result<Self> {
+        // Create the file
+        let mut file = TraceWriter::create(&trace_path(dir, ci), ci, joined)
+            .map_err(Error::Record)?;
+
+        // Start the writer
+        let (tx, rx) = mpsc::sync_channel::<Block>(RECORD_QUEUE_LEN);
+        let thread = std::thread::spawn(move || {
+            // Blocks can come in out of order from the processing threads,
+            // hold on to them until it's their turn
+            let mut pending = BTreeMap::new();
+            let mut next_seq = 0;
+
+            for block in rx {
+                pending.insert(block.seq, block);
+
+                // Write everything we can in order
+                while let Some(block) = pending.remove(&next_seq) {
+                    file.write_block(&block)?;
+                    next_seq += 1;
+                }
+            }
+
+            // The session is over, there shouldn't be anything left, but
+            // don't lose anything if there is
+            for block in pending.values() {
+                file.write_block(block)?;
+            }
+
+            Ok(file)
+        });
+
+        Ok(Self { tx, thread })
+    }
+
+    /// Get a handle to record blocks from another thread
+    pub(crate) fn sender(&self) -> SyncSender<Block> {
+        self.tx.clone()
+    }
+
+    /// Wait for everything to be written, and finish the file for a session
+    /// which left its process at session clock `left`
+    pub(crate) fn finish(self, left: u64) -> Result<()> {
+        drop(self.tx);
+        self.thread.join().map_err(|_| Error::JoinThread)?
+            .and_then(|file| file.finish(left))
+            .map_err(Error::Record)
+    }
+}
+
+/// Reads back a trace file written by a recording server
+pub struct TraceReader {
+    /// The trace file
+    file: File,
+
+    /// Client the trace was recorded from
+    client: ClientInfo,
+
+    /// Size of the register state in register events, zero if there were
+    /// none
+    regs_size: u32,
+
+    /// Index of all blocks
+    index: Vec<BlockInfo>,
+
+    /// Number of events in all blocks
+    events: u64,
+
+    /// Session clock when the session joined its process
+    joined: u64,
+
+    /// Session clock when the session left its process, if the recording was
+    /// finished
+    left: Option<u64>,
+
+    /// Index of the next block to return from [`TraceReader::next_chunk`]
+    next_block: usize,
+}
+
+impl TraceReader {
+    /// Open the trace file at `path`, reading the header and the index
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = File::open(path).map_err(Error::Record)?;
+
+        // Check the magic and version
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact(&mut header).map_err(Error::Record)?;
+        if header[..8] != TRACE_MAGIC || u32_at(&header, 8) != TRACE_VERSION {
+            return Err(Error::TraceFormat);
+        }
+
+        // Get the client
+        let client_len = u32_at(&header, 12);
+        if client_len > MAX_CLIENT_LEN {
+            return Err(Error::TraceFormat);
+        }
+        let mut client = vec![0u8; client_len as usize];
+        file.read_exact(&mut client).map_err(Error::Record)?;
+        let client = ClientInfo::from_bytes(&client)?;
+
+        // Get the index, or rebuild it if the recording was cut short
+        let index_offset = u64_at(&header, 32);
+        let index = if index_offset != 0 {
+            Self::read_index(&mut file, index_offset, u64_at(&header, 40))?
+        } else {
+            Self::rebuild_index(&mut file,
+                (HEADER_SIZE + client_len as usize) as u64)?
+        };
+
+        // Get the number of events
+        let events = index.last().map(|x| x.first_event + x.events as u64)
+            .unwrap_or(0);
+
+        // A session which left its process can't have left at tick zero, as
+        // joining it took that one
+        let left = u64_at(&header, 64);
+
+        Ok(Self {
+            regs_size:  u32_at(&header, 24),
+            joined:     u64_at(&header, 56),
+            left:       (left != 0).then_some(left),
+            next_block: 0,
+            file, client, index, events,
+        })
+    }
+
+    /// Read the index of `blocks` blocks at `offset`
+    fn read_index(file: &mut File, offset: u64, blocks: u64)
+            -> Result<Vec<BlockInfo>> {
+        // Read the whole index, making sure it's really in the file first
+        let size = blocks.checked_mul(INDEX_ENTRY_SIZE as u64)
+            .ok_or(Error::TraceFormat)?;
+        let file_size = file.metadata().map_err(Error::Record)?.len();
+        let end = offset.checked_add(size).ok_or(Error::TraceFormat)?;
+        if end > file_size {
+            return Err(Error::TraceFormat);
+        }
+        let mut index = vec![0u8; size as usize];
+        file.seek(SeekFrom::Start(offset)).map_err(Error::Record)?;
+        file.read_exact(&mut index).map_err(Error::Record)?;
+
+        Ok(index.chunks(INDEX_ENTRY_SIZE).map(|entry| BlockInfo {
+            seq:         u64_at(entry,  0),
+            first_event: u64_at(entry,  8),
+            events:      u32_at(entry, 16),
+            len:         u32_at(entry, 20),
+            offset:      u64_at(entry, 24),
+        }).collect())
+    }
+
+    /// Rebuild the index by walking the blocks starting at `offset`, up to
+    /// the last complete block
+    fn rebuild_index(file: &mut File, mut offset: u64)
+            -> Result<Vec<BlockInfo>> {
+        let file_size = file.metadata().map_err(Error::Record)?.len();
+
+        let mut index = Vec::new();
+        let mut header = [0u8; BLOCK_HEADER_SIZE];
+        while offset + BLOCK_HEADER_SIZE as u64 <= file_size {
+            // Read the block header
+            file.seek(SeekFrom::Start(offset)).map_err(Error::Record)?;
+            file.read_exact(&mut header).map_err(Error::Record)?;
+
+            // Stop at a block which was only partially written
+            let compressed = u32_at(&header, 24) as u64;
+            if offset + BLOCK_HEADER_SIZE as u64 + compressed > file_size {
+                break;
+            }
+
+            index.push(BlockInfo {
+                seq:         u64_at(&header,  0),
+                first_event: u64_at(&header,  8),
+                events:      u32_at(&header, 16),
+                len:         u32_at(&header, 20),
+                offset,
+            });
+            offset += BLOCK_HEADER_SIZE as u64 + compressed;
+        }
+
+        Ok(index)
+    }
+
+    /// Get the information about the client the trace was recorded from
+    pub fn client(&self) -> &ClientInfo {
+        &self.client
+    }
+
+    /// Get the architecture of the client
+    pub fn arch(&self) -> &Architecture {
+        &self.client.arch
+    }
+
+    /// Returns `true` if the client is big endian
+    pub fn big_endian(&self) -> bool {
+        self.client.big_endian
+    }
+
+    /// Get the size of the register state in register events, or zero if the
+    /// trace has no register events
+    pub fn regs_size(&self) -> u32 {
+        self.regs_size
+    }
+
+    /// Get the session clock when the session joined its process, see the
+    /// [module docs](self)
+    pub fn joined(&self) -> u64 {
+        self.joined
+    }
+
+    /// Get the session clock when the session left its process, or `None` if
+    /// the recording was cut short
+    pub fn left(&self) -> Option<u64> {
+        self.left
+    }
+
+    /// Get the index of all blocks in the trace
+    pub fn blocks(&self) -> &[BlockInfo] {
+        &self.index
+    }
+
+    /// Get the number of events in the trace
+    pub fn events(&self) -> u64 {
+        self.events
+    }
+
+    /// Find the block holding event number `event`, returning the index of
+    /// the block and the index of the event within the block
+    pub fn find_event(&self, event: u64) -> Option<(usize, usize)> {
+        // Find the last block starting at or before the event
+        let idx = self.index.partition_point(|x| x.first_event <= event)
+            .checked_sub(1)?;
+        let block = &self.index[idx];
+
+        let within = event - block.first_event;
+        (within < block.events as u64).then_some((idx, within as usize))
+    }
+
+    /// Read and decompress the chunk in the block at index `idx`
+    pub fn read_block(&mut self, idx: usize) -> Result<Vec<u8>> {
+        let block = *self.index.get(idx).ok_or(Error::TraceFormat)?;
+        if block.len as usize > CHUNK_SIZE {
+            return Err(Error::TraceFormat);
+        }
+
+        // Read the compressed block
+        let mut header = [0u8; BLOCK_HEADER_SIZE];
+        self.file.seek(SeekFrom::Start(block.offset)).map_err(Error::Record)?;
+        self.file.read_exact(&mut header).map_err(Error::Record)?;
+        let compressed = u32_at(&header, 24);
+        if compressed as usize > lz4_flex::block::get_maximum_output_size(
+                CHUNK_SIZE) {
+            return Err(Error::TraceFormat);
+        }
+        let mut data = vec![0u8; compressed as usize];
+        self.file.read_exact(&mut data).map_err(Error::Record)?;
+
+        // Decompress it
+        let chunk = lz4_flex::block::decompress(&data, block.len as usize)
+            .map_err(|_| Error::TraceFormat)?;
+        if chunk.len() != block.len as usize {
+            return Err(Error::TraceFormat);
+        }
+        Ok(chunk)
+    }
+
+    /// Read the next chunk, returning its sequence number and contents, or
+    /// `None` at the end of the trace
+    pub fn next_chunk(&mut self) -> Result<Option<(u64, Vec<u8>)>> {
+        // Check for the end of the trace
+        let Some(block) = self.index.get(self.next_block) else {
+            return Ok(None);
+        };
+        let seq = block.seq;
+
+        let chunk = self.read_block(self.next_block)?;
+        self.next_block += 1;
+        Ok(Some((seq, chunk)))
+    }
+
+    /// Make [`TraceReader::next_chunk`] continue from the block at index
+    /// `idx`
+    pub fn seek_block(&mut self, idx: usize) {
+        self.next_block = idx;
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = Result<(u64, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_chunk().transpose()
+    }
+}
+
+/// Replay recorded traces through `T`, processing each with `threads`
+/// threads, exactly as the server would have processed them live
+///
+/// `path` is either a single trace file, or a directory of them like the one
+/// passed to [`CannoliServer::record`]. All threads of a process are replayed
+/// at the same time, so they share the process's context and
+/// [`Cannoli::fini_pid`] is invoked once they are all done. Processes are
+/// replayed one after another, in the order they connected, and the process
+/// an `execve()` replaced is finished before its replacement with the same
+/// PID starts, like on the live server.
+///
+/// Returns the result of every session, like [`ServerHandle::join`].
+///
+/// [`CannoliServer::record`]: crate::CannoliServer::record
+/// [`ServerHandle::join`]: crate::ServerHandle::join
+#[cfg(target_os = "linux")]
+pub fn replay<T>(path: impl AsRef<Path>, threads: usize)
+        -> Result<Vec<SessionResult>>
+            where T: Cannoli + 'static,
+                  T::PidContext: Send + Sync + 'static {
+    use crate::loopback::Loopback;
+
+    // Find the trace files
+    let path = path.as_ref();
+    let files = if path.is_dir() {
+        let mut files = std::fs::read_dir(path).map_err(Error::Record)?
+            .map(|entry| entry.map(|x| x.path()))
+            .collect::<std::io::Result<Vec<_>>>().map_err(Error::Record)?;
+        files.retain(|x| x.extension() == Some("trace".as_ref()));
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    // Open all of them, in the order their sessions joined their processes
+    let mut readers = files.into_iter().map(TraceReader::open)
+        .collect::<Result<Vec<_>>>()?;
+    readers.sort_by_key(|x| x.joined());
+
+    // Group them by process. A session joins the latest process with its PID
+    // if that process was still running, otherwise it starts a new one, like
+    // after an `execve()`. Recordings which were cut short are considered to
+    // have run until the end.
+    let mut processes: Vec<(i32, u64, Vec<TraceReader>)> = Vec::new();
+    for reader in readers {
+        let pid  = reader.client().pid;
+        let left = reader.left().unwrap_or(u64::MAX);
+        match processes.iter_mut().rev().find(|x| x.0 == pid) {
+            Some((_, running, readers)) if reader.joined() < *running => {
+                *running = (*running).max(left);
+                readers.push(reader);
+            }
+            _ => processes.push((pid, left, vec![reader])),
+        }
+    }
+
+    // Replay each process
+    let mut results = Vec::new();
+    for (_, _, readers) in processes {
+        std::thread::scope(|s| {
+            // Replay every thread of the process at the same time
+            let sessions = readers.into_iter().map(|mut reader| {
+                s.spawn(move || {
+                    let client = reader.client().clone();
+
+                    // Feed the session everything that was recorded
+                    let result = Loopback::<T>::with_client(
+                            client.clone(), threads)
+                        .and_then(|mut session| {
+                            let sent = reader.try_for_each(|chunk| {
+                                session.send(&chunk?.1)
+                            });
+
+                            // If the session failed, that's the interesting
+                            // error rather than failing to send to it
+                            session.finish().and(sent)
+                        });
+
+                    SessionResult { client: Some(client), result }
+                })
+            }).collect::<Vec<_>>();
+
+            // Wait for all of them
+            results.extend(sessions.into_iter().map(|session| {
+                session.join().unwrap_or(SessionResult {
+                    client: None,
+                    result: Err(Error::JoinThread),
+                })
+            }));
+        });
+    }
+
+    Ok(results)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn record_replay() -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    use crate::wire::Encoder;
+    use crate::loopback::{Loopback, PcRecorder};
+
+    // Record two threads of the same process, which join it at session clock
+    // 0 and 1, and leave it at 2 and 3
+    let dir = std::env::temp_dir()
+        .join(format!("cannoli_record_replay_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).map_err(Error::Record)?;
+    let process = Loopback::<PcRecorder>::client_info();
+    let mut expected = Vec::new();
+    for tid in 0..2 {
+        // The second thread claims to be an architecture we don't know
+        let arch = if tid == 0 { process.arch.clone() } else {
+            Architecture::Unknown("e2k".into())
+        };
+        let ci = ClientInfo { tid: process.pid + tid, uid: tid as u64,
+            exe: Some("/bin/true".into()), argv: vec!["true".into(), "".into()],
+            envp: vec!["A=b".into()], at_entry: 0x1000, arch,
+            ..process.clone()
+        };
+        let recorder = Recorder::create(&dir, &ci, tid as u64)?;
+        let sender = recorder.sender();
+
+        // Send the chunks out of order, they must be written in order
+        for seq in (0..100u64).rev() {
+            let mut enc = Encoder::new64();
+            for event in 0..seq % 3 + 1 {
+                let pc = ((tid as u64) << 32) | (seq << 8) | event;
+                enc.exec(pc);
+                expected.push(pc);
+            }
+            sender.send(Block::new(seq, enc.as_bytes())).unwrap();
+        }
+        drop(sender);
+        recorder.finish(tid as u64 + 2)?;
+
+        // Make sure it reads back in order
+        let path = trace_path(&dir, &ci);
+        let seqs = TraceReader::open(&path)?
+            .map(|x| x.map(|x| x.0)).collect::<Result<Vec<_>>>()?;
+        assert!(seqs == (0..100).collect::<Vec<_>>());
+
+        // Find an event in the middle, the 34 blocks before block 34 hold
+        // 67 events
+        let mut reader = TraceReader::open(&path)?;
+        let client = reader.client();
+        assert!(client.exe == ci.exe && client.argv == ci.argv);
+        assert!(client.envp == ci.envp && client.at_entry == 0x1000);
+        assert!(client.gpr_width == 8 && client.num_gprs == 16);
+        assert!(reader.arch() == &ci.arch);
+        assert!(reader.joined() == tid as u64);
+        assert!(reader.left() == Some(tid as u64 + 2));
+        assert!(reader.events() == 199 && reader.blocks().len() == 100);
+        assert!(reader.find_event(68) == Some((34, 1)));
+        assert!(reader.find_event(198) == Some((99, 0)));
+        assert!(reader.find_event(199).is_none());
+        let pcs = EventIter::new(&reader.read_block(34)?).map(|x| match x {
+            Ok(Event::Exec { pc }) => pc & 0xffff_ffff,
+            _ => panic!("Unexpected event"),
+        }).collect::<Vec<_>>();
+        assert!(pcs == [34 << 8, (34 << 8) | 1]);
+
+        // Cut a copy short like an interrupted recording, every complete
+        // block must still be found
+        let partial = path.with_extension("partial");
+        std::fs::copy(&path, &partial).map_err(Error::Record)?;
+        let file = std::fs::OpenOptions::new().write(true).open(&partial)
+            .map_err(Error::Record)?;
+        file.set_len(reader.blocks()[99].offset + 1).map_err(Error::Record)?;
+        file.write_all_at(&[0u8; 8], 32).map_err(Error::Record)?;
+        let truncated = TraceReader::open(&partial)?;
+        assert!(truncated.blocks() == &reader.blocks()[..99]);
+        assert!(truncated.events() == 198);
+    }
+
+    // Record the process the first thread `execve()`d into, which connects
+    // under the same PID once both threads left
+    let ci = ClientInfo { uid: 2, ..process.clone() };
+    let recorder = Recorder::create(&dir, &ci, 4)?;
+    let exec_pcs = vec![0x2000, 0x2004];
+    let mut enc = Encoder::new64();
+    exec_pcs.iter().for_each(|&pc| { enc.exec(pc); });
+    recorder.sender().send(Block::new(0, enc.as_bytes())).unwrap();
+    recorder.finish(5)?;
+
+    // Replay it, both threads should be part of the same process, and the
+    // exec'd process a new process once they finished
+    let sessions = replay::<PcRecorder>(&dir, 2)?;
+    std::fs::remove_dir_all(&dir).map_err(Error::Record)?;
+    assert!(sessions.len() == 3);
+    assert!(sessions[2].client.as_ref().unwrap().uid == 2);
+    for session in sessions {
+        session.result?;
+    }
+
+    // The threads ran concurrently, so only the set of PCs is known
+    expected.sort();
+    let mut results = PcRecorder::results(process.pid);
+    assert!(results.len() == 2, "fini_pid was not invoked per process");
+    results[0].1.sort();
+    assert!(results[0] == (2, expected) && results[1] == (1, exec_pcs));
+    Ok(())
+}
@@ -0,0 +1,706 @@
+//! Named decoding of the register state sent with register hooks
+//!
+//! The `regs` blob passed to [`Cannoli::regs`] is a raw copy of the general
+//! purpose registers out of QEMU's `CPUArchState`, in the order QEMU keeps
+//! them. A [`RegisterFile`] knows that layout for each [`Architecture`], and
+//! decodes the blob into named registers of the correct width, with accessors
+//! for the registers analyses usually care about.
+//!
+//! QEMU keeps guest registers in host byte order, even for big-endian guests,
+//! so the blob is always decoded as little-endian. The guest's endianness is
+//! still recorded, for users which want to interpret memory the registers
+//! point to.
+//!
+//! Register hooks can also capture other groups of registers, like the
+//! floating point and vector registers, which are passed to
+//! [`Cannoli::register_groups`] as [`RegisterSections`]. Each section holds
+//! one [`RegisterGroup`], as a number of registers of the same width.
+//!
+//! Register delta hooks only send the registers which changed since the
+//! previous register delta event of the chunk, the first one of each chunk
+//! holding all of them. [`RegisterDeltas`] rebuilds the full register state
+//! out of them, which is what [`Cannoli::regs`] gets.
+//!
+//! [`Cannoli::regs`]: crate::Cannoli::regs
+//! [`Cannoli::register_groups`]: crate::Cannoli::register_groups
+
+use crate::{Architecture, ClientInfo, Error, Result};
+
+/// Size of the header starting every section of a register groups event, the
+/// group in the low byte, the width of its registers in the next byte, and
+/// the number of registers in the upper 16 bits
+pub const SECTION_HEADER_SIZE: usize = 4;
+
+/// Layout of the register state of an architecture
+struct Layout {
+    /// Width of each register, in bytes
+    width: usize,
+
+    /// Names of the registers, in the order QEMU keeps them
+    names: &'static [&'static str],
+
+    /// Index of the stack pointer
+    sp: Option<usize>,
+
+    /// Index of the register holding the return address
+    ra: Option<usize>,
+
+    /// Indices of the registers arguments are passed in, in order
+    args: &'static [usize],
+}
+
+/// Names of the x86_64 registers, `CPUX86State::regs`
+const X86_64_REGS: &[&str] = &[
+    "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10",
+    "r11", "r12", "r13", "r14", "r15",
+];
+
+/// Names of the i386 registers, `CPUX86State::regs`
+const I386_REGS: &[&str] = &[
+    "eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+];
+
+/// Names of the AArch64 registers, `CPUARMState::xregs`
+const AARCH64_REGS: &[&str] = &[
+    "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11",
+    "x12", "x13", "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21",
+    "x22", "x23", "x24", "x25", "x26", "x27", "x28", "x29", "x30", "sp",
+];
+
+/// Names of the 32-bit ARM registers, `CPUARMState::regs`
+const ARM_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "sp", "lr", "pc",
+];
+
+/// Names of the MIPS registers with the o32 ABI, `TCState::gpr`
+const MIPS_REGS: &[&str] = &[
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3",
+    "t4", "t5", "t6", "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+/// Names of the MIPS registers with the n64 ABI, `TCState::gpr`
+const MIPS64_REGS: &[&str] = &[
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+    "t0", "t1", "t2", "t3", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra",
+];
+
+/// Names of the RISC-V registers, `CPURISCVState::gpr`
+const RISCV_REGS: &[&str] = &[
+    "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7", "x8", "x9", "x10", "x11",
+    "x12", "x13", "x14", "x15", "x16", "x17", "x18", "x19", "x20", "x21",
+    "x22", "x23", "x24", "x25", "x26", "x27", "x28", "x29", "x30", "x31",
+];
+
+/// Names of the PowerPC registers, `CPUPPCState::gpr`
+const PPC_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21",
+    "r22", "r23", "r24", "r25", "r26", "r27", "r28", "r29", "r30", "r31",
+];
+
+/// Names of the s390x registers, `CPUS390XState::regs`
+const S390X_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15",
+];
+
+/// Names of the SPARC global registers, `CPUSPARCState::gregs`, the
+/// windowed registers are not part of the register state
+const SPARC_REGS: &[&str] = &[
+    "g0", "g1", "g2", "g3", "g4", "g5", "g6", "g7",
+];
+
+/// Names of the m68k registers, `CPUM68KState::dregs` followed by
+/// `CPUM68KState::aregs`
+const M68K_REGS: &[&str] = &[
+    "d0", "d1", "d2", "d3", "d4", "d5", "d6", "d7", "a0", "a1", "a2", "a3",
+    "a4", "a5", "a6", "a7",
+];
+
+/// Names of the Alpha registers, `CPUAlphaState::ir`
+const ALPHA_REGS: &[&str] = &[
+    "v0", "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7", "s0", "s1", "s2",
+    "s3", "s4", "s5", "fp", "a0", "a1", "a2", "a3", "a4", "a5", "t8", "t9",
+    "t10", "t11", "ra", "t12", "at", "gp", "sp",
+];
+
+/// Names of the CRIS registers, `CPUCRISState::regs`
+const CRIS_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "sp", "acr",
+];
+
+/// Names of the Hexagon registers, `CPUHexagonState::gpr`, the general
+/// purpose registers followed by the control registers
+const HEXAGON_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21",
+    "r22", "r23", "r24", "r25", "r26", "r27", "r28", "sp", "fp", "lr", "sa0",
+    "lc0", "sa1", "lc1", "p3_0", "c5", "m0", "m1", "usr", "pc", "ugp", "gp",
+    "cs0", "cs1", "upcyclelo", "upcyclehi", "framelimit", "framekey",
+    "pktcountlo", "pktcounthi", "c20", "c21", "c22", "c23", "c24", "c25",
+    "c26", "c27", "c28", "c29", "utimerlo", "utimerhi",
+];
+
+/// Names of the MicroBlaze registers, `CPUMBState::regs`
+const MICROBLAZE_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21",
+    "r22", "r23", "r24", "r25", "r26", "r27", "r28", "r29", "r30", "r31",
+];
+
+/// Names of the Nios II registers, `CPUNios2State::regs`
+const NIOS2_REGS: &[&str] = &[
+    "zero", "at", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21",
+    "r22", "r23", "et", "bt", "gp", "sp", "fp", "ea", "ba", "ra",
+];
+
+/// Names of the OpenRISC registers, the first set of
+/// `CPUOpenRISCState::shadow_gpr`
+const OPENRISC_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21",
+    "r22", "r23", "r24", "r25", "r26", "r27", "r28", "r29", "r30", "r31",
+];
+
+/// Names of the PA-RISC registers, `CPUHPPAState::gr`
+const PARISC_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21",
+    "r22", "r23", "r24", "r25", "r26", "r27", "r28", "r29", "r30", "r31",
+];
+
+/// Names of the SH-4 registers, `CPUSH4State::gregs`, followed by the
+/// other bank of `r0` through `r7`
+const SH4_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15", "r0b", "r1b", "r2b", "r3b", "r4b", "r5b",
+    "r6b", "r7b",
+];
+
+/// Names of the Xtensa registers, `CPUXtensaState::regs`
+const XTENSA_REGS: &[&str] = &[
+    "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "a8", "a9", "a10", "a11",
+    "a12", "a13", "a14", "a15",
+];
+
+/// Names of the AVR registers, `CPUAVRState::r`
+const AVR_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21",
+    "r22", "r23", "r24", "r25", "r26", "r27", "r28", "r29", "r30", "r31",
+];
+
+/// Names of the LoongArch registers, `CPULoongArchState::gpr`
+const LOONGARCH_REGS: &[&str] = &[
+    "zero", "ra", "tp", "sp", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7", "t8", "r21", "fp", "s0",
+    "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8",
+];
+
+/// Names of the RX registers, `CPURXState::regs`
+const RX_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15",
+];
+
+/// Names of the TriCore registers, `CPUTriCoreState::gpr_a` followed by
+/// `CPUTriCoreState::gpr_d`
+const TRICORE_REGS: &[&str] = &[
+    "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "a8", "a9", "a10", "a11",
+    "a12", "a13", "a14", "a15", "d0", "d1", "d2", "d3", "d4", "d5", "d6",
+    "d7", "d8", "d9", "d10", "d11", "d12", "d13", "d14", "d15",
+];
+
+/// Names for the registers of architectures we don't know about, which are
+/// just numbered
+const GENERIC_REGS: &[&str] = &[
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11",
+    "r12", "r13", "r14", "r15", "r16", "r17", "r18", "r19", "r20", "r21",
+    "r22", "r23", "r24", "r25", "r26", "r27", "r28", "r29", "r30", "r31",
+    "r32", "r33", "r34", "r35", "r36", "r37", "r38", "r39", "r40", "r41",
+    "r42", "r43", "r44", "r45", "r46", "r47", "r48", "r49", "r50", "r51",
+    "r52", "r53", "r54", "r55", "r56", "r57", "r58", "r59", "r60", "r61",
+    "r62", "r63",
+];
+
+/// Get the register layout of `arch`
+fn layout(arch: &Architecture) -> Layout {
+    use Architecture::*;
+
+    // Helper to build a layout
+    let lay = |width, names, sp, ra, args| {
+        Layout { width, names, sp, ra, args }
+    };
+
+    match arch {
+        X86_64 =>
+            lay(8, X86_64_REGS, Some(4), None, &[7, 6, 2, 1, 8, 9]),
+        I386 | I686 =>
+            lay(4, I386_REGS, Some(4), None, &[]),
+        Aarch64 | Aarch64be =>
+            lay(8, AARCH64_REGS, Some(31), Some(30), &[0, 1, 2, 3, 4, 5, 6, 7]),
+        Armv5teb | Armv5tel =>
+            lay(4, ARM_REGS, Some(13), Some(14), &[0, 1, 2, 3]),
+        Mips =>
+            lay(4, MIPS_REGS, Some(29), Some(31), &[4, 5, 6, 7]),
+        Mips64 =>
+            lay(8, MIPS64_REGS, Some(29), Some(31),
+                &[4, 5, 6, 7, 8, 9, 10, 11]),
+        Riscv32 =>
+            lay(4, RISCV_REGS, Some(2), Some(1),
+                &[10, 11, 12, 13, 14, 15, 16, 17]),
+        Riscv64 =>
+            lay(8, RISCV_REGS, Some(2), Some(1),
+                &[10, 11, 12, 13, 14, 15, 16, 17]),
+        Ppc =>
+            lay(4, PPC_REGS, Some(1), None, &[3, 4, 5, 6, 7, 8, 9, 10]),
+        Ppc64 | Ppc64le =>
+            lay(8, PPC_REGS, Some(1), None, &[3, 4, 5, 6, 7, 8, 9, 10]),
+        S390x =>
+            lay(8, S390X_REGS, Some(15), Some(14), &[2, 3, 4, 5, 6]),
+        Sparc =>
+            lay(4, SPARC_REGS, None, None, &[]),
+        Sparc64 =>
+            lay(8, SPARC_REGS, None, None, &[]),
+        M68k =>
+            lay(4, M68K_REGS, Some(15), None, &[]),
+        Alpha =>
+            lay(8, ALPHA_REGS, Some(30), Some(26), &[16, 17, 18, 19, 20, 21]),
+        Cris =>
+            lay(4, CRIS_REGS, Some(14), None, &[10, 11, 12, 13]),
+        Hexagon =>
+            lay(4, HEXAGON_REGS, Some(29), Some(31), &[0, 1, 2, 3, 4, 5]),
+        Microblaze =>
+            lay(4, MICROBLAZE_REGS, Some(1), Some(15), &[5, 6, 7, 8, 9, 10]),
+        Nios2 =>
+            lay(4, NIOS2_REGS, Some(27), Some(31), &[4, 5, 6, 7]),
+        Openrisc =>
+            lay(4, OPENRISC_REGS, Some(1), Some(9), &[3, 4, 5, 6, 7, 8]),
+        Parisc =>
+            lay(4, PARISC_REGS, Some(30), Some(2), &[26, 25, 24, 23]),
+        Sh4 | Sh4eb =>
+            lay(4, SH4_REGS, Some(15), None, &[4, 5, 6, 7]),
+        Xtensa =>
+            lay(4, XTENSA_REGS, Some(1), Some(0), &[2, 3, 4, 5, 6, 7]),
+        Avr =>
+            lay(4, AVR_REGS, None, None, &[]),
+        Loongarch64 =>
+            lay(8, LOONGARCH_REGS, Some(3), Some(1),
+                &[4, 5, 6, 7, 8, 9, 10, 11]),
+        Rx =>
+            lay(4, RX_REGS, Some(0), None, &[1, 2, 3, 4]),
+        Tricore =>
+            lay(4, TRICORE_REGS, Some(10), Some(11), &[20, 21, 22, 23]),
+        Unknown(_) =>
+            lay(8, GENERIC_REGS, None, None, &[]),
+    }
+}
+
+/// Decode register `idx` of `width` bytes out of `regs`, zero extended to 64
+/// bits, `None` if the blob is too short to hold it
+fn decode(regs: &[u8], idx: usize, width: usize) -> Option<u64> {
+    let bytes = regs.get(idx * width..(idx + 1) * width)?;
+
+    // Zero extend the register to 64 bits
+    let mut val = [0u8; 8];
+    val[..bytes.len()].copy_from_slice(bytes);
+    Some(u64::from_le_bytes(val))
+}
+
+/// Decoder for the register state of a guest, see the [module docs](self)
+#[derive(Clone, Debug)]
+pub struct RegisterFile {
+    /// Architecture of the guest
+    arch: Architecture,
+
+    /// Set if the guest is big-endian
+    big_endian: bool,
+
+    /// Width of each register, in bytes
+    width: usize,
+
+    /// Names of the registers, in the order QEMU keeps them
+    names: &'static [&'static str],
+
+    /// Index of the stack pointer
+    sp: Option<usize>,
+
+    /// Index of the register holding the return address
+    ra: Option<usize>,
+
+    /// Indices of the registers arguments are passed in, in order
+    args: &'static [usize],
+}
+
+impl RegisterFile {
+    /// Get the register file of a guest running `arch`
+    pub fn new(arch: Architecture, big_endian: bool) -> Self {
+        let Layout { width, names, sp, ra, args } = layout(&arch);
+        Self { arch, big_endian, width, names, sp, ra, args }
+    }
+
+    /// Get the register file of the guest a client is running. The register
+    /// width the client reported takes precedence over the one we know for
+    /// the architecture. The registers of architectures we don't know about
+    /// are numbered, as many as the client said it sends
+    pub fn for_client(ci: &ClientInfo) -> Self {
+        let mut file = Self::new(ci.arch.clone(), ci.big_endian);
+        if matches!(ci.gpr_width, 1..=8) {
+            file.width = ci.gpr_width;
+        }
+        if let Architecture::Unknown(_) = file.arch {
+            file.names = &file.names[..ci.num_gprs.min(file.names.len())];
+        }
+        file
+    }
+
+    /// Architecture of the guest
+    pub fn arch(&self) -> &Architecture {
+        &self.arch
+    }
+
+    /// Returns `true` if the guest is big-endian
+    pub fn big_endian(&self) -> bool {
+        self.big_endian
+    }
+
+    /// Width of each register, in bytes
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Names of the registers, in the order they appear in the blob
+    pub fn names(&self) -> &'static [&'static str] {
+        self.names
+    }
+
+    /// Look up the index of the register named `name`, with or without a
+    /// leading `$`
+    pub fn index(&self, name: &str) -> Option<usize> {
+        let name = name.strip_prefix('$').unwrap_or(name);
+        self.names.iter().position(|x| x.eq_ignore_ascii_case(name))
+    }
+
+    /// Decode register `idx` out of `regs`, `None` if the blob is too short
+    /// to hold it
+    pub fn get(&self, regs: &[u8], idx: usize) -> Option<u64> {
+        // Bounds check against the names, then against the blob
+        if idx >= self.names.len() {
+            return None;
+        }
+        decode(regs, idx, self.width)
+    }
+
+    /// Decode the register named `name` out of `regs`
+    pub fn by_name(&self, regs: &[u8], name: &str) -> Option<u64> {
+        self.get(regs, self.index(name)?)
+    }
+
+    /// Decode all named registers present in `regs`, in order
+    pub fn iter<'a>(&self, regs: &'a [u8])
+            -> impl Iterator<Item = (&'static str, u64)> + 'a {
+        let width = self.width;
+        self.names.iter().enumerate().map_while(move |(idx, name)| {
+            Some((*name, decode(regs, idx, width)?))
+        })
+    }
+
+    /// Decode the stack pointer, `None` if the architecture has no dedicated
+    /// stack pointer register
+    pub fn sp(&self, regs: &[u8]) -> Option<u64> {
+        self.get(regs, self.sp?)
+    }
+
+    /// Decode the return address register, `None` if the architecture keeps
+    /// return addresses on the stack
+    pub fn ra(&self, regs: &[u8]) -> Option<u64> {
+        self.get(regs, self.ra?)
+    }
+
+    /// Number of arguments passed in registers by the guest's calling
+    /// convention
+    pub fn num_args(&self) -> usize {
+        self.args.len()
+    }
+
+    /// Decode argument `n` (zero-indexed) of the guest's calling convention,
+    /// `None` if it isn't passed in a register
+    pub fn arg(&self, regs: &[u8], n: usize) -> Option<u64> {
+        self.get(regs, *self.args.get(n)?)
+    }
+
+    /// Decode all arguments passed in registers, in order
+    pub fn args<'a>(&self, regs: &'a [u8]) -> impl Iterator<Item = u64> + 'a {
+        let width = self.width;
+        self.args.iter().map_while(move |&idx| decode(regs, idx, width))
+    }
+}
+
+/// A group of registers a register hook can capture
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum RegisterGroup {
+    /// The general purpose registers, in the layout [`RegisterFile`] decodes
+    Gpr = 0,
+
+    /// Scalar floating point registers. The x87 registers `R0` through `R7`
+    /// (10 bytes each, not rotated by the top of stack), or the MIPS FPRs
+    Float = 1,
+
+    /// Vector registers. The x86 XMM registers, the ARM NEON registers which
+    /// also hold the VFP registers, or the MIPS MSA registers
+    Vector = 2,
+}
+
+impl RegisterGroup {
+    /// Get the bit for this group in a mask of groups
+    pub const fn mask(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+impl TryFrom<u8> for RegisterGroup {
+    type Error = Error;
+
+    fn try_from(val: u8) -> Result<Self> {
+        Ok(match val {
+            0 => Self::Gpr,
+            1 => Self::Float,
+            2 => Self::Vector,
+            _ => return Err(Error::InvalidRegisterGroup(val)),
+        })
+    }
+}
+
+/// The registers of one [`RegisterGroup`] captured by a register hook
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegisterSection<'a> {
+    /// The group of registers
+    pub group: RegisterGroup,
+
+    /// Width of each register, in bytes
+    pub width: usize,
+
+    /// The raw registers, back to back
+    pub bytes: &'a [u8],
+}
+
+impl<'a> RegisterSection<'a> {
+    /// Number of registers in the section
+    pub fn count(&self) -> usize {
+        self.bytes.len().checked_div(self.width).unwrap_or(0)
+    }
+
+    /// Get the raw bytes of register `idx`
+    pub fn get(&self, idx: usize) -> Option<&'a [u8]> {
+        self.bytes.get(idx * self.width..(idx + 1) * self.width)
+    }
+
+    /// Decode register `idx` as a little-endian integer, `None` if it doesn't
+    /// exist or is wider than 16 bytes
+    pub fn value(&self, idx: usize) -> Option<u128> {
+        let bytes = self.get(idx).filter(|x| x.len() <= 16)?;
+        let mut val = [0u8; 16];
+        val[..bytes.len()].copy_from_slice(bytes);
+        Some(u128::from_le_bytes(val))
+    }
+}
+
+/// The sections of a register groups event, see the [module docs](self)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegisterSections<'a> {
+    /// Raw sections, validated when they were decoded
+    raw: &'a [u8],
+}
+
+impl<'a> RegisterSections<'a> {
+    /// Validate the sections in `raw`
+    pub fn parse(raw: &'a [u8]) -> Result<Self> {
+        // Walk every section, making sure it's complete and of a known group
+        let mut rem = raw;
+        while !rem.is_empty() {
+            let (_, len) = section_header(rem)?;
+            rem = rem.get(SECTION_HEADER_SIZE + len..)
+                .ok_or(Error::BufferTruncated)?;
+        }
+
+        Ok(Self { raw })
+    }
+
+    /// Get the raw sections, in the format they are sent in
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// Iterate over the sections, in the order they were captured
+    pub fn iter(&self) -> impl Iterator<Item = RegisterSection<'a>> {
+        let mut rem = self.raw;
+        std::iter::from_fn(move || {
+            // The sections were validated in `parse()`
+            let (section, len) = section_header(rem).ok()?;
+            let bytes = &rem[SECTION_HEADER_SIZE..][..len];
+            rem = &rem[SECTION_HEADER_SIZE + len..];
+            Some(RegisterSection { bytes, ..section })
+        })
+    }
+
+    /// Get the section holding `group`, if it was captured
+    pub fn get(&self, group: RegisterGroup) -> Option<RegisterSection<'a>> {
+        self.iter().find(|x| x.group == group)
+    }
+}
+
+/// Decode the header of the section at the start of `raw`, returning the
+/// section without its bytes and the length of its bytes
+fn section_header(raw: &[u8]) -> Result<(RegisterSection<'static>, usize)> {
+    let header = raw.get(..SECTION_HEADER_SIZE)
+        .ok_or(Error::BufferTruncated)?;
+    let header = u32::from_le_bytes(header.try_into().unwrap());
+
+    // Split up the header
+    let group = RegisterGroup::try_from(header as u8)?;
+    let width = (header >> 8) as u8 as usize;
+    let count = (header >> 16) as usize;
+
+    Ok((RegisterSection { group, width, bytes: &[] }, width * count))
+}
+
+/// Build the header of a section of `count` registers of `group`, each
+/// `width` bytes wide
+pub const fn section_header_for(group: RegisterGroup, width: u8, count: u16)
+        -> u32 {
+    group as u32 | (width as u32) << 8 | (count as u32) << 16
+}
+
+/// Rebuilds the full register state out of the register delta events of a
+/// chunk
+#[derive(Clone, Debug, Default)]
+pub struct RegisterDeltas {
+    /// Width of each register, in bytes, learned from the snapshot
+    width: usize,
+
+    /// The register state, empty until the snapshot was applied
+    regs: Vec<u8>,
+}
+
+impl RegisterDeltas {
+    /// Create an empty state, the next delta applied must be a snapshot
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget the register state, like at the start of a chunk
+    pub fn reset(&mut self) {
+        self.width = 0;
+        self.regs.clear();
+    }
+
+    /// Apply a delta of the registers set in `mask`, whose new values are in
+    /// `regs`, returning the full register state
+    pub fn apply(&mut self, mask: u64, regs: &[u8]) -> Result<&[u8]> {
+        // Nothing changed
+        let changed = mask.count_ones() as usize;
+        if changed == 0 {
+            return if regs.is_empty() && !self.regs.is_empty() {
+                Ok(&self.regs)
+            } else {
+                Err(Error::InvalidRegisterDelta)
+            };
+        }
+
+        // Get the width of the registers
+        let width = regs.len() / changed;
+        if width == 0 || width * changed != regs.len() {
+            return Err(Error::InvalidRegisterDelta);
+        }
+
+        // The first delta is a snapshot of all registers
+        if self.regs.is_empty() {
+            if mask & mask.wrapping_add(1) != 0 {
+                return Err(Error::InvalidRegisterDelta);
+            }
+            self.width = width;
+            self.regs.extend_from_slice(regs);
+            return Ok(&self.regs);
+        }
+
+        // Update the registers which changed
+        if width != self.width {
+            return Err(Error::InvalidRegisterDelta);
+        }
+        let mut new = regs.chunks_exact(width);
+        for idx in (0..u64::BITS as usize).filter(|x| mask & (1 << x) != 0) {
+            self.regs.get_mut(idx * width..(idx + 1) * width)
+                .ok_or(Error::InvalidRegisterDelta)?
+                .copy_from_slice(new.next().unwrap());
+        }
+
+        Ok(&self.regs)
+    }
+}
+
+#[test]
+fn register_decode() {
+    // Build an x86_64 blob where each register holds its index
+    let regs = (0..16u64).flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+    let file = RegisterFile::new(Architecture::X86_64, false);
+    assert_eq!(file.by_name(&regs, "rsp"), Some(4));
+    assert_eq!(file.by_name(&regs, "$R15"), Some(15));
+    assert_eq!(file.sp(&regs), Some(4));
+    assert_eq!(file.ra(&regs), None);
+    assert_eq!(file.args(&regs).collect::<Vec<_>>(), [7, 6, 2, 1, 8, 9]);
+    assert_eq!(file.iter(&regs).count(), 16);
+
+    // 32-bit MIPS registers are 4 bytes wide, and registers past the end of
+    // a short blob are missing
+    let regs = (0..29u32).flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+    let file = RegisterFile::new(Architecture::Mips, true);
+    assert_eq!(file.by_name(&regs, "a0"), Some(4));
+    assert_eq!(file.sp(&regs), None);
+    assert_eq!(file.ra(&regs), None);
+    assert_eq!(file.iter(&regs).count(), 29);
+
+    // TriCore keeps its address registers before its data registers
+    let regs = (0..32u32).flat_map(|x| x.to_le_bytes()).collect::<Vec<_>>();
+    let file = RegisterFile::new(Architecture::from_name("tricore"), false);
+    assert_eq!(file.by_name(&regs, "d4"), Some(20));
+    assert_eq!(file.sp(&regs), Some(10));
+    assert_eq!(file.arch().inst_align(), Some(2));
+
+    // Architectures we don't know about just have numbered registers
+    let file = RegisterFile::new(Architecture::from_name("e2k"), false);
+    assert_eq!(file.arch(), &Architecture::Unknown("e2k".into()));
+    assert_eq!(file.arch().id(), -1);
+    assert_eq!(file.arch().pointer_width(), None);
+    assert_eq!(file.by_name(&regs, "r3"), Some(0x0000_0007_0000_0006));
+    assert_eq!(file.iter(&regs).count(), 16);
+
+    // Register groups are split back up into their sections
+    let mut raw = Vec::new();
+    raw.extend(section_header_for(RegisterGroup::Float, 10, 2).to_le_bytes());
+    raw.extend((0..20u8).collect::<Vec<_>>());
+    raw.extend(section_header_for(RegisterGroup::Vector, 16, 0).to_le_bytes());
+    let sections = RegisterSections::parse(&raw).unwrap();
+    let float = sections.get(RegisterGroup::Float).unwrap();
+    assert!(float.count() == 2 && float.get(1) == Some(&raw[14..24]));
+    assert_eq!(float.value(0), Some(0x09080706050403020100));
+    assert_eq!(sections.get(RegisterGroup::Vector).unwrap().count(), 0);
+    assert!(sections.get(RegisterGroup::Gpr).is_none());
+    assert!(RegisterSections::parse(&raw[..23]).is_err());
+
+    // Register deltas apply on top of the snapshot
+    let mut deltas = RegisterDeltas::new();
+    assert!(deltas.apply(0b10, &[0; 4]).is_err());
+    assert_eq!(deltas.apply(0b111, &[1, 0, 2, 0, 3, 0]).unwrap(),
+        &[1, 0, 2, 0, 3, 0]);
+    assert_eq!(deltas.apply(0b101, &[4, 0, 5, 0]).unwrap(),
+        &[4, 0, 2, 0, 5, 0]);
+    assert_eq!(deltas.apply(0, &[]).unwrap(), &[4, 0, 2, 0, 5, 0]);
+    assert!(deltas.apply(0b1000, &[6, 0]).is_err());
+    assert!(deltas.apply(0b1, &[6, 0, 0, 0]).is_err());
+}
@@ -0,0 +1,507 @@
+//! The Cannoli server, which accepts connections from the jitter running in
+//! QEMU and hands each of them to [`handle_client`]
+
+use std::mem::MaybeUninit;
+use std::io::{Read, ErrorKind};
+use std::time::Duration;
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+use std::os::unix::io::OwnedFd;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::{Cannoli, ClientConn, ClientInfo, Endpoint, Stream};
+use crate::{Error, Hello, Result, handle_client, open_pipe};
+
+/// How long the accept loop sleeps when there were no new connections before
+/// checking again if it was asked to stop
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a client may take to send its whole handshake. Anything which
+/// connects and then goes quiet, like a port scanner or a stopped QEMU, would
+/// otherwise hold its session open forever
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest `pcomm` or `comm` we accept from a client, anything larger is not
+/// a real client (Linux limits these to 16 bytes). This also limits the path
+/// of the guest's executable
+const MAX_COMM_LEN: u32 = 4096;
+
+/// Largest guest arguments or environment we accept from a client
+const MAX_ARGS_LEN: u32 = 16 * 1024 * 1024;
+
+/// Callback invoked with errors which ended a single client session, along
+/// with the client's information if the handshake got far enough to get it
+pub type ErrorCallback = Arc<dyn Fn(Option<&ClientInfo>, &Error) + Send + Sync>;
+
+/// Callback invoked with the result of each client session once it ends
+pub type SessionCallback = Arc<dyn Fn(SessionResult) + Send + Sync>;
+
+/// Builder for a Cannoli server, which accepts connections from QEMU and
+/// dispatches the traces it streams to a [`Cannoli`] implementation
+#[derive(Clone)]
+pub struct CannoliServer {
+    /// Endpoint to listen on for new QEMU clients
+    endpoint: Endpoint,
+
+    /// Number of trace processing threads to create per connection
+    threads: usize,
+
+    /// Stop the server once the first process to connect and all of its
+    /// descendants have disconnected
+    exit_with_root: bool,
+
+    /// Invoked when a session fails
+    on_error: Option<ErrorCallback>,
+
+    /// Invoked when a session ends
+    on_session: Option<SessionCallback>,
+
+    /// Directory to record the trace of every session to
+    record: Option<PathBuf>,
+}
+
+impl std::fmt::Debug for CannoliServer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("CannoliServer")
+            .field("endpoint",       &self.endpoint)
+            .field("threads",        &self.threads)
+            .field("exit_with_root", &self.exit_with_root)
+            .field("on_error",       &self.on_error.is_some())
+            .field("on_session",     &self.on_session.is_some())
+            .field("record",         &self.record)
+            .finish()
+    }
+}
+
+impl CannoliServer {
+    /// Create a new server builder which creates `threads` number of threads
+    /// for every connection that comes in. These threads will handle all
+    /// Cannoli parsing and callbacks
+    ///
+    /// The server listens on the endpoint in the [`SERVER_ENV`] environment
+    /// variable if it is set (this is the same variable the jitter uses to
+    /// find the server), otherwise it listens on [`DEFAULT_SERVER`]
+    ///
+    /// [`SERVER_ENV`]: crate::SERVER_ENV
+    /// [`DEFAULT_SERVER`]: crate::DEFAULT_SERVER
+    pub fn new(threads: usize) -> Self {
+        Self {
+            endpoint:       Endpoint::from_env(),
+            exit_with_root: false,
+            on_error:       None,
+            on_session:     None,
+            record:         None,
+            threads,
+        }
+    }
+
+    /// Listen on `endpoint` rather than the default. This can be a TCP
+    /// address, or a Unix domain socket when prefixed with `unix:`
+    pub fn endpoint(mut self, endpoint: impl Into<Endpoint>) -> Self {
+        self.endpoint = endpoint.into();
+        self
+    }
+
+    /// Listen on a Unix domain socket at `path`
+    pub fn unix(mut self, path: impl Into<PathBuf>) -> Self {
+        self.endpoint = Endpoint::Unix(path.into());
+        self
+    }
+
+    /// Stop accepting connections once the first process which connects (the
+    /// root) and every process descended from it have disconnected. This is
+    /// useful for one-shot tracing of a single program.
+    ///
+    /// Processes are linked to the root by their parent PID when they
+    /// connect, so a child which only connects after its entire ancestry has
+    /// disconnected is not traced.
+    pub fn exit_with_root(mut self, exit_with_root: bool) -> Self {
+        self.exit_with_root = exit_with_root;
+        self
+    }
+
+    /// Invoke `callback` whenever a client session fails, or a connection
+    /// could not be accepted at all. A failure only ends the session it
+    /// happened in, the server and every other session keep running.
+    ///
+    /// Errors are also reported in the [`SessionResult`]s returned once the
+    /// server finishes, or passed to [`CannoliServer::on_session`].
+    pub fn on_error<F>(mut self, callback: F) -> Self
+            where F: Fn(Option<&ClientInfo>, &Error) + Send + Sync + 'static {
+        self.on_error = Some(Arc::new(callback));
+        self
+    }
+
+    /// Invoke `callback` with the result of every client session as soon as
+    /// it ends, rather than collecting them to be returned once the server
+    /// finishes. Long-running servers should use this, as otherwise the
+    /// result of every session is kept around until then.
+    pub fn on_session<F>(mut self, callback: F) -> Self
+            where F: Fn(SessionResult) + Send + Sync + 'static {
+        self.on_session = Some(Arc::new(callback));
+        self
+    }
+
+    /// Record the raw trace of every session to its own file in `dir`,
+    /// which is created if it doesn't exist. See the [`record`] module for
+    /// the format, and how files are named.
+    ///
+    /// Recording copies and compresses every chunk on the threads processing
+    /// the trace, and writes it out on a background thread. If the disk falls
+    /// behind, the threads processing the trace wait on it, but only once
+    /// they have handed the chunk back to the client.
+    ///
+    /// [`record`]: crate::record
+    pub fn record(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.record = Some(dir.into());
+        self
+    }
+
+    /// Start the server in the background. This will spin up the required
+    /// processing needed to talk with QEMU and deserialize messages, while
+    /// dispatching callbacks to the user's `T`
+    ///
+    /// The returned [`ServerHandle`] can be used to stop the server and
+    /// collect the results of every client session
+    pub fn spawn<T>(self) -> Result<ServerHandle>
+            where T: Cannoli + 'static,
+                  T::PidContext: Send + Sync + 'static {
+        // Create the directory for recordings
+        if let Some(dir) = &self.record {
+            std::fs::create_dir_all(dir).map_err(Error::Record)?;
+        }
+
+        // Create socket, waiting for clients to connect and inform us about
+        // some memory regions
+        let listener = self.endpoint.bind().map_err(Error::Bind)?;
+
+        // Poll for connections so we can notice when we are asked to stop
+        listener.set_nonblocking(true).map_err(Error::SetNonblocking)?;
+
+        // Create the shared server state
+        let shared = Arc::new(Shared {
+            stop: AtomicBool::new(false),
+            tree: Mutex::new(ProcessTree::default()),
+            exit_with_root: self.exit_with_root,
+            on_error:       self.on_error,
+            on_session:     self.on_session,
+            record:         self.record,
+        });
+
+        // Get the number of threads so we can move it into each connection
+        let threads = self.threads;
+
+        // Create the thread accepting new connections
+        let thread = {
+            let shared = shared.clone();
+            std::thread::spawn(move || {
+                // Handles to all sessions we have created which are still
+                // running, and the results of the ones which finished
+                let mut sessions = Vec::new();
+                let mut results  = Vec::new();
+
+                // Wait for connections until we're asked to stop
+                while !shared.stop.load(Ordering::Acquire) {
+                    // Clean up after sessions which finished
+                    shared.reap(&mut sessions, &mut results, false);
+
+                    // Get the next client
+                    let stream = match listener.accept() {
+                        Ok(stream) => stream,
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                            std::thread::sleep(ACCEPT_POLL_INTERVAL);
+                            continue;
+                        }
+                        Err(err) => {
+                            // Report the error, and back off in case it
+                            // persists (eg. out of file descriptors)
+                            shared.report(None, &Error::Accept(err));
+                            std::thread::sleep(ACCEPT_POLL_INTERVAL);
+                            continue;
+                        }
+                    };
+
+                    // Spawn a thread on new connections
+                    let shared = shared.clone();
+                    sessions.push(std::thread::spawn(move || {
+                        serve_client::<T>(stream, threads, &shared)
+                    }));
+                }
+
+                // Wait for all sessions to finish
+                shared.reap(&mut sessions, &mut results, true);
+                results
+            })
+        };
+
+        Ok(ServerHandle { shared, thread })
+    }
+
+    /// Start the server and wait for it to finish. This only returns when
+    /// the server was configured to stop with
+    /// [`CannoliServer::exit_with_root`]
+    pub fn run<T>(self) -> Result<Vec<SessionResult>>
+            where T: Cannoli + 'static,
+                  T::PidContext: Send + Sync + 'static {
+        self.spawn::<T>()?.join()
+    }
+}
+
+/// Handle to a server started with [`CannoliServer::spawn`]
+pub struct ServerHandle {
+    /// State shared with the server threads
+    shared: Arc<Shared>,
+
+    /// Thread accepting connections, returns the results of all sessions
+    thread: JoinHandle<Vec<SessionResult>>,
+}
+
+impl ServerHandle {
+    /// Stop accepting new connections. Clients which are already connected
+    /// keep being processed until they disconnect, clients which are still in
+    /// the handshake are dropped if they don't finish it in time
+    pub fn stop(&self) {
+        self.shared.stop.store(true, Ordering::Release);
+    }
+
+    /// Returns `true` once the server stopped accepting connections and every
+    /// session has finished
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Wait for the server to stop accepting connections and for all
+    /// sessions to drain, returning the result of every session. Nothing is
+    /// returned for sessions which were passed to
+    /// [`CannoliServer::on_session`]
+    ///
+    /// This does not stop the server, call [`ServerHandle::stop`] first unless
+    /// the server will stop on its own
+    pub fn join(self) -> Result<Vec<SessionResult>> {
+        self.thread.join().map_err(|_| Error::JoinThread)
+    }
+}
+
+/// The outcome of a single client connection
+#[derive(Debug)]
+pub struct SessionResult {
+    /// Information about the client, if it got far enough to send it
+    pub client: Option<ClientInfo>,
+
+    /// Result of processing the client
+    pub result: Result<()>,
+}
+
+/// State shared between the accept loop, the sessions, and the
+/// [`ServerHandle`]
+struct Shared {
+    /// Set when the server should stop accepting connections
+    stop: AtomicBool,
+
+    /// Tracking of the root process tree for
+    /// [`CannoliServer::exit_with_root`]
+    tree: Mutex<ProcessTree>,
+
+    /// Stop the server when the root process tree has disconnected
+    exit_with_root: bool,
+
+    /// Invoked when a session fails
+    on_error: Option<ErrorCallback>,
+
+    /// Invoked when a session ends
+    on_session: Option<SessionCallback>,
+
+    /// Directory to record the trace of every session to
+    record: Option<PathBuf>,
+}
+
+/// Tracks the first process which connected, and all of its descendants
+#[derive(Default)]
+struct ProcessTree {
+    /// PIDs of the root process and all of its descendants
+    pids: HashSet<i32>,
+
+    /// Number of connected sessions in this tree
+    live: usize,
+}
+
+impl Shared {
+    /// Invoked when a client connected, returns whether the client is part of
+    /// the root process tree
+    fn connected(&self, ci: &ClientInfo) -> bool {
+        let mut tree = self.tree.lock().unwrap();
+
+        // The first client is the root, otherwise check the lineage
+        if tree.pids.is_empty() || tree.pids.contains(&ci.pid) ||
+                tree.pids.contains(&ci.ppid) {
+            tree.pids.insert(ci.pid);
+            tree.live += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Report an error to the user's callback, if there is one
+    fn report(&self, ci: Option<&ClientInfo>, err: &Error) {
+        if let Some(on_error) = &self.on_error {
+            on_error(ci, err);
+        }
+    }
+
+    /// Join the sessions in `sessions` which finished, or all of them if
+    /// `wait` is set. Their results are handed to the user's callback if
+    /// there is one, otherwise they're collected in `results`
+    fn reap(&self, sessions: &mut Vec<JoinHandle<SessionResult>>,
+            results: &mut Vec<SessionResult>, wait: bool) {
+        let mut ii = 0;
+        while ii < sessions.len() {
+            // Leave sessions which are still running alone
+            if !wait && !sessions[ii].is_finished() {
+                ii += 1;
+                continue;
+            }
+
+            // Get the result of the session
+            let result = sessions.swap_remove(ii).join()
+                .unwrap_or(SessionResult {
+                    client: None,
+                    result: Err(Error::JoinThread),
+                });
+
+            match &self.on_session {
+                Some(on_session) => on_session(result),
+                None             => results.push(result),
+            }
+        }
+    }
+
+    /// Invoked when a client in the root process tree disconnects
+    fn disconnected(&self) {
+        let mut tree = self.tree.lock().unwrap();
+        tree.live -= 1;
+
+        // Stop the server if nothing in the tree is connected anymore
+        if self.exit_with_root && tree.live == 0 {
+            self.stop.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// Serve a newly connected client until it disconnects, reporting any error
+/// which ended the session
+fn serve_client<T>(stream: Stream, threads: usize, shared: &Shared)
+        -> SessionResult
+            where T: Cannoli + 'static,
+                  T::PidContext: Send + Sync + 'static {
+    // Perform the handshake
+    let (stream, header, fd, ci) = match handshake(stream) {
+        Ok(handshake) => handshake,
+        Err(err) => {
+            shared.report(None, &err);
+            return SessionResult { client: None, result: Err(err) };
+        }
+    };
+
+    // Track the client if it's part of the root process tree
+    let in_tree = shared.connected(&ci);
+
+    // Open the IPC pipe and handle the client
+    let result = open_pipe(&header, fd)
+        .and_then(|pipe| handle_client::<T>(stream, pipe, threads, &ci,
+            shared.record.as_deref()));
+
+    // Client is gone
+    if in_tree {
+        shared.disconnected();
+    }
+
+    // Let the user know if something went wrong
+    if let Err(err) = &result {
+        shared.report(Some(&ci), err);
+    }
+
+    SessionResult { client: Some(ci), result }
+}
+
+/// Perform the handshake with a newly connected client, returning the
+/// stream, the header, the pipe's file descriptor if it was passed to us, and
+/// the information about the client
+fn handshake(mut stream: Stream)
+        -> Result<(Stream, ClientConn, Option<OwnedFd>, ClientInfo)> {
+    // Accepted streams should already be blocking, but make sure, we rely on
+    // it for the handshake
+    stream.set_nonblocking(false).map_err(Error::SetNonblocking)?;
+
+    // Don't wait forever on clients which never send their handshake
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(Error::Handshake)?;
+
+    // Get the hello, and the pipe's file descriptor if the client passed it
+    // to us
+    let mut hello: MaybeUninit<Hello> = MaybeUninit::uninit();
+    let fd = stream.recv_with_fd(unsafe { pod_bytes(&mut hello) })
+        .map_err(handshake_error)?;
+
+    // Make sure we can talk to this client
+    let hello: Hello = unsafe { hello.assume_init() };
+    hello.validate()?;
+
+    // Get the header
+    let mut header: MaybeUninit<ClientConn> = MaybeUninit::uninit();
+    stream.read_exact(unsafe { pod_bytes(&mut header) })
+        .map_err(handshake_error)?;
+
+    // Get the actual header now that it's initialized
+    let header: ClientConn = unsafe { header.assume_init() };
+
+    // Make sure the lengths are sane before allocating for them
+    if header.pcomm_len > MAX_COMM_LEN || header.comm_len > MAX_COMM_LEN ||
+            header.exe_len > MAX_COMM_LEN || header.arch_len > MAX_COMM_LEN {
+        return Err(Error::Handshake(std::io::Error::new(
+            ErrorKind::InvalidData, "Client sent an oversized comm")));
+    }
+    if header.argv_len > MAX_ARGS_LEN || header.envp_len > MAX_ARGS_LEN {
+        return Err(Error::Handshake(std::io::Error::new(
+            ErrorKind::InvalidData, "Client sent oversized arguments")));
+    }
+
+    // Get the pcomm, comm, exe, argv, envp, and architecture name
+    let len = [header.pcomm_len, header.comm_len, header.exe_len,
+        header.argv_len, header.envp_len, header.arch_len].iter()
+        .map(|&x| x as usize).sum();
+    let mut strings = vec![0u8; len];
+    stream.read_exact(&mut strings).map_err(handshake_error)?;
+
+    // Construct client information
+    let ci = ClientInfo::from_handshake(&hello, &header, &strings)?;
+
+    // The session detects disconnects itself, reads must not time out there
+    stream.set_read_timeout(None).map_err(Error::Handshake)?;
+
+    Ok((stream, header, fd, ci))
+}
+
+/// Convert an error reading the handshake into an [`Error::Handshake`],
+/// reporting an expired [`HANDSHAKE_TIMEOUT`] as [`ErrorKind::TimedOut`]
+/// (depending on the platform the read fails with [`ErrorKind::WouldBlock`])
+fn handshake_error(err: std::io::Error) -> Error {
+    if err.kind() == ErrorKind::WouldBlock {
+        Error::Handshake(std::io::Error::new(ErrorKind::TimedOut,
+            "Client didn't send its handshake in time"))
+    } else {
+        Error::Handshake(err)
+    }
+}
+
+/// Get the bytes backing a plain-old-data value, so it can be filled in from
+/// the stream
+///
+/// # Safety
+///
+/// `T` must be plain-old-data, such that any bytes are a valid `T`
+unsafe fn pod_bytes<T: Copy>(val: &mut MaybeUninit<T>) -> &mut [u8] {
+    core::slice::from_raw_parts_mut(
+        val.as_mut_ptr() as *mut u8, core::mem::size_of::<T>())
+}
@@ -0,0 +1,1201 @@
+//! The wire format of the trace streamed from the jitter to the server
+//!
+//! A chunk of trace is a sequence of events, each starting with a one byte
+//! opcode followed by its little-endian operands. Bit 7 of the opcode is set
+//! when the target is 64-bit, in which case addresses and PCs are 64 bits
+//! wide, otherwise they are 32 bits wide.
+//!
+//! | Opcode (32/64) | Event    | Operands                                    |
+//! |----------------|----------|---------------------------------------------|
+//! | `0x00`/`0x80`  | Exec     | `pc`                                        |
+//! | `0x01`/`0x81`  | Regs     | `size: u32`, `pc`, `size` bytes of regs     |
+//! | `0x1n`/`0x9n`  | Read     | `addr`, `val` (`n` bytes), `pc`             |
+//! | `0x2n`/`0xan`  | Write    | `addr`, `val` (`n` bytes), `pc`             |
+//! | `0x30`/`0xb0`  | Mmap     | `addr`, `len`, `anon: u8`, `read: u8`,      |
+//! |                |          | `write: u8`, `exec: u8`, `path_len: u32`,   |
+//! |                |          | `offset`, `path_len` bytes of UTF-8 path    |
+//! | `0x31`/`0xb1`  | Munmap   | `addr`, `len`                               |
+//! | `0x32`/`0xb2`  | Clone    | `child: i32`, `flags: u64`                  |
+//! | `0x33`/`0xb3`  | Execve   | `path_len: u32`, `path_len` bytes of UTF-8  |
+//! |                |          | path                                        |
+//! | `0x34`/`0xb4`  | Syscall  | `num: i32`, `pc`, `ret`, 8 `arg`s           |
+//! | `0x35`/`0xb5`  | Signal   | `sig: i32`, `pc`, `handler`                 |
+//! | `0x36`/`0xb6`  | Sigret   | `pc`                                        |
+//! | `0x37`/`0xb7`  | Mprotect | `addr`, `len`, `read: u8`, `write: u8`,     |
+//! |                |          | `exec: u8`                                  |
+//! | `0x38`/`0xb8`  | Brk      | `old`, `new`                                |
+//! | `0x39`/`0xb9`  | Map      | `addr`, `len`, `read: u8`, `write: u8`,     |
+//! |                |          | `exec: u8`, `path_len: u32`, `offset`,      |
+//! |                |          | `path_len` bytes of UTF-8 path              |
+//! | `0x3a`/`0xba`  | Groups   | `size: u32`, `pc`, `size` bytes of register |
+//! |                |          | sections                                    |
+//! | `0x3b`/`0xbb`  | Delta    | `size: u32`, `pc`, `mask: u64`, `size`      |
+//! |                |          | bytes of the registers set in `mask`        |
+//! | `0x3c`/`0xbc`  | Sysenter | `num: i32`, `pc`, 8 `arg`s                  |
+//! | `0x40`/`0xc0`  | Time     | `tsc: u64`                                  |
+//! | `0x41`/`0xc1`  | Epoch    | `epoch: u64`                                |
+//!
+//! Memory access opcodes are built from [`OP_READ`] or [`OP_WRITE`], the size
+//! of the access in bytes (1, 2, 4, or 8), and [`OP_64BIT`] for 64-bit
+//! targets.
+//!
+//! Time events are only emit by clients with
+//! [`CAP_TIMESTAMPS`](crate::CAP_TIMESTAMPS), they carry the host's TSC when
+//! the jitter started filling the chunk, and when it flushed it, or by clients
+//! with [`CAP_EVENT_TIMESTAMPS`](crate::CAP_EVENT_TIMESTAMPS), which emit one
+//! right before every register and memory event.
+//!
+//! Clients with [`CAP_EPOCHS`](crate::CAP_EPOCHS) start every chunk with an
+//! epoch event, see [`ChunkInfo::epoch`](crate::ChunkInfo::epoch).
+//!
+//! Clients with [`CAP_MAP_SNAPSHOTS`](crate::CAP_MAP_SNAPSHOTS) send the
+//! mappings a process already has as map events, in the first chunks of the
+//! first thread of the process to connect.
+//!
+//! Clients with [`CAP_REGISTER_GROUPS`](crate::CAP_REGISTER_GROUPS) may emit
+//! register groups events, each section of which is a `u32` header followed
+//! by the registers of one group, see
+//! [`section_header_for`](crate::regs::section_header_for).
+//!
+//! Clients with [`CAP_REGISTER_DELTAS`](crate::CAP_REGISTER_DELTAS) may emit
+//! register delta events, holding the registers which changed since the
+//! previous delta event of the chunk, in order. The first one of every chunk
+//! holds all registers, see [`RegisterDeltas`](crate::RegisterDeltas).
+//!
+//! Clients with [`CAP_SYSCALL_ENTRIES`](crate::CAP_SYSCALL_ENTRIES) emit a
+//! syscall entry event before every syscall they report, including the ones
+//! which never return.
+
+use std::mem::size_of;
+use crate::{Error, Result};
+use crate::regs::{RegisterSection, RegisterSections, section_header_for};
+
+/// Set in every opcode emit by a 64-bit target
+pub const OP_64BIT: u8 = 0x80;
+
+/// Opcode for an executed PC on a 32-bit target
+pub const EXEC32: u8 = 0x00;
+
+/// Opcode for an executed PC on a 64-bit target
+pub const EXEC64: u8 = EXEC32 | OP_64BIT;
+
+/// Opcode for a register dump on a 32-bit target
+pub const REGS32: u8 = 0x01;
+
+/// Opcode for a register dump on a 64-bit target
+pub const REGS64: u8 = REGS32 | OP_64BIT;
+
+/// Base opcode for memory reads, combined with the size of the access
+pub const OP_READ: u8 = 0x10;
+
+/// Base opcode for memory writes, combined with the size of the access
+pub const OP_WRITE: u8 = 0x20;
+
+/// Opcode for an `mmap()` on a 32-bit target
+pub const MMAP32: u8 = 0x30;
+
+/// Opcode for an `mmap()` on a 64-bit target
+pub const MMAP64: u8 = MMAP32 | OP_64BIT;
+
+/// Opcode for a `munmap()` on a 32-bit target
+pub const MUNMAP32: u8 = 0x31;
+
+/// Opcode for a `munmap()` on a 64-bit target
+pub const MUNMAP64: u8 = MUNMAP32 | OP_64BIT;
+
+/// Opcode for a new thread or process on a 32-bit target
+pub const CLONE32: u8 = 0x32;
+
+/// Opcode for a new thread or process on a 64-bit target
+pub const CLONE64: u8 = CLONE32 | OP_64BIT;
+
+/// Opcode for an `execve()` on a 32-bit target
+pub const EXECVE32: u8 = 0x33;
+
+/// Opcode for an `execve()` on a 64-bit target
+pub const EXECVE64: u8 = EXECVE32 | OP_64BIT;
+
+/// Opcode for a syscall on a 32-bit target
+pub const SYSCALL32: u8 = 0x34;
+
+/// Opcode for a syscall on a 64-bit target
+pub const SYSCALL64: u8 = SYSCALL32 | OP_64BIT;
+
+/// Opcode for a signal delivered to a handler on a 32-bit target
+pub const SIGNAL32: u8 = 0x35;
+
+/// Opcode for a signal delivered to a handler on a 64-bit target
+pub const SIGNAL64: u8 = SIGNAL32 | OP_64BIT;
+
+/// Opcode for a return from a signal handler on a 32-bit target
+pub const SIGRETURN32: u8 = 0x36;
+
+/// Opcode for a return from a signal handler on a 64-bit target
+pub const SIGRETURN64: u8 = SIGRETURN32 | OP_64BIT;
+
+/// Opcode for an `mprotect()` on a 32-bit target
+pub const MPROTECT32: u8 = 0x37;
+
+/// Opcode for an `mprotect()` on a 64-bit target
+pub const MPROTECT64: u8 = MPROTECT32 | OP_64BIT;
+
+/// Opcode for the program break moving on a 32-bit target
+pub const BRK32: u8 = 0x38;
+
+/// Opcode for the program break moving on a 64-bit target
+pub const BRK64: u8 = BRK32 | OP_64BIT;
+
+/// Opcode for a mapping in the initial snapshot on a 32-bit target
+pub const MAP32: u8 = 0x39;
+
+/// Opcode for a mapping in the initial snapshot on a 64-bit target
+pub const MAP64: u8 = MAP32 | OP_64BIT;
+
+/// Opcode for a register groups dump on a 32-bit target
+pub const GROUPS32: u8 = 0x3a;
+
+/// Opcode for a register groups dump on a 64-bit target
+pub const GROUPS64: u8 = GROUPS32 | OP_64BIT;
+
+/// Opcode for a register delta on a 32-bit target
+pub const DELTA32: u8 = 0x3b;
+
+/// Opcode for a register delta on a 64-bit target
+pub const DELTA64: u8 = DELTA32 | OP_64BIT;
+
+/// Opcode for a syscall being made on a 32-bit target
+pub const SYSENTER32: u8 = 0x3c;
+
+/// Opcode for a syscall being made on a 64-bit target
+pub const SYSENTER64: u8 = SYSENTER32 | OP_64BIT;
+
+/// Opcode for a host timestamp on a 32-bit target
+pub const TIME32: u8 = 0x40;
+
+/// Opcode for a host timestamp on a 64-bit target
+pub const TIME64: u8 = TIME32 | OP_64BIT;
+
+/// Opcode for a process epoch on a 32-bit target
+pub const EPOCH32: u8 = 0x41;
+
+/// Opcode for a process epoch on a 64-bit target
+pub const EPOCH64: u8 = EPOCH32 | OP_64BIT;
+
+/// Gross macro to deserialize multiple plain-old-data types into a tuple
+/// with only one length check.
+///
+/// This is only safe if you pass in plain-old-data types.
+macro_rules! consume {
+    ($payload:expr, $($ty:ty),+) => {{
+        // Get the size of the payload to deserialize, in bytes
+        const OP_SIZE: usize = $(
+            size_of::<$ty>() +
+        )+ 0;
+
+        // Get pointer to payload
+        let ptr = $payload.as_ptr();
+
+        // Advance payload pointer, also performs the length check
+        $payload = $payload.get(OP_SIZE..)
+            .ok_or_else(|| Error::BufferTruncated)?;
+
+        // Create a temporary value tracking how many bytes we've read so
+        // far
+        let mut _tmp = 0;
+
+        // Ignore clippy here, I don't know if there's a better way to
+        // express what we're doing to the compiler in this case
+        #[allow(clippy::mixed_read_write_in_expression)]
+        unsafe {
+            (
+                // For each requested type, read it!
+                $(
+                    core::ptr::read_unaligned(
+                        ptr.offset({
+                            // Save off the current offset
+                            let x = _tmp;
+
+                            // Advance the index
+                            _tmp += size_of::<$ty>() as isize;
+
+                            // Return the offset to read from
+                            x
+                        }) as *const $ty),
+                )+
+            )
+        }
+    }}
+}
+
+/// A single decoded event from the trace
+///
+/// Addresses and PCs are always widened to 64 bits, regardless of the
+/// bitness of the target which emit them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event<'a> {
+    /// An instruction at `pc` was executed
+    Exec {
+        /// Program counter of the instruction
+        pc: u64,
+    },
+
+    /// An instruction at `pc` was executed, along with the raw register
+    /// state of the target at that point
+    Regs {
+        /// Program counter of the instruction
+        pc: u64,
+
+        /// Raw register state, in the target's layout
+        regs: &'a [u8],
+    },
+
+    /// An instruction at `pc` was executed, along with the registers which
+    /// changed since the previous register delta of the chunk
+    RegsDelta {
+        /// Program counter of the instruction
+        pc: u64,
+
+        /// Mask of the registers which changed, bit `n` set for register `n`
+        mask: u64,
+
+        /// The new values of the registers which changed, in order
+        regs: &'a [u8],
+    },
+
+    /// An instruction at `pc` was executed, along with the groups of
+    /// registers its hook captured
+    RegisterGroups {
+        /// Program counter of the instruction
+        pc: u64,
+
+        /// The captured groups of registers
+        groups: RegisterSections<'a>,
+    },
+
+    /// Memory was read
+    Read {
+        /// Program counter of the instruction doing the access
+        pc: u64,
+
+        /// Address which was accessed
+        addr: u64,
+
+        /// Value which was read
+        val: u64,
+
+        /// Size of the access in bytes
+        sz: u8,
+    },
+
+    /// Memory was written
+    Write {
+        /// Program counter of the instruction doing the access
+        pc: u64,
+
+        /// Address which was accessed
+        addr: u64,
+
+        /// Value which was written
+        val: u64,
+
+        /// Size of the access in bytes
+        sz: u8,
+    },
+
+    /// A successful `mmap()` in the target
+    Mmap {
+        /// Base address of the mapping
+        base: u64,
+
+        /// Length of the mapping in bytes
+        len: u64,
+
+        /// Set if the mapping is anonymous
+        anon: bool,
+
+        /// Set if the mapping is readable
+        read: bool,
+
+        /// Set if the mapping is writable
+        write: bool,
+
+        /// Set if the mapping is executable
+        exec: bool,
+
+        /// Path of the mapped file
+        path: &'a str,
+
+        /// Offset into the mapped file
+        offset: u64,
+    },
+
+    /// The target is `munmap()`ing memory
+    Munmap {
+        /// Base address of the unmapped region
+        base: u64,
+
+        /// Length of the unmapped region in bytes
+        len: u64,
+    },
+
+    /// The target changed the permissions of memory with `mprotect()`
+    Mprotect {
+        /// Base address of the region
+        base: u64,
+
+        /// Length of the region in bytes
+        len: u64,
+
+        /// Set if the region is now readable
+        read: bool,
+
+        /// Set if the region is now writable
+        write: bool,
+
+        /// Set if the region is now executable
+        exec: bool,
+    },
+
+    /// The target's program break moved with `brk()`, growing or shrinking
+    /// its heap
+    Brk {
+        /// Program break before the `brk()`
+        old: u64,
+
+        /// Program break after the `brk()`
+        new: u64,
+    },
+
+    /// A mapping the target's process already had when it connected
+    Map {
+        /// Base address of the mapping
+        base: u64,
+
+        /// Length of the mapping in bytes
+        len: u64,
+
+        /// Set if the mapping is readable
+        read: bool,
+
+        /// Set if the mapping is writable
+        write: bool,
+
+        /// Set if the mapping is executable
+        exec: bool,
+
+        /// Path of the mapped file, empty for anonymous mappings
+        path: &'a str,
+
+        /// Offset of the mapping in the file
+        offset: u64,
+    },
+
+    /// The target created a new thread or process with `clone()`, `fork()`,
+    /// or `vfork()`
+    Clone {
+        /// TID of the new thread, or PID of the new process
+        child: i32,
+
+        /// The `CLONE_*` flags the child was created with, `CLONE_THREAD` is
+        /// set for new threads
+        flags: u64,
+    },
+
+    /// The target is about to `execve()`, which replaces the process if it
+    /// succeeds
+    Execve {
+        /// Path of the executable
+        path: &'a str,
+    },
+
+    /// The target is making a syscall, it may never return
+    SyscallEntry {
+        /// Program counter QEMU had for the target when it made the syscall
+        pc: u64,
+
+        /// Syscall number
+        num: i32,
+
+        /// Arguments of the syscall
+        args: [u64; 8],
+    },
+
+    /// A syscall of the target returned
+    Syscall {
+        /// Program counter QEMU had for the target when it made the syscall
+        pc: u64,
+
+        /// Syscall number
+        num: i32,
+
+        /// Arguments of the syscall
+        args: [u64; 8],
+
+        /// Return value of the syscall, sign extended for 32-bit targets
+        ret: i64,
+    },
+
+    /// A signal was delivered to a handler of the target
+    Signal {
+        /// Number of the signal
+        sig: i32,
+
+        /// Program counter the target was interrupted at, where it resumes
+        /// once the handler returns
+        pc: u64,
+
+        /// Address of the handler the signal was delivered to
+        handler: u64,
+    },
+
+    /// A signal handler of the target returned with `sigreturn()`
+    Sigreturn {
+        /// Program counter the target resumes at
+        pc: u64,
+    },
+
+    /// The host's time stamp counter, when the jitter started or flushed the
+    /// chunk
+    Time {
+        /// Value of the host's TSC
+        tsc: u64,
+    },
+
+    /// The process's epoch when the jitter started the chunk
+    Epoch {
+        /// Value of the process's epoch counter
+        epoch: u64,
+    },
+}
+
+/// Zero-copy iterator over the [`Event`]s in a chunk of trace
+///
+/// Once an error is returned, the iterator stops yielding events, as the rest
+/// of the chunk can't be framed anymore
+#[derive(Clone, Debug)]
+pub struct EventIter<'a> {
+    /// Remaining bytes of the chunk to decode
+    payload: &'a [u8],
+}
+
+impl<'a> EventIter<'a> {
+    /// Create an iterator over the events in `payload`
+    pub fn new(payload: &'a [u8]) -> Self {
+        Self { payload }
+    }
+
+    /// Get the bytes which have not been decoded yet
+    pub fn remaining(&self) -> &'a [u8] {
+        self.payload
+    }
+
+    /// Decode the next event from the payload
+    fn decode(&mut self) -> Result<Event<'a>> {
+        let mut payload = self.payload;
+
+        /// Decode a memory access with the given types for the address and
+        /// value, and access size in bytes
+        macro_rules! memop {
+            ($event:ident, $addr:ty, $val:ty, $sz:expr) => {{
+                let (addr, val, pc) = consume!(payload, $addr, $val, $addr);
+                Event::$event {
+                    pc:   pc   as u64,
+                    addr: addr as u64,
+                    val:  val  as u64,
+                    sz:   $sz,
+                }
+            }}
+        }
+
+        // Get the opcode
+        let op: u8 = consume!(payload, u8).0;
+
+        // Decode each opcode
+        let event = match op {
+            EXEC32 => Event::Exec { pc: consume!(payload, u32).0 as u64 },
+            EXEC64 => Event::Exec { pc: consume!(payload, u64).0 },
+
+            REGS32 | REGS64 => {
+                let size = consume!(payload, u32).0 as usize;
+                let pc = if op == REGS64 {
+                    consume!(payload, u64).0
+                } else {
+                    consume!(payload, u32).0 as u64
+                };
+                let regs = payload.get(..size)
+                    .ok_or(Error::BufferTruncated)?;
+                payload = &payload[size..];
+                Event::Regs { pc, regs }
+            },
+
+            DELTA32 | DELTA64 => {
+                let size = consume!(payload, u32).0 as usize;
+                let (pc, mask) = if op == DELTA64 {
+                    consume!(payload, u64, u64)
+                } else {
+                    let (pc, mask) = consume!(payload, u32, u64);
+                    (pc as u64, mask)
+                };
+                let regs = payload.get(..size)
+                    .ok_or(Error::BufferTruncated)?;
+                payload = &payload[size..];
+                Event::RegsDelta { pc, mask, regs }
+            },
+
+            GROUPS32 | GROUPS64 => {
+                let size = consume!(payload, u32).0 as usize;
+                let pc = if op == GROUPS64 {
+                    consume!(payload, u64).0
+                } else {
+                    consume!(payload, u32).0 as u64
+                };
+                let groups = RegisterSections::parse(payload.get(..size)
+                    .ok_or(Error::BufferTruncated)?)?;
+                payload = &payload[size..];
+                Event::RegisterGroups { pc, groups }
+            },
+
+            MMAP32 | MMAP64 => {
+                let (base, len, anon, read, write, exec, path_len, offset) =
+                    if op == MMAP64 {
+                        consume!(payload, u64, u64, u8, u8, u8, u8, u32, u64)
+                    } else {
+                        let (base, len, anon, read, write, exec, path_len,
+                            offset) = consume!(payload,
+                                u32, u32, u8, u8, u8, u8, u32, u32);
+                        (base as u64, len as u64, anon, read, write, exec,
+                            path_len, offset as u64)
+                    };
+                let path = core::str::from_utf8(
+                    payload.get(..path_len as usize)
+                    .ok_or(Error::BufferTruncated)?)
+                    .map_err(Error::PathEncoding)?;
+                payload = &payload[path_len as usize..];
+
+                Event::Mmap {
+                    anon:  anon  != 0,
+                    read:  read  != 0,
+                    write: write != 0,
+                    exec:  exec  != 0,
+                    base, len, path, offset,
+                }
+            },
+            MUNMAP32 => {
+                let (base, len) = consume!(payload, u32, u32);
+                Event::Munmap { base: base as u64, len: len as u64 }
+            },
+            MUNMAP64 => {
+                let (base, len) = consume!(payload, u64, u64);
+                Event::Munmap { base, len }
+            },
+            MPROTECT32 | MPROTECT64 => {
+                let (base, len, read, write, exec) = if op == MPROTECT64 {
+                    consume!(payload, u64, u64, u8, u8, u8)
+                } else {
+                    let (base, len, read, write, exec) =
+                        consume!(payload, u32, u32, u8, u8, u8);
+                    (base as u64, len as u64, read, write, exec)
+                };
+
+                Event::Mprotect {
+                    read:  read  != 0,
+                    write: write != 0,
+                    exec:  exec  != 0,
+                    base, len,
+                }
+            },
+            BRK32 => {
+                let (old, new) = consume!(payload, u32, u32);
+                Event::Brk { old: old as u64, new: new as u64 }
+            },
+            BRK64 => {
+                let (old, new) = consume!(payload, u64, u64);
+                Event::Brk { old, new }
+            },
+            MAP32 | MAP64 => {
+                let (base, len, read, write, exec, path_len, offset) =
+                    if op == MAP64 {
+                        consume!(payload, u64, u64, u8, u8, u8, u32, u64)
+                    } else {
+                        let (base, len, read, write, exec, path_len,
+                            offset) = consume!(payload,
+                                u32, u32, u8, u8, u8, u32, u32);
+                        (base as u64, len as u64, read, write, exec,
+                            path_len, offset as u64)
+                    };
+                let path = core::str::from_utf8(
+                    payload.get(..path_len as usize)
+                    .ok_or(Error::BufferTruncated)?)
+                    .map_err(Error::PathEncoding)?;
+                payload = &payload[path_len as usize..];
+
+                Event::Map {
+                    read:  read  != 0,
+                    write: write != 0,
+                    exec:  exec  != 0,
+                    base, len, path, offset,
+                }
+            },
+            CLONE32 | CLONE64 => {
+                let (child, flags) = consume!(payload, i32, u64);
+                Event::Clone { child, flags }
+            },
+            EXECVE32 | EXECVE64 => {
+                let path_len = consume!(payload, u32).0 as usize;
+                let path = core::str::from_utf8(
+                    payload.get(..path_len).ok_or(Error::BufferTruncated)?)
+                    .map_err(Error::PathEncoding)?;
+                payload = &payload[path_len..];
+                Event::Execve { path }
+            },
+
+            SYSENTER32 => {
+                let (num, pc) = consume!(payload, i32, u32);
+                let mut args = [0u64; 8];
+                for arg in &mut args {
+                    *arg = consume!(payload, u32).0 as u64;
+                }
+                Event::SyscallEntry { pc: pc as u64, num, args }
+            },
+            SYSENTER64 => {
+                let (num, pc) = consume!(payload, i32, u64);
+                let mut args = [0u64; 8];
+                for arg in &mut args {
+                    *arg = consume!(payload, u64).0;
+                }
+                Event::SyscallEntry { pc, num, args }
+            },
+
+            SYSCALL32 => {
+                let (num, pc, ret) = consume!(payload, i32, u32, i32);
+                let mut args = [0u64; 8];
+                for arg in &mut args {
+                    *arg = consume!(payload, u32).0 as u64;
+                }
+                Event::Syscall { pc: pc as u64, num, args, ret: ret as i64 }
+            },
+            SYSCALL64 => {
+                let (num, pc, ret) = consume!(payload, i32, u64, i64);
+                let mut args = [0u64; 8];
+                for arg in &mut args {
+                    *arg = consume!(payload, u64).0;
+                }
+                Event::Syscall { pc, num, args, ret }
+            },
+
+            SIGNAL32 => {
+                let (sig, pc, handler) = consume!(payload, i32, u32, u32);
+                Event::Signal { sig, pc: pc as u64, handler: handler as u64 }
+            },
+            SIGNAL64 => {
+                let (sig, pc, handler) = consume!(payload, i32, u64, u64);
+                Event::Signal { sig, pc, handler }
+            },
+            SIGRETURN32 =>
+                Event::Sigreturn { pc: consume!(payload, u32).0 as u64 },
+            SIGRETURN64 => Event::Sigreturn { pc: consume!(payload, u64).0 },
+
+            TIME32 | TIME64 => Event::Time { tsc: consume!(payload, u64).0 },
+            EPOCH32 | EPOCH64 =>
+                Event::Epoch { epoch: consume!(payload, u64).0 },
+
+            0x11 => memop!(Read, u32, u8,  1),
+            0x12 => memop!(Read, u32, u16, 2),
+            0x14 => memop!(Read, u32, u32, 4),
+            0x18 => memop!(Read, u32, u64, 8),
+            0x91 => memop!(Read, u64, u8,  1),
+            0x92 => memop!(Read, u64, u16, 2),
+            0x94 => memop!(Read, u64, u32, 4),
+            0x98 => memop!(Read, u64, u64, 8),
+
+            0x21 => memop!(Write, u32, u8,  1),
+            0x22 => memop!(Write, u32, u16, 2),
+            0x24 => memop!(Write, u32, u32, 4),
+            0x28 => memop!(Write, u32, u64, 8),
+            0xa1 => memop!(Write, u64, u8,  1),
+            0xa2 => memop!(Write, u64, u16, 2),
+            0xa4 => memop!(Write, u64, u32, 4),
+            0xa8 => memop!(Write, u64, u64, 8),
+
+            // Invalid opcode
+            _ => return Err(Error::InvalidOpcode(op)),
+        };
+
+        // Only advance once the whole event was decoded
+        self.payload = payload;
+        Ok(event)
+    }
+}
+
+impl<'a> Iterator for EventIter<'a> {
+    type Item = Result<Event<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Nothing left to decode
+        if self.payload.is_empty() {
+            return None;
+        }
+
+        // Decode the event, nothing after a malformed event can be decoded
+        let event = self.decode();
+        if event.is_err() {
+            self.payload = &[];
+        }
+
+        Some(event)
+    }
+}
+
+/// Builds chunks of trace in the wire format, byte-identical to what the
+/// jitter emits for a target of the same bitness
+///
+/// Addresses and PCs are truncated to 32 bits when encoding for a 32-bit
+/// target
+#[derive(Clone, Debug, Default)]
+pub struct Encoder {
+    /// Encoded trace
+    buf: Vec<u8>,
+
+    /// Set if encoding for a 64-bit target
+    bits64: bool,
+}
+
+impl Encoder {
+    /// Create an encoder for a 32-bit target
+    pub fn new32() -> Self {
+        Self { buf: Vec::new(), bits64: false }
+    }
+
+    /// Create an encoder for a 64-bit target
+    pub fn new64() -> Self {
+        Self { buf: Vec::new(), bits64: true }
+    }
+
+    /// Returns `true` if encoding for a 64-bit target
+    pub fn is_64bit(&self) -> bool {
+        self.bits64
+    }
+
+    /// Get the encoded trace
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Get the encoded trace, consuming the encoder
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Number of encoded bytes
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns `true` if nothing has been encoded
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Discard everything encoded so far, keeping the bitness
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+
+    /// Push an opcode, setting [`OP_64BIT`] for 64-bit targets
+    fn op(&mut self, op: u8) {
+        self.buf.push(if self.bits64 { op | OP_64BIT } else { op });
+    }
+
+    /// Push a target-sized value
+    fn usize(&mut self, val: u64) {
+        if self.bits64 {
+            self.buf.extend_from_slice(&val.to_le_bytes());
+        } else {
+            self.buf.extend_from_slice(&(val as u32).to_le_bytes());
+        }
+    }
+
+    /// Push a memory access, `kind` is [`OP_READ`] or [`OP_WRITE`]
+    fn memop(&mut self, kind: u8, addr: u64, val: u64, sz: u8, pc: u64) {
+        // Get the bytes of the value for the access size
+        assert!(matches!(sz, 1 | 2 | 4 | 8),
+            "Invalid memory access size {sz}");
+        let val = val.to_le_bytes();
+
+        self.op(kind | sz);
+        self.usize(addr);
+        self.buf.extend_from_slice(&val[..sz as usize]);
+        self.usize(pc);
+    }
+
+    /// Encode an executed instruction at `pc`
+    pub fn exec(&mut self, pc: u64) -> &mut Self {
+        self.op(EXEC32);
+        self.usize(pc);
+        self
+    }
+
+    /// Encode an executed instruction at `pc` along with the raw register
+    /// state of the target
+    pub fn regs(&mut self, pc: u64, regs: &[u8]) -> &mut Self {
+        self.op(REGS32);
+        self.buf.extend_from_slice(&(regs.len() as u32).to_le_bytes());
+        self.usize(pc);
+        self.buf.extend_from_slice(regs);
+        self
+    }
+
+    /// Encode an executed instruction at `pc` along with the registers in
+    /// `mask` which changed
+    pub fn regs_delta(&mut self, pc: u64, mask: u64, regs: &[u8])
+            -> &mut Self {
+        self.op(DELTA32);
+        self.buf.extend_from_slice(&(regs.len() as u32).to_le_bytes());
+        self.usize(pc);
+        self.buf.extend_from_slice(&mask.to_le_bytes());
+        self.buf.extend_from_slice(regs);
+        self
+    }
+
+    /// Encode an executed instruction at `pc` along with groups of its
+    /// registers
+    pub fn register_groups<'b>(&mut self, pc: u64,
+            sections: impl IntoIterator<Item = RegisterSection<'b>>)
+            -> &mut Self {
+        // Build the sections
+        let mut raw = Vec::new();
+        for section in sections {
+            let count = section.count();
+            assert!(section.width <= u8::MAX as usize &&
+                count <= u16::MAX as usize &&
+                section.bytes.len() == section.width * count,
+                "Invalid register section {section:?}");
+            raw.extend_from_slice(&section_header_for(section.group,
+                section.width as u8, count as u16).to_le_bytes());
+            raw.extend_from_slice(section.bytes);
+        }
+
+        self.op(GROUPS32);
+        self.buf.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        self.usize(pc);
+        self.buf.extend_from_slice(&raw);
+        self
+    }
+
+    /// Encode a read of `sz` bytes (1, 2, 4, or 8) of `val` from `addr` by
+    /// the instruction at `pc`
+    pub fn read(&mut self, addr: u64, val: u64, sz: u8, pc: u64) -> &mut Self {
+        self.memop(OP_READ, addr, val, sz, pc);
+        self
+    }
+
+    /// Encode a write of `sz` bytes (1, 2, 4, or 8) of `val` to `addr` by
+    /// the instruction at `pc`
+    pub fn write(&mut self, addr: u64, val: u64, sz: u8, pc: u64)
+            -> &mut Self {
+        self.memop(OP_WRITE, addr, val, sz, pc);
+        self
+    }
+
+    /// Encode a successful `mmap()`. `path` is taken as raw bytes, as this is
+    /// what the jitter gets from QEMU
+    #[allow(clippy::too_many_arguments)]
+    pub fn mmap(&mut self, base: u64, len: u64, anon: bool, read: bool,
+            write: bool, exec: bool, path: &[u8], offset: u64) -> &mut Self {
+        self.op(MMAP32);
+        self.usize(base);
+        self.usize(len);
+        self.buf.extend_from_slice(
+            &[anon as u8, read as u8, write as u8, exec as u8]);
+        self.buf.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        self.usize(offset);
+        self.buf.extend_from_slice(path);
+        self
+    }
+
+    /// Encode a `munmap()`
+    pub fn munmap(&mut self, base: u64, len: u64) -> &mut Self {
+        self.op(MUNMAP32);
+        self.usize(base);
+        self.usize(len);
+        self
+    }
+
+    /// Encode a successful `mprotect()`
+    pub fn mprotect(&mut self, base: u64, len: u64, read: bool, write: bool,
+            exec: bool) -> &mut Self {
+        self.op(MPROTECT32);
+        self.usize(base);
+        self.usize(len);
+        self.buf.extend_from_slice(&[read as u8, write as u8, exec as u8]);
+        self
+    }
+
+    /// Encode the program break moving from `old` to `new`
+    pub fn brk(&mut self, old: u64, new: u64) -> &mut Self {
+        self.op(BRK32);
+        self.usize(old);
+        self.usize(new);
+        self
+    }
+
+    /// Encode a mapping of the initial snapshot. `path` is taken as raw
+    /// bytes, as this is what the jitter gets from QEMU
+    #[allow(clippy::too_many_arguments)]
+    pub fn map(&mut self, base: u64, len: u64, read: bool, write: bool,
+            exec: bool, path: &[u8], offset: u64) -> &mut Self {
+        self.op(MAP32);
+        self.usize(base);
+        self.usize(len);
+        self.buf.extend_from_slice(&[read as u8, write as u8, exec as u8]);
+        self.buf.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        self.usize(offset);
+        self.buf.extend_from_slice(path);
+        self
+    }
+
+    /// Encode the creation of a new thread or process
+    pub fn clone_event(&mut self, child: i32, flags: u64) -> &mut Self {
+        self.op(CLONE32);
+        self.buf.extend_from_slice(&child.to_le_bytes());
+        self.buf.extend_from_slice(&flags.to_le_bytes());
+        self
+    }
+
+    /// Encode an `execve()`. `path` is taken as raw bytes, as this is what
+    /// the jitter gets from QEMU
+    pub fn execve(&mut self, path: &[u8]) -> &mut Self {
+        self.op(EXECVE32);
+        self.buf.extend_from_slice(&(path.len() as u32).to_le_bytes());
+        self.buf.extend_from_slice(path);
+        self
+    }
+
+    /// Encode a syscall `num` being made at `pc`
+    pub fn syscall_entry(&mut self, pc: u64, num: i32, args: &[u64; 8])
+            -> &mut Self {
+        self.op(SYSENTER32);
+        self.buf.extend_from_slice(&num.to_le_bytes());
+        self.usize(pc);
+        for &arg in args {
+            self.usize(arg);
+        }
+        self
+    }
+
+    /// Encode a syscall `num` made at `pc` which returned `ret`
+    pub fn syscall(&mut self, pc: u64, num: i32, args: &[u64; 8], ret: i64)
+            -> &mut Self {
+        self.op(SYSCALL32);
+        self.buf.extend_from_slice(&num.to_le_bytes());
+        self.usize(pc);
+        self.usize(ret as u64);
+        for &arg in args {
+            self.usize(arg);
+        }
+        self
+    }
+
+    /// Encode the delivery of signal `sig` to `handler`, interrupting the
+    /// target at `pc`
+    pub fn signal(&mut self, sig: i32, pc: u64, handler: u64) -> &mut Self {
+        self.op(SIGNAL32);
+        self.buf.extend_from_slice(&sig.to_le_bytes());
+        self.usize(pc);
+        self.usize(handler);
+        self
+    }
+
+    /// Encode a return from a signal handler, resuming the target at `pc`
+    pub fn sigreturn(&mut self, pc: u64) -> &mut Self {
+        self.op(SIGRETURN32);
+        self.usize(pc);
+        self
+    }
+
+    /// Encode a host timestamp
+    pub fn time(&mut self, tsc: u64) -> &mut Self {
+        self.op(TIME32);
+        self.buf.extend_from_slice(&tsc.to_le_bytes());
+        self
+    }
+
+    /// Encode a process epoch, the jitter starts every chunk with one
+    pub fn epoch(&mut self, epoch: u64) -> &mut Self {
+        self.op(EPOCH32);
+        self.buf.extend_from_slice(&epoch.to_le_bytes());
+        self
+    }
+
+    /// Encode a decoded [`Event`]
+    pub fn event(&mut self, event: &Event) -> &mut Self {
+        match *event {
+            Event::Exec { pc } => self.exec(pc),
+            Event::Regs { pc, regs } => self.regs(pc, regs),
+            Event::RegsDelta { pc, mask, regs } =>
+                self.regs_delta(pc, mask, regs),
+            Event::RegisterGroups { pc, groups } =>
+                self.register_groups(pc, groups.iter()),
+            Event::Read { pc, addr, val, sz } => self.read(addr, val, sz, pc),
+            Event::Write { pc, addr, val, sz } =>
+                self.write(addr, val, sz, pc),
+            Event::Mmap { base, len, anon, read, write, exec, path, offset } =>
+                self.mmap(base, len, anon, read, write, exec,
+                    path.as_bytes(), offset),
+            Event::Munmap { base, len } => self.munmap(base, len),
+            Event::Mprotect { base, len, read, write, exec } =>
+                self.mprotect(base, len, read, write, exec),
+            Event::Brk { old, new } => self.brk(old, new),
+            Event::Map { base, len, read, write, exec, path, offset } =>
+                self.map(base, len, read, write, exec, path.as_bytes(), offset),
+            Event::Clone { child, flags } => self.clone_event(child, flags),
+            Event::Execve { path } => self.execve(path.as_bytes()),
+            Event::SyscallEntry { pc, num, ref args } =>
+                self.syscall_entry(pc, num, args),
+            Event::Syscall { pc, num, ref args, ret } =>
+                self.syscall(pc, num, args, ret),
+            Event::Signal { sig, pc, handler } =>
+                self.signal(sig, pc, handler),
+            Event::Sigreturn { pc } => self.sigreturn(pc),
+            Event::Time { tsc } => self.time(tsc),
+            Event::Epoch { epoch } => self.epoch(epoch),
+        }
+    }
+}
+
+#[test]
+fn encoder_layout() {
+    // These are the layouts the jitter's shellcode produces
+    let mut enc = Encoder::new32();
+    enc.exec(0x11223344).read(0xaabbccdd, 0xbeef, 2, 0x11223344)
+        .time(0x0102030405060708);
+    assert_eq!(enc.as_bytes(), &[
+        0x00, 0x44, 0x33, 0x22, 0x11,
+        0x12, 0xdd, 0xcc, 0xbb, 0xaa, 0xef, 0xbe, 0x44, 0x33, 0x22, 0x11,
+        0x40, 0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01,
+    ]);
+
+    let mut enc = Encoder::new64();
+    enc.write(0x1000, 0x42, 1, 0x2000).regs(0x3000, &[1, 2]);
+    assert_eq!(enc.as_bytes(), &[
+        0xa1, 0x00, 0x10, 0, 0, 0, 0, 0, 0, 0x42, 0x00, 0x20, 0, 0, 0, 0, 0, 0,
+        0x81, 2, 0, 0, 0, 0x00, 0x30, 0, 0, 0, 0, 0, 0, 1, 2,
+    ]);
+}
+
+#[test]
+fn encoder_roundtrip() {
+    use crate::regs::RegisterGroup;
+
+    // Xorshift, we don't need anything fancy
+    let mut seed = 0x8644d6eb17b7ab1au64;
+    let mut rng = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 43;
+        seed
+    };
+
+    // Storage for register state and paths referenced by events
+    let regs: Vec<u8> = (0..=255).collect();
+    let paths = ["", "/lib/libc.so.6", "/tmp/\u{1f980}"];
+
+    // Register groups with an empty and a non-empty section
+    let mut groups = Vec::new();
+    groups.extend(
+        section_header_for(RegisterGroup::Float, 8, 0).to_le_bytes());
+    groups.extend(
+        section_header_for(RegisterGroup::Vector, 16, 4).to_le_bytes());
+    groups.extend_from_slice(&regs[..64]);
+
+    for iter in 0..256 {
+        // Pick the bitness, and mask for the target-sized values
+        let bits64 = iter % 2 == 0;
+        let mask = if bits64 { !0 } else { u32::MAX as u64 };
+        let mut enc = if bits64 { Encoder::new64() } else { Encoder::new32() };
+
+        // Generate a random chunk of events
+        let mut expected = Vec::new();
+        for _ in 0..rng() % 64 {
+            let (pc, addr, val) = (rng() & mask, rng() & mask, rng());
+            let sz = 1u8 << (rng() % 4);
+            let val = if sz == 8 { val } else { val & ((1 << (sz * 8)) - 1) };
+            let flags = rng();
+            let ret = if bits64 { rng() as i64 } else { rng() as i32 as i64 };
+
+            expected.push(match rng() % 19 {
+                0 => Event::Exec { pc },
+                1 => Event::Regs { pc, regs: &regs[..rng() as usize % 256] },
+                2 => Event::Read { pc, addr, val, sz },
+                3 => Event::Write { pc, addr, val, sz },
+                4 => Event::Mmap {
+                    base:   addr,
+                    len:    rng() & mask,
+                    anon:   flags & 1 != 0,
+                    read:   flags & 2 != 0,
+                    write:  flags & 4 != 0,
+                    exec:   flags & 8 != 0,
+                    path:   paths[rng() as usize % paths.len()],
+                    offset: rng() & mask,
+                },
+                5 => Event::Munmap { base: addr, len: rng() & mask },
+                6 => Event::Time { tsc: rng() },
+                9 => Event::Syscall {
+                    pc,
+                    num:  rng() as i32,
+                    args: [(); 8].map(|_| rng() & mask),
+                    ret,
+                },
+                7 => Event::Clone { child: rng() as i32, flags: rng() },
+                8 => Event::Execve {
+                    path: paths[rng() as usize % paths.len()],
+                },
+                10 => Event::Signal { sig: rng() as i32, pc, handler: addr },
+                11 => Event::Sigreturn { pc },
+                12 => Event::Mprotect {
+                    base:  addr,
+                    len:   rng() & mask,
+                    read:  flags & 1 != 0,
+                    write: flags & 2 != 0,
+                    exec:  flags & 4 != 0,
+                },
+                13 => Event::Brk { old: addr, new: rng() & mask },
+                14 => Event::Map {
+                    base:   addr,
+                    len:    rng() & mask,
+                    read:   flags & 1 != 0,
+                    write:  flags & 2 != 0,
+                    exec:   flags & 4 != 0,
+                    path:   paths[rng() as usize % paths.len()],
+                    offset: rng() & mask,
+                },
+                15 => Event::RegisterGroups {
+                    pc,
+                    groups: RegisterSections::parse(&groups).unwrap(),
+                },
+                16 => Event::RegsDelta {
+                    pc,
+                    mask: rng(),
+                    regs: &regs[..rng() as usize % 256],
+                },
+                17 => Event::SyscallEntry {
+                    pc,
+                    num:  rng() as i32,
+                    args: [(); 8].map(|_| rng() & mask),
+                },
+                _ => Event::Epoch { epoch: rng() },
+            });
+            enc.event(expected.last().unwrap());
+        }
+
+        // Decode it and make sure we got the same thing back
+        let decoded = EventIter::new(enc.as_bytes())
+            .collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(decoded, expected);
+
+        // Truncating the chunk anywhere must never panic
+        for len in 0..enc.len() {
+            EventIter::new(&enc.as_bytes()[..len]).for_each(drop);
+        }
+    }
+}
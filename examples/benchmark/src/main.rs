@@ -2,7 +2,7 @@
 
 use std::sync::Arc;
 use std::time::Instant;
-use cannoli::{Cannoli, create_cannoli};
+use cannoli::{Cannoli, ChunkInfo, create_cannoli};
 
 /// Get the time stamp counter
 fn rdtsc() -> u64 {
@@ -67,7 +67,7 @@ impl Cannoli for Benchmark {
     }
 
     fn trace(&mut self, _pid: &Self::PidContext, _tid: &Self::TidContext,
-            trace: &[Self::Trace]) {
+            _chunk: &ChunkInfo, trace: &[Self::Trace]) {
         // Track number of instructions in the trace
         self.instructions += trace.len() as u64;
     }
@@ -1,12 +1,12 @@
 //! An example user of Cannoli which symbolizes a trace
 
-#![feature(once_cell)]
-
-use std::sync::{Mutex, LazyLock, Arc};
+use std::io::Write;
+use std::sync::{Mutex, Arc};
 use std::process::Command;
-use std::collections::{HashMap, BTreeMap, BTreeSet};
-use cannoli::{Cannoli, create_cannoli};
+use std::collections::{BTreeMap, BTreeSet};
+use cannoli::{Cannoli, ChunkInfo, Mapping, create_cannoli};
 
+/// Coverage database for a process, shared between all of its threads
 struct CoverageDb {
     /// Symbols for the process
     symbols: Mutex<BTreeMap<u64, Arc<str>>>,
@@ -18,13 +18,6 @@ struct CoverageDb {
     unknown_sym: Arc<str>,
 }
 
-/// Mapping of `pid`s to symbol tables
-static SYMBOLS_BY_PID:
-        LazyLock<Mutex<HashMap<i32, Arc<CoverageDb>>>> =
-    LazyLock::new(|| {
-        Default::default()
-    });
-
 /// The structure we implement [`Cannoli`] for!
 struct Coverage;
 
@@ -39,38 +32,44 @@ enum Trace {
     RemoveSymbols { base: u64, len: u64 },
 }
 
-/// Context shared between threads
-struct Context {
-    /// Mapping of addresses to symbol names for this process
-    db: Arc<CoverageDb>,
-}
-
 impl Cannoli for Coverage {
     /// The type emit in the serialized trace
     type Trace = Trace;
 
-    type PidContext = ();
-    type TidContext = Context;
+    /// Symbols and coverage are tracked per process
+    type PidContext = CoverageDb;
+    type TidContext = ();
     
+    /// Create the coverage database for the process
     fn init_pid(_: &cannoli::ClientInfo) -> Arc<Self::PidContext> {
-        Arc::new(())
+        Arc::new(CoverageDb {
+            symbols:     Default::default(),
+            coverage:    Default::default(),
+            unknown_sym: "<unknown>".into(),
+        })
     }
 
-    /// Load the symbol table
     fn init_tid(_pid: &Self::PidContext,
-            ci: &cannoli::ClientInfo) -> (Self, Self::TidContext) {
-        let db = SYMBOLS_BY_PID.lock().unwrap()
-            .entry(ci.pid).or_insert_with(|| {
-                Arc::new(CoverageDb {
-                    symbols:     Default::default(),
-                    coverage:    Default::default(),
-                    unknown_sym: "<unknown>".into(),
-                })
-            }).clone();
-
-        (Self, Context {
-            db,
-        })
+            _ci: &cannoli::ClientInfo) -> (Self, Self::TidContext) {
+        (Self, ())
+    }
+
+    /// Write out the coverage once every thread of the process is gone
+    fn fini_pid(db: &Self::PidContext, ci: &cannoli::ClientInfo) {
+        // Get the coverage
+        let cov = db.coverage.lock().unwrap();
+
+        // Write each covered symbol and offset on its own line
+        let path = format!("coverage_{}.txt", ci.pid);
+        let mut file = std::io::BufWriter::new(
+            std::fs::File::create(&path).unwrap());
+        for (sym, off) in cov.iter() {
+            writeln!(file, "{sym}+{off:#x}").unwrap();
+        }
+        file.flush().unwrap();
+
+        println!("Wrote {} coverage entries for pid {} to {path}",
+            cov.len(), ci.pid);
     }
 
     // Look for dynamic code being loaded
@@ -154,6 +153,19 @@ impl Cannoli for Coverage {
         trace.push(Trace::AddSymbols(syms));
     }
 
+    // Symbolize everything mapped before we connected, like the main binary
+    // and the interpreter
+    fn initial_maps(pid: &Self::PidContext, tid: &Self::TidContext,
+            maps: &[Mapping], trace: &mut Vec<Self::Trace>) {
+        for map in maps {
+            // Pseudo-paths like `[stack]` aren't files
+            let anon = map.path.is_empty() || map.path.starts_with('[');
+
+            Self::mmap(pid, tid, map.base, map.len, anon, map.read, map.write,
+                map.exec, map.path, map.offset, trace);
+        }
+    }
+
     fn munmap(_pid: &Self::PidContext,
               _tid: &Self::TidContext, base: u64, len: u64,
               trace: &mut Vec<Self::Trace>) {
@@ -165,11 +177,11 @@ impl Cannoli for Coverage {
         trace.push(Trace::Exec(pc));
     }
 
-    fn trace(&mut self, _pid: &Self::PidContext,
-            tid: &Self::TidContext, trace: &[Self::Trace]) {
+    fn trace(&mut self, db: &Self::PidContext, _tid: &Self::TidContext,
+            _chunk: &ChunkInfo, trace: &[Self::Trace]) {
         // Get access to the symbol and coverage databases
-        let mut syms = tid.db.symbols.lock().unwrap();
-        let mut cov  = tid.db.coverage.lock().unwrap();
+        let mut syms = db.symbols.lock().unwrap();
+        let mut cov  = db.coverage.lock().unwrap();
 
         for trace in trace {
             match trace {
@@ -178,7 +190,7 @@ impl Cannoli for Coverage {
                     let (sym, off) = syms.range(..=pc)
                         .next_back().map(|(sym_pc, sym)| {
                             (sym.clone(), pc - *sym_pc)
-                        }).unwrap_or((tid.db.unknown_sym.clone(), *pc));
+                        }).unwrap_or((db.unknown_sym.clone(), *pc));
 
                     println!("cov {:10} | {sym}+{off:#x}", cov.len());
                     cov.insert((sym, off));
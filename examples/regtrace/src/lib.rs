@@ -22,3 +22,13 @@ fn hook_mem(_pc: u64, _write: bool, _size: usize) -> bool {
     false
 }
 
+/// Called when a syscall is made, and again when it returns. Returning `true`
+/// will cause the syscall, its arguments, and its return value to be reported
+/// in the trace.
+///
+/// This may be called from multiple threads
+#[no_mangle]
+fn hook_syscall(_num: i32) -> bool {
+    // Only registers are traced
+    false
+}
@@ -1,22 +1,23 @@
 //! An example user of Cannoli which collects register traces
 
-#![feature(array_chunks)]
-
 use std::sync::Arc;
-use cannoli::{Cannoli, create_cannoli};
+use cannoli::{Cannoli, RegisterFile, create_cannoli};
 
 /// The structure we implement [`Cannoli`] for!
 struct Coverage;
 
 /// Context shared between threads
-struct Context;
+struct Context {
+    /// Decoder for the guest's registers
+    regs: RegisterFile,
+}
 
 impl Cannoli for Coverage {
     /// The type emit in the serialized trace
     type Trace = ();
 
     /// Context, the shared, immutable context shared between all threads doing
-    /// processing. We stuff our symbol table here.
+    /// processing. We stuff our register decoder here.
     type TidContext = Context;
     type PidContext = ();
     
@@ -24,19 +25,15 @@ impl Cannoli for Coverage {
         Arc::new(())
     }
 
-    /// Load the symbol table
+    /// Pick the register layout of the guest
     fn init_tid(_pid: &Self::PidContext,
-                _ci: &cannoli::ClientInfo) -> (Self, Self::TidContext) {
-        (Self, Context)
+                ci: &cannoli::ClientInfo) -> (Self, Self::TidContext) {
+        (Self, Context { regs: RegisterFile::for_client(ci) })
     }
 
-    fn regs(_pid: &Self::PidContext, _tid: &Self::TidContext,
+    fn regs(_pid: &Self::PidContext, tid: &Self::TidContext,
             pc: u64, regs: &[u8], _trace: &mut Vec<Self::Trace>) {
-        let mut parsed = [0; 32];
-        for (ii, chunk) in regs.array_chunks::<4>().enumerate() {
-            parsed[ii] = u32::from_le_bytes(*chunk);
-        }
-
+        let parsed = tid.regs.iter(regs).collect::<Vec<_>>();
         println!("Regs {pc:#x} {parsed:#x?}");
     }
 }
@@ -44,4 +41,3 @@ impl Cannoli for Coverage {
 fn main() {
     create_cannoli::<Coverage>(4).unwrap();
 }
-
@@ -1,7 +1,7 @@
 //! An example user of Cannoli which symbolizes a trace
 
 use std::sync::Arc;
-use cannoli::{Cannoli, create_cannoli};
+use cannoli::{Cannoli, ChunkInfo, create_cannoli};
 
 /// An original pointer address, and then a resolved symbol + offset for that
 /// address
@@ -150,7 +150,7 @@ impl Cannoli for Symbolizer {
 
     /// Print the trace we processed!
     fn trace(&mut self, _pid: &Self::PidContext, _tid: &Self::TidContext,
-             trace: &[Self::Trace]) {
+             _chunk: &ChunkInfo, trace: &[Self::Trace]) {
         for op in trace {
             match op {
                 Operation::Exec { pc } => {
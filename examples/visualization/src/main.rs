@@ -3,7 +3,7 @@
 use std::sync::Arc;
 use std::time::Instant;
 use std::sync::atomic::{AtomicU64, Ordering};
-use cannoli::{Cannoli, create_cannoli};
+use cannoli::{Cannoli, ChunkInfo, create_cannoli};
 use snuffles::{CameraMode, Vsync, Msaa, EventHandler, Window, Vertex};
 use snuffles::{Persist, DrawCommand};
 
@@ -83,7 +83,7 @@ impl Cannoli for Tracer {
     }
 
     fn trace(&mut self, _pid: &Self::PidContext, _tid: &Self::TidContext,
-             trace: &[Self::Trace]) {
+             _chunk: &ChunkInfo, trace: &[Self::Trace]) {
         let mut loads  = 0;
         let mut stores = 0;
         let mut instrs = 0;
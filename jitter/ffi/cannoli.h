@@ -10,11 +10,48 @@ typedef __UINT64_TYPE__ uint64_t;
 typedef __SIZE_TYPE__   size_t;
 
 /// Random 64-bit integer defining this Cannoli version
-static const uint64_t CANNOLI_VERSION = 0x9ee44dcf4508ae3eULL;
+static const uint64_t CANNOLI_VERSION = 0x5c0e9f3b7d21a846ULL;
 
 /// Poison value to indicate that the trace buffer is not actively set
 static const uint64_t CANNOLI_POISON = 0x5ac91c0a3c7b863eULL;
 
+/// Invoked by a `CannoliWalkMaps` for every mapping of the guest, with the
+/// `ctx` it was given. `path` is NULL for anonymous mappings
+typedef void (*CannoliMapFn)(void *ctx, uint64_t start, uint64_t len,
+        int is_read, int is_write, int is_exec, const char *path,
+        uint64_t offset);
+
+/// Provided by QEMU when initializing Cannoli, invokes `map_fn` for every
+/// mapping the guest currently has
+typedef void (*CannoliWalkMaps)(CannoliMapFn map_fn, void *ctx);
+
+/// Register group holding scalar floating point registers, matches
+/// `cannoli::RegisterGroup::Float`
+static const uint32_t CANNOLI_REG_FLOAT = 1;
+
+/// Register group holding vector registers, matches
+/// `cannoli::RegisterGroup::Vector`
+static const uint32_t CANNOLI_REG_VECTOR = 2;
+
+/// A group of registers register hooks can capture, provided by QEMU when
+/// initializing Cannoli
+typedef struct CannoliRegGroup {
+    /// Which group this is, a `CANNOLI_REG_*`
+    uint32_t group;
+
+    /// Number of registers in the group
+    uint32_t count;
+
+    /// Number of bytes to capture of each register
+    uint32_t width;
+
+    /// Number of bytes between the starts of consecutive registers
+    uint32_t stride;
+
+    /// Byte offset of the first register in the `CPUArchState`
+    size_t offset;
+} CannoliRegGroup;
+
 /// Definition of the bindings defined in Cannoli, passed to QEMU so it knows
 /// how to invoke us
 struct Cannoli32 {
@@ -86,6 +123,56 @@ struct Cannoli32 {
     /// Invoked when the Linux application invokes munmap() (even if
     /// unsuccessful)
     void (*munmap)(uint32_t start, uint32_t len);
+
+    /// Invoked when the Linux application successfully has mprotect()ed
+    /// memory
+    void (*mprotect)(uint32_t start, uint32_t len, int is_read, int is_write,
+            int is_exec);
+
+    /// Invoked when the program break of the Linux application moved from
+    /// `old` to `new` with brk()
+    void (*brk)(uint32_t old, uint32_t new);
+
+    /// Invoked in the parent when the Linux application successfully created
+    /// a new thread or process with clone(), fork(), or vfork(). `child` is
+    /// the TID of the new thread or the PID of the new process, and `flags`
+    /// are the `CLONE_*` flags it was created with.
+    ///
+    /// For new threads, this is invoked before the new thread runs
+    void (*clone)(int32_t child, uint64_t flags);
+
+    /// Invoked when the Linux application is about to execve() `path`
+    void (*execve)(const char *path);
+
+    /// Invoked when the Linux application makes a syscall, before QEMU
+    /// handles it. `pc` is the PC QEMU has for the guest at that point, `num`
+    /// is the syscall number, and `args` points to the 8 arguments
+    void (*syscall_entry)(uint32_t pc, int32_t num, const uint32_t *args);
+
+    /// Invoked when a syscall of the Linux application returned. `pc` is the
+    /// same PC `syscall_entry` got, `num` is the syscall number, `args` points
+    /// to the 8 arguments, and `ret` is the return value
+    void (*syscall)(uint32_t pc, int32_t num, const uint32_t *args,
+            int32_t ret);
+
+    /// Invoked when a signal is delivered to a handler of the Linux
+    /// application. `sig` is the target signal number, `pc` is the PC the
+    /// application was interrupted at, and `handler` is the address of the
+    /// handler
+    void (*signal)(int32_t sig, uint32_t pc, uint32_t handler);
+
+    /// Invoked when a signal handler of the Linux application returned with
+    /// sigreturn(), `pc` is the PC the application resumes at
+    void (*sigreturn)(uint32_t pc);
+
+    /// Invoked once QEMU loaded the Linux application, before it runs.
+    /// `exe` is the path of the executable, `argv` and `envp` are the
+    /// NULL-terminated arguments and environment of the application, and
+    /// `entry`, `phdr`, and `base` are `AT_ENTRY`, `AT_PHDR`, and `AT_BASE`
+    /// from its auxiliary vector
+    void (*exec_info)(const char *exe, const char *const *argv,
+            const char *const *envp, uint32_t entry, uint32_t phdr,
+            uint32_t base);
 };
 
 /// Definition of the bindings defined in Cannoli, passed to QEMU so it knows
@@ -159,6 +246,56 @@ struct Cannoli64 {
     /// Invoked when the Linux application invokes munmap() (even if
     /// unsuccessful)
     void (*munmap)(uint64_t start, uint64_t len);
+
+    /// Invoked when the Linux application successfully has mprotect()ed
+    /// memory
+    void (*mprotect)(uint64_t start, uint64_t len, int is_read, int is_write,
+            int is_exec);
+
+    /// Invoked when the program break of the Linux application moved from
+    /// `old` to `new` with brk()
+    void (*brk)(uint64_t old, uint64_t new);
+
+    /// Invoked in the parent when the Linux application successfully created
+    /// a new thread or process with clone(), fork(), or vfork(). `child` is
+    /// the TID of the new thread or the PID of the new process, and `flags`
+    /// are the `CLONE_*` flags it was created with.
+    ///
+    /// For new threads, this is invoked before the new thread runs
+    void (*clone)(int32_t child, uint64_t flags);
+
+    /// Invoked when the Linux application is about to execve() `path`
+    void (*execve)(const char *path);
+
+    /// Invoked when the Linux application makes a syscall, before QEMU
+    /// handles it. `pc` is the PC QEMU has for the guest at that point, `num`
+    /// is the syscall number, and `args` points to the 8 arguments
+    void (*syscall_entry)(uint64_t pc, int32_t num, const uint64_t *args);
+
+    /// Invoked when a syscall of the Linux application returned. `pc` is the
+    /// same PC `syscall_entry` got, `num` is the syscall number, `args` points
+    /// to the 8 arguments, and `ret` is the return value
+    void (*syscall)(uint64_t pc, int32_t num, const uint64_t *args,
+            int64_t ret);
+
+    /// Invoked when a signal is delivered to a handler of the Linux
+    /// application. `sig` is the target signal number, `pc` is the PC the
+    /// application was interrupted at, and `handler` is the address of the
+    /// handler
+    void (*signal)(int32_t sig, uint64_t pc, uint64_t handler);
+
+    /// Invoked when a signal handler of the Linux application returned with
+    /// sigreturn(), `pc` is the PC the application resumes at
+    void (*sigreturn)(uint64_t pc);
+
+    /// Invoked once QEMU loaded the Linux application, before it runs.
+    /// `exe` is the path of the executable, `argv` and `envp` are the
+    /// NULL-terminated arguments and environment of the application, and
+    /// `entry`, `phdr`, and `base` are `AT_ENTRY`, `AT_PHDR`, and `AT_BASE`
+    /// from its auxiliary vector
+    void (*exec_info)(const char *exe, const char *const *argv,
+            const char *const *envp, uint64_t entry, uint64_t phdr,
+            uint64_t base);
 };
 
 // If we're building in QEMU these will be defined and we'll make an alias for
@@ -13,13 +13,21 @@ compile_error!("This code literally has x86_64 assembly at its core, so uhh \
     x86_64 only right now :)");
 
 use std::io::Write;
-use std::ffi::CStr;
-use std::net::TcpStream;
+use std::ffi::{CStr, c_void};
 use std::mem::{ManuallyDrop, size_of};
 use std::cell::{RefCell, UnsafeCell, RefMut};
-use std::sync::OnceLock;
-use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
-use cannoli::{Architecture, ClientConn};
+use std::sync::{Mutex, OnceLock};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicUsize, Ordering};
+use cannoli::{Architecture, ClientConn, Endpoint, Hello, Stream, Transport};
+use cannoli::{CAP_MEMORY, CAP_REGISTERS, CAP_MAPPINGS, CAP_TIMESTAMPS};
+use cannoli::CAP_EVENT_TIMESTAMPS;
+use cannoli::{CAP_EPOCHS, CAP_PROCESSES, CAP_SYSCALLS, CAP_SIGNALS};
+use cannoli::{CAP_ADDRESS_SPACE, CAP_MAP_SNAPSHOTS, CAP_REGISTER_GROUPS};
+use cannoli::{CAP_REGISTER_DELTAS, CAP_SYSCALL_ENTRIES, RegisterGroup};
+use cannoli::{TIMESTAMPS_ENV, EVENT_TIMESTAMPS_ENV};
+use cannoli::regs::{SECTION_HEADER_SIZE, section_header_for};
+use cannoli::wire::{Encoder, TIME32, TIME64, EPOCH32, EPOCH64};
 use mempipe::{SendPipe, ChunkWriter};
 
 /// Chunk size to use when streaming data over IPC
@@ -28,12 +36,16 @@ const CHUNK_SIZE: usize = 256 * 1024;
 /// Number of chunks to use with IPC
 const NUM_BUFFERS: usize = 16;
 
+/// Largest size of a map event in bytes, not counting its path
+const MAX_MAP_SIZE: usize = 32;
+
 // Pull in the FFI bindings we generated
 include!(concat!(env!("OUT_DIR"), "/ffi_bindings.rs"));
 
 extern "Rust" {
     fn hook_inst(_pc: u64, _branch: bool) -> HookType;
     fn hook_mem(_pc: u64, _write: bool, _size: usize) -> bool;
+    fn hook_syscall(_num: i32) -> bool;
 }
 
 /// Different types of hooks
@@ -59,6 +71,19 @@ pub enum HookType {
     /// GPR state for the target architecture
     Register,
 
+    /// Hook fires every time an instruction is hit, and reports PC and the
+    /// GPRs which changed since the previous `RegisterDelta` hook in the
+    /// chunk, the first one of each chunk reports all of them. This is much
+    /// smaller than `Register` for most instructions, and the server rebuilds
+    /// the full GPR state. Targets with more than 64 GPRs, or GPRs other than
+    /// 4 or 8 bytes wide, get `Register` hooks instead
+    RegisterDelta,
+
+    /// Hook fires every time an instruction is hit, and reports PC and the
+    /// groups of registers in the mask, built from [`RegisterGroup::mask`].
+    /// Groups QEMU doesn't provide for the target architecture are left out
+    RegisterGroups(u32),
+
     /// Don't hook at all
     Never,
 }
@@ -71,7 +96,7 @@ struct HookState {
     pipe: SendPipe<CHUNK_SIZE, NUM_BUFFERS>,
 
     /// Connection to the server for sending metadata needed to establish IPC
-    _server: TcpStream,
+    _server: Stream,
 
     /// Currently active buffer. This is set upon JIT entries, and taken on JIT
     /// exits.
@@ -80,20 +105,43 @@ struct HookState {
     /// through the boundaries of the JIT entry and exit
     active_buffer: Option<
         ManuallyDrop<ChunkWriter<'static, CHUNK_SIZE, NUM_BUFFERS>>>,
+
+    /// Set if chunks are stamped with the host's TSC on JIT entry and exit
+    timestamps: bool,
 }
 
-impl Default for HookState {
-    fn default() -> Self {
+impl HookState {
+    /// Connect to the server for the current thread, which was created by
+    /// the thread `parent_tid`, or zero if unknown. If the thread is the
+    /// first of a new `process`, a snapshot of its mappings is sent first
+    fn new(parent_tid: i32, process: bool) -> Self {
+        // Get the server endpoint, this is `CANNOLI_SERVER` if it was set in
+        // the environment
+        let endpoint = Endpoint::from_env();
+
+        // If we're talking over a Unix domain socket we can hand the pipe
+        // directly to the server, thus we don't need a named pipe
+        let transport = match endpoint {
+            Endpoint::Unix(_) => Transport::Memfd,
+            Endpoint::Tcp(_)  => Transport::NamedShm,
+        };
+
         // Create a new pipe
-        let pipe = SendPipe::create().expect("Cannoli: Failed to create pipe");
+        let pipe = match transport {
+            Transport::NamedShm => SendPipe::create(),
+            Transport::Memfd    => SendPipe::create_memfd(),
+        }.expect("Cannoli: Failed to create pipe");
 
         // Connect to the server
-        let mut server = TcpStream::connect("127.0.0.1:11458")
+        let mut server = endpoint.connect()
             .expect("Cannoli: Failed to connect to Cannoli server");
 
         // Get QEMU target information
         let qi = QEMU_INFO.get().expect("Cannoli: QEMU_INFO not set!?");
 
+        // Get the guest information
+        let gi = GUEST_INFO.get().expect("Cannoli: GUEST_INFO not set!?");
+
         // Get the current running threads PPID, PID, and TID
         let (ppid, pid, tid) = unsafe {(
             libc::getppid(), libc::getpid(), libc::gettid(),
@@ -110,39 +158,143 @@ impl Default for HookState {
         // Construct the payload to send to the server
         let header = ClientConn {
             uid:        pipe.uid(),
-            arch:       qi.arch       as i32,
+            arch:       qi.arch.id(),
             big_endian: qi.big_endian as i32,
             pcomm_len:  pcomm.len()   as u32,
             comm_len:   comm.len()    as u32,
+            transport:  transport     as u32,
+            epoch:      next_epoch(),
+            exe_len:    gi.exe.len()  as u32,
+            argv_len:   gi.argv.len() as u32,
+            envp_len:   gi.envp.len() as u32,
+            at_entry:   gi.at_entry,
+            at_phdr:    gi.at_phdr,
+            at_base:    gi.at_base,
+            gpr_width:  qi.gpr_width  as u32,
+            num_gprs:   qi.num_gprs   as u32,
+            gpr_offset: qi.gpr_offset as u64,
+            arch_len:   qi.arch.name().len() as u32,
+            reserved:   0,
+            parent_tid,
             ppid,
             pid,
             tid,
         };
 
-        // Construct the header
+        // Check if we should stamp chunks with the time
+        let timestamps = std::env::var_os(TIMESTAMPS_ENV).is_some();
+
+        // Introduce ourselves, we may emit every kind of event, and
+        // timestamps if they were asked for
+        let caps = CAP_MEMORY | CAP_REGISTERS | CAP_MAPPINGS | CAP_EPOCHS |
+            CAP_PROCESSES | CAP_SYSCALLS | CAP_SIGNALS | CAP_ADDRESS_SPACE |
+            CAP_MAP_SNAPSHOTS | CAP_REGISTER_GROUPS | CAP_REGISTER_DELTAS |
+            CAP_SYSCALL_ENTRIES |
+            if timestamps { CAP_TIMESTAMPS } else { 0 } |
+            if qi.event_timestamps { CAP_EVENT_TIMESTAMPS } else { 0 };
+        let hello = Hello::new(caps, CANNOLI_VERSION);
         let mut payload = Vec::new();
+        payload.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(
+                &hello as *const Hello as *const u8,
+                std::mem::size_of_val(&hello))
+        });
+
+        // Construct the header
         payload.extend_from_slice(unsafe {
             std::slice::from_raw_parts(
                 &header as *const ClientConn as *const u8,
                 std::mem::size_of_val(&header))
         });
 
-        // Add the parent and self comm values
+        // Add the parent and self comm values, the guest's strings, and the
+        // name of the architecture
         payload.extend_from_slice(&pcomm);
         payload.extend_from_slice(&comm);
-
-        // Send the data!
-        server.write_all(&payload)
-            .expect("Cannoli: Failed to send initial greeting");
-
-        Self {
+        payload.extend_from_slice(&gi.exe);
+        payload.extend_from_slice(&gi.argv);
+        payload.extend_from_slice(&gi.envp);
+        payload.extend_from_slice(qi.arch.name().as_bytes());
+
+        // Send the data! Passing the pipe's file descriptor along with it if
+        // the pipe has no name
+        match pipe.fd() {
+            Some(fd) => server.send_with_fd(&payload, fd),
+            None     => server.write_all(&payload),
+        }.expect("Cannoli: Failed to send initial greeting");
+
+        let mut state = Self {
             active_buffer: None,
             _server: server,
             pipe,
+            timestamps,
+        };
+
+        // Tell the server about the mappings a new process already has,
+        // threads share them with the rest of their process
+        if process {
+            state.send_maps(qi);
+        }
+
+        state
+    }
+
+    /// Send a snapshot of the mappings of the process, in as many chunks as
+    /// it takes
+    fn send_maps(&mut self, qi: &QemuInfo) {
+        // Nothing to send if QEMU can't walk the mappings for us
+        let Some(walk_maps) = qi.walk_maps else { return };
+
+        // Have QEMU report every mapping to us
+        let mut chunks = vec![snapshot_chunk()];
+        unsafe {
+            walk_maps(Some(report_map),
+                &mut chunks as *mut Vec<Encoder> as *mut c_void);
+        }
+
+        // Send the chunks
+        for enc in &chunks {
+            self.pipe.alloc_buffer(true).send(enc.as_bytes());
         }
     }
 }
 
+/// Start a chunk of the snapshot of the mappings of the process
+fn snapshot_chunk() -> Encoder {
+    // Get QEMU target information
+    let qi = QEMU_INFO.get().expect("Cannoli: QEMU_INFO not set!?");
+
+    // Create the chunk, it starts with its epoch like any other
+    let mut enc = if qi.bits64 { Encoder::new64() } else { Encoder::new32() };
+    enc.epoch(next_epoch());
+    enc
+}
+
+/// Invoked by QEMU for every mapping of the guest when taking a snapshot.
+/// `ctx` points to the `Vec<Encoder>` of chunks of the snapshot
+unsafe extern "C" fn report_map(ctx: *mut c_void, start: u64, len: u64,
+        read: i32, write: i32, exec: i32, path: *const i8, offset: u64) {
+    // Get the chunks of the snapshot
+    let chunks = &mut *(ctx as *mut Vec<Encoder>);
+
+    // Convert the path into bytes
+    let path: &[u8] = if path.is_null() {
+        &[]
+    } else {
+        CStr::from_ptr(path).to_bytes()
+    };
+
+    // Start a new chunk if the mapping doesn't fit in the current one
+    let enc = chunks.last().expect("Cannoli: Snapshot without chunks");
+    if enc.len() + MAX_MAP_SIZE + path.len() > CHUNK_SIZE {
+        chunks.push(snapshot_chunk());
+    }
+
+    // Add the mapping
+    chunks.last_mut().unwrap().map(start, len, read != 0, write != 0,
+        exec != 0, path, offset);
+}
+
 /// Global state about the QEMU process we're in. This can only hold values
 /// which are constant through execution of the target.
 ///
@@ -156,11 +308,140 @@ struct QemuInfo {
 
     /// Tracks if this is a big endian target
     big_endian: bool,
+
+    /// Set if this is a 64-bit target
+    bits64: bool,
+
+    /// Width of each general purpose register (in bytes)
+    gpr_width: usize,
+
+    /// Number of general purpose registers
+    num_gprs: usize,
+
+    /// Byte offset of the general purpose registers in the CPU state
+    gpr_offset: usize,
+
+    /// Provided by QEMU to walk the mappings of the guest, `None` for QEMUs
+    /// which don't provide it
+    walk_maps: CannoliWalkMaps,
+
+    /// Groups of registers register groups hooks can capture, the GPRs
+    /// followed by the groups QEMU provided
+    reg_groups: Vec<RegGroup>,
+
+    /// Set if register and memory hooks are preceded by a time hook, from
+    /// [`EVENT_TIMESTAMPS_ENV`]
+    event_timestamps: bool,
+}
+
+/// A group of registers in the CPU state which register groups hooks can
+/// capture
+#[derive(Debug)]
+struct RegGroup {
+    /// The group of registers
+    group: RegisterGroup,
+
+    /// Header of the section holding this group in the trace
+    header: u32,
+
+    /// Byte offset of the first register off of `rbp`
+    offset: u32,
+
+    /// Number of registers in the group
+    count: u32,
+
+    /// Number of bytes captured of each register
+    width: u32,
+
+    /// Number of bytes between the starts of consecutive registers
+    stride: u32,
+}
+
+impl RegGroup {
+    /// Describe a group of `count` registers, returns `None` if the group
+    /// doesn't fit in a section
+    fn new(group: RegisterGroup, offset: usize, count: usize, width: usize,
+            stride: usize) -> Option<Self> {
+        // Make sure the group fits in a section and in the shellcode
+        if count == 0 || count > u16::MAX as usize ||
+                width == 0 || width > u8::MAX as usize || stride < width ||
+                offset > i32::MAX as usize || stride > i32::MAX as usize {
+            return None;
+        }
+
+        Some(Self {
+            header: section_header_for(group, width as u8, count as u16),
+            offset: offset as u32,
+            count:  count  as u32,
+            width:  width  as u32,
+            stride: stride as u32,
+            group,
+        })
+    }
 }
 
 /// Global state holding information about the QEMU being used
 static QEMU_INFO: OnceLock<QemuInfo> = OnceLock::new();
 
+/// Information about the guest, which QEMU gives us once it loaded it
+#[derive(Debug)]
+struct GuestInfo {
+    /// Path of the guest's executable
+    exe: Vec<u8>,
+
+    /// Arguments of the guest, each terminated by a NUL byte
+    argv: Vec<u8>,
+
+    /// Environment of the guest, each variable terminated by a NUL byte
+    envp: Vec<u8>,
+
+    /// `AT_ENTRY` from the guest's auxiliary vector
+    at_entry: u64,
+
+    /// `AT_PHDR` from the guest's auxiliary vector
+    at_phdr: u64,
+
+    /// `AT_BASE` from the guest's auxiliary vector
+    at_base: u64,
+}
+
+/// Global state holding information about the guest, set once QEMU loaded
+/// it. Nothing is reported before then
+static GUEST_INFO: OnceLock<GuestInfo> = OnceLock::new();
+
+/// Join the NULL-terminated list of C strings `list`, terminating each with
+/// a NUL byte
+unsafe fn join_cstrs(list: *const *const i8) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if list.is_null() {
+        return bytes;
+    }
+
+    // Go through the strings until the NULL
+    let mut ii = 0;
+    while !(*list.add(ii)).is_null() {
+        bytes.extend_from_slice(CStr::from_ptr(*list.add(ii)).to_bytes());
+        bytes.push(0);
+        ii += 1;
+    }
+
+    bytes
+}
+
+/// Epoch counter shared by all threads of the process, every chunk takes the
+/// next epoch when it's started, which orders chunks across threads
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Take the next epoch of the process
+fn next_epoch() -> u64 {
+    EPOCH.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Threads which were created but haven't connected yet, mapped to the
+/// thread which created them. QEMU reports new threads before they run, so
+/// they always find their creator here.
+static PARENTS: Mutex<BTreeMap<i32, i32>> = Mutex::new(BTreeMap::new());
+
 thread_local! {
     /// The thread-local QEMU hook state
     ///
@@ -171,7 +452,11 @@ thread_local! {
     /// detect when we may have forked and need to re-initialize the hook state
     static HOOK_STATE: (AtomicI32, UnsafeCell<RefCell<HookState>>) = (
         AtomicI32::new(unsafe { libc::gettid() }),
-        UnsafeCell::new(RefCell::new(HookState::default()))
+        UnsafeCell::new(RefCell::new(
+            match PARENTS.lock().unwrap().remove(&unsafe { libc::gettid() }) {
+                Some(parent_tid) => HookState::new(parent_tid, false),
+                None             => HookState::new(0, true),
+            }))
     );
 }
 
@@ -205,13 +490,21 @@ fn patch(buf: &mut [u8], magic: impl AsRef<[u8]>, new: impl AsRef<[u8]>) {
 /// We might have to re-create the thread locals as sometimes the TLS is not
 /// re-initialized on `fork()` (however it is on `pthread_create()`)
 fn with_hook<F: FnOnce(RefMut<'_, HookState>)>(callback: F) {
+    // We can't connect without the guest's information, and nothing should
+    // run in the JIT before QEMU loaded the guest
+    assert!(GUEST_INFO.get().is_some(),
+        "Cannoli: Hook invoked before QEMU loaded the guest");
+
     HOOK_STATE.with(|hook| {
         // Check if the hook state changed threads (fork() without setting a
         // new TLS)
-        if hook.0.load(Ordering::Acquire) != unsafe { libc::gettid() } {
-            // Re-initialize the hook-state
+        let parent_tid = hook.0.load(Ordering::Acquire);
+        if parent_tid != unsafe { libc::gettid() } {
+            // Re-initialize the hook-state, the thread which owned it is the
+            // one which created our process
             unsafe {
-                hook.1.get().write(RefCell::new(HookState::default()));
+                hook.1.get().write(RefCell::new(
+                    HookState::new(parent_tid, true)));
             }
 
             // Update the thread ID
@@ -223,6 +516,155 @@ fn with_hook<F: FnOnce(RefMut<'_, HookState>)>(callback: F) {
     });
 }
 
+/// Like [`with_hook`], for the hooks QEMU invokes outside of the JIT, which
+/// are ignored until QEMU loaded the guest
+fn with_event_hook<F: FnOnce(RefMut<'_, HookState>)>(callback: F) {
+    // Don't connect before QEMU loaded the guest. The only things reported
+    // until then are the mappings made while loading it, and those are sent
+    // in the snapshot when we connect
+    if GUEST_INFO.get().is_none() {
+        return;
+    }
+
+    with_hook(callback)
+}
+
+/// Send a single event from a hook QEMU invokes outside of the JIT, for a
+/// target whose `usize` is `T`. `what` names the hook for errors, and
+/// `build` encodes the event, which follows a new epoch so it's ordered with
+/// respect to the chunks of the other threads
+fn send_event<T>(what: &str, build: impl FnOnce(&mut Encoder)) {
+    with_event_hook(|mut hook| {
+        // Shouldn't have an active buffer, the JIT exit flushed it
+        assert!(hook.active_buffer.is_none(),
+            "Cannoli: {what} from inside the JIT?");
+
+        // Allocate a new blocking buffer in our pipe
+        let buffer = hook.pipe.alloc_buffer(true);
+
+        // Build the packet
+        let mut enc = encoder::<T>();
+        enc.epoch(next_epoch());
+        build(&mut enc);
+
+        // Send the payload
+        buffer.send(enc.as_bytes());
+    });
+}
+
+/// Get the shellcode between the symbols `start` and `end`
+unsafe fn shellcode(start: *const u8, end: *const u8) -> &'static [u8] {
+    core::slice::from_raw_parts(start, end as usize - start as usize)
+}
+
+/// Output the host assembly for a time hook, which logs the host's TSC, if
+/// per-event timestamps are enabled. `flush`, `buf` and `buf_size` are like
+/// for `lift_register_groups`
+///
+/// Returns the number of bytes written to `buf`, the hook being stamped goes
+/// right after them
+unsafe fn lift_time(bits64: bool, flush: usize, buf: *mut u8,
+        buf_size: usize) -> usize {
+    let qi = QEMU_INFO.get().expect("Cannoli: QEMU info not set");
+    if !qi.event_timestamps {
+        return 0;
+    }
+
+    // Get the shellcode
+    let shellcode = if bits64 {
+        shellcode(core::ptr::addr_of!(cannoli_timehook64),
+            core::ptr::addr_of!(cannoli_timehook64_end))
+    } else {
+        shellcode(core::ptr::addr_of!(cannoli_timehook32),
+            core::ptr::addr_of!(cannoli_timehook32_end))
+    };
+
+    // Make sure the shellcode will fit in the temporary buffer we got from
+    // QEMU
+    assert!(shellcode.len() <= buf_size,
+        "Cannoli: Time shellcode too large for QEMU buffer");
+    let tmp = std::slice::from_raw_parts_mut(buf, shellcode.len());
+
+    // Emit it, with the flush
+    tmp.copy_from_slice(shellcode);
+    patch(tmp, REPLACE_WITH_FLUSH.to_le_bytes(), flush.to_le_bytes());
+
+    tmp.len()
+}
+
+/// Output the host assembly for a register groups hook at `pc`, capturing
+/// the groups in `mask`. `flush` is the address of the `flush_buffer` for
+/// the target's bitness, `buf` and `buf_size` are like for `lift_instruction`
+///
+/// The shellcode is a head which reserves room for the event, a section for
+/// each captured group which copies the group's registers into the event, and
+/// a tail
+unsafe fn lift_register_groups(bits64: bool, pc: u64, mask: u32,
+        flush: usize, buf: *mut u8, buf_size: usize) -> usize {
+    let qi = QEMU_INFO.get().expect("Cannoli: QEMU info not set");
+
+    // Get the groups to capture, and the size of the event's payload
+    let groups = qi.reg_groups.iter()
+        .filter(|x| mask & x.group.mask() != 0).collect::<Vec<_>>();
+    let size: u32 = groups.iter()
+        .map(|x| SECTION_HEADER_SIZE as u32 + x.width * x.count).sum();
+
+    // Get the pieces of shellcode
+    let head = if bits64 {
+        shellcode(core::ptr::addr_of!(cannoli_grphook64),
+            core::ptr::addr_of!(cannoli_grphook64_end))
+    } else {
+        shellcode(core::ptr::addr_of!(cannoli_grphook32),
+            core::ptr::addr_of!(cannoli_grphook32_end))
+    };
+    let section = shellcode(core::ptr::addr_of!(cannoli_grpsection),
+        core::ptr::addr_of!(cannoli_grpsection_end));
+    let tail = shellcode(core::ptr::addr_of!(cannoli_grptail),
+        core::ptr::addr_of!(cannoli_grptail_end));
+
+    // Make sure the shellcode will fit in the temporary buffer we got from
+    // QEMU
+    let len = head.len() + section.len() * groups.len() + tail.len();
+    assert!(len <= buf_size,
+        "Cannoli: Register groups shellcode too large for QEMU buffer");
+    let tmp = std::slice::from_raw_parts_mut(buf, len);
+
+    // Emit the head, with the PC, the flush, and the size of the payload
+    let (out, mut rest) = tmp.split_at_mut(head.len());
+    out.copy_from_slice(head);
+    if bits64 {
+        patch(out, REPLACE_WITH_PC.to_le_bytes(), pc.to_le_bytes());
+    } else {
+        patch(out, (REPLACE_WITH_PC as u32).to_le_bytes(),
+            (pc as u32).to_le_bytes());
+    }
+    patch(out, REPLACE_WITH_FLUSH.to_le_bytes(), flush.to_le_bytes());
+    patch(out, REPLACE_WITH_REGHOOK_SIZE.to_le_bytes(), size.to_le_bytes());
+
+    // Emit a section for each group
+    for group in groups {
+        let (out, next) = rest.split_at_mut(section.len());
+        out.copy_from_slice(section);
+        patch(out, REPLACE_WITH_SECTION_HEADER.to_le_bytes(),
+            group.header.to_le_bytes());
+        patch(out, REPLACE_WITH_REGHOOK_OFFSET.to_le_bytes(),
+            group.offset.to_le_bytes());
+        patch(out, REPLACE_WITH_SECTION_COUNT.to_le_bytes(),
+            group.count.to_le_bytes());
+        patch(out, REPLACE_WITH_SECTION_WIDTH.to_le_bytes(),
+            group.width.to_le_bytes());
+        patch(out, REPLACE_WITH_SECTION_SKIP.to_le_bytes(),
+            (group.stride - group.width).to_le_bytes());
+        rest = next;
+    }
+
+    // Emit the tail
+    rest.copy_from_slice(tail);
+
+    // Return the size of the shellcode we want to inject
+    len
+}
+
 // ============================================================================
 
 /// Byte offset to register state off of `rbp` for the target architecture
@@ -243,6 +685,7 @@ static REGISTER_SIZE: AtomicUsize = AtomicUsize::new(0);
 ///
 /// - `$tusize`  - The type for a usize on the emulated target. Always either
 ///                [`u32`] or [`u64`]
+/// - `$tisize`  - The signed counterpart of `$tusize`, [`i32`] or [`i64`]
 /// - `$cannoli` - The correct Cannoli instance for this target, either
 ///                [`Cannoli32`] or [`Cannoli64`]
 /// - `$init`    - Identifier for the initializer for this target
@@ -251,21 +694,35 @@ static REGISTER_SIZE: AtomicUsize = AtomicUsize::new(0);
 /// - `$exit`    - Identifier for the JIT exit hook for this target
 /// - `$mmap`    - Identifier for the callback for mmap()s
 /// - `$munmap`  - Identifier for the callback for munmap()s
+/// - `$mprotect` - Identifier for the callback for mprotect()s
+/// - `$brk`     - Identifier for the callback for brk()s
+/// - `$clone`   - Identifier for the callback for new threads and processes
+/// - `$execve`  - Identifier for the callback for execve()s
+/// - `$syscall_entry` - Identifier for the callback for syscalls being made
+/// - `$syscall` - Identifier for the callback for syscalls returning
+/// - `$signal`  - Identifier for the callback for delivered signals
+/// - `$sigreturn` - Identifier for the callback for sigreturn()s
+/// - `$exec_info` - Identifier for the callback with the guest's information
 /// - `$flush`   - Identifier for safe-to-call-from-JIT assembly which performs
 ///                a "fake" JIT exit and entry to flush the IPC data and get
 ///                a new buffer.
 /// - `$memop`   - Identifier for the memory access hook
 macro_rules! create_bitness {
     (
-        $tusize:ty, $cannoli:tt, $init:ident, $lift:ident, $entry:ident,
-        $exit:ident, $flush:ident, $memop:ident, $mmap:ident, $munmap:ident
+        $tusize:ty, $tisize:ty, $cannoli:tt, $init:ident, $lift:ident,
+        $entry:ident, $exit:ident, $flush:ident, $memop:ident, $mmap:ident,
+        $munmap:ident, $mprotect:ident, $brk:ident, $clone:ident,
+        $execve:ident, $syscall_entry:ident, $syscall:ident, $signal:ident,
+        $sigreturn:ident, $exec_info:ident
     ) => {
 
 /// Called by QEMU to initialize this library, we also return version
 /// information to QEMU so it knows that we're using the right version
 #[no_mangle]
 extern fn $init(arch: *const i8, big_endian: i32, gpr_offset: usize,
-        gpr_width: usize, num_gprs: usize) -> &'static $cannoli {
+        gpr_width: usize, num_gprs: usize, walk_maps: CannoliWalkMaps,
+        groups: *const CannoliRegGroup, num_groups: usize)
+        -> &'static $cannoli {
     /// Bindings information for QEMU
     const BINDINGS: $cannoli = $cannoli {
         version:          CANNOLI_VERSION,
@@ -275,22 +732,61 @@ extern fn $init(arch: *const i8, big_endian: i32, gpr_offset: usize,
         jit_exit:         Some($exit),
         mmap:             Some($mmap),
         munmap:           Some($munmap),
+        mprotect:         Some($mprotect),
+        brk:              Some($brk),
+        clone:            Some($clone),
+        execve:           Some($execve),
+        syscall_entry:    Some($syscall_entry),
+        syscall:          Some($syscall),
+        signal:           Some($signal),
+        sigreturn:        Some($sigreturn),
+        exec_info:        Some($exec_info),
     };
 
-    // Save the register offset and size in the globals.
+    // Save the register offset and size in the globals, for the shellcode.
+    // The layout is also saved in the QEMU info to send it to the server
     REGISTER_OFFSET.store(gpr_offset, Ordering::Relaxed);
     REGISTER_SIZE.store(num_gprs * gpr_width, Ordering::Relaxed);
 
-    // Convert architecture string to enum
+    // Convert architecture string to enum, architectures we don't know
+    // about are still traced, they're just reported by name
     let arch = unsafe { Architecture::from_cstr(arch) };
 
     // Convert big endian to a bool
     let big_endian = big_endian != 0;
 
+    // Collect the groups of registers we can capture, the GPRs are always
+    // there. Groups we don't know, or can't capture, are skipped
+    let groups = if groups.is_null() {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(groups, num_groups) }
+    };
+    let mut reg_groups = Vec::new();
+    reg_groups.extend(RegGroup::new(RegisterGroup::Gpr, gpr_offset, num_gprs,
+        gpr_width, gpr_width));
+    for group in groups {
+        let kind = match group.group {
+            CANNOLI_REG_FLOAT  => RegisterGroup::Float,
+            CANNOLI_REG_VECTOR => RegisterGroup::Vector,
+            _ => continue,
+        };
+        reg_groups.extend(RegGroup::new(kind, group.offset,
+            group.count as usize, group.width as usize,
+            group.stride as usize));
+    }
+
     // Set up global QEMU info
     QEMU_INFO.set(QemuInfo {
+        bits64: <$tusize>::BITS == 64,
         arch,
         big_endian,
+        gpr_width,
+        num_gprs,
+        gpr_offset,
+        walk_maps,
+        reg_groups,
+        event_timestamps: std::env::var_os(EVENT_TIMESTAMPS_ENV).is_some(),
     }).expect("Cannoli: Whoa, set QEMU info twice!?");
 
     &BINDINGS
@@ -310,6 +806,25 @@ unsafe extern fn $lift(pc: $tusize, bb_end: i32,
     // Get the requested hook type for this instruction
     let hook_type = hook_inst(pc as u64, bb_end != 0);
 
+    // Register delta hooks track the changed GPRs in a 64-bit mask, fall back
+    // to full register hooks for the targets they can't handle
+    let qi = QEMU_INFO.get().expect("Cannoli: QEMU info not set");
+    let hook_type = match hook_type {
+        HookType::RegisterDelta if qi.num_gprs > u64::BITS as usize ||
+                !matches!(qi.gpr_width, 4 | 8) => HookType::Register,
+        _ => hook_type,
+    };
+
+    // Stamp register events with the host's TSC if asked to, the time hook
+    // goes in front of the register hook
+    let stamp = if matches!(hook_type, HookType::Register |
+            HookType::RegisterDelta | HookType::RegisterGroups(_)) {
+        lift_time(<$tusize>::BITS == 64, $flush as usize, buf, buf_size)
+    } else {
+        0
+    };
+    let (buf, buf_size) = (buf.add(stamp), buf_size - stamp);
+
     // Get the start and end address of the shellcode
     //
     // Check the size of `$tusize` to determine the correct shellcode to use
@@ -350,6 +865,35 @@ unsafe extern fn $lift(pc: $tusize, bb_end: i32,
                 core::ptr::addr_of!(cannoli_reghook64_end) as usize,
             )
         }
+        (32, HookType::RegisterDelta) if qi.gpr_width == 4 => {
+            (
+                core::ptr::addr_of!(cannoli_deltahook32_4)     as usize,
+                core::ptr::addr_of!(cannoli_deltahook32_4_end) as usize,
+            )
+        }
+        (32, HookType::RegisterDelta) => {
+            (
+                core::ptr::addr_of!(cannoli_deltahook32_8)     as usize,
+                core::ptr::addr_of!(cannoli_deltahook32_8_end) as usize,
+            )
+        }
+        (64, HookType::RegisterDelta) if qi.gpr_width == 4 => {
+            (
+                core::ptr::addr_of!(cannoli_deltahook64_4)     as usize,
+                core::ptr::addr_of!(cannoli_deltahook64_4_end) as usize,
+            )
+        }
+        (64, HookType::RegisterDelta) => {
+            (
+                core::ptr::addr_of!(cannoli_deltahook64_8)     as usize,
+                core::ptr::addr_of!(cannoli_deltahook64_8_end) as usize,
+            )
+        }
+        (_, HookType::RegisterGroups(mask)) => {
+            // These are built out of multiple pieces of shellcode
+            return stamp + lift_register_groups(<$tusize>::BITS == 64,
+                pc as u64, mask, $flush as usize, buf, buf_size);
+        }
         (_, HookType::Never) => {
             // Don't hook at all
             return 0;
@@ -385,7 +929,7 @@ unsafe extern fn $lift(pc: $tusize, bb_end: i32,
         ($flush as usize).to_le_bytes());
 
     // Register hooks have extra patches
-    if matches!(hook_type, HookType::Register) {
+    if matches!(hook_type, HookType::Register | HookType::RegisterDelta) {
         // Patch register hook size and offset
         patch(tmp, REPLACE_WITH_REGHOOK_OFFSET.to_le_bytes(),
             (REGISTER_OFFSET.load(Ordering::Relaxed) as u32).to_le_bytes());
@@ -393,8 +937,14 @@ unsafe extern fn $lift(pc: $tusize, bb_end: i32,
             (REGISTER_SIZE.load(Ordering::Relaxed) as u32).to_le_bytes());
     }
 
+    // Register delta hooks also need to know how many registers to compare
+    if matches!(hook_type, HookType::RegisterDelta) {
+        patch(tmp, REPLACE_WITH_DELTA_COUNT.to_le_bytes(),
+            (qi.num_gprs as u32).to_le_bytes());
+    }
+
     // Return the size of the shellcode we want to inject
-    tmp.len()
+    stamp + tmp.len()
 }
 
 /// Invoked from QEMU when entering the JIT. This provides an opportunity for
@@ -406,6 +956,10 @@ unsafe extern fn $lift(pc: $tusize, bb_end: i32,
 unsafe extern fn $entry(out_regs: *mut usize) {
     // Make sure the hook state is thread-local
     with_hook(|mut hook| {
+        // Borrow the fields of the hook state separately, the buffer borrows
+        // the pipe while we look at the rest
+        let hook = &mut *hook;
+
         // Make sure we didn't do a double entry. This is to catch places where
         // we might exit the JIT without calling JIT exit, indicating where we
         // need more QEMU hooks
@@ -415,17 +969,37 @@ unsafe extern fn $entry(out_regs: *mut usize) {
         // Allocate a new buffer in our pipe
         let mut buffer = hook.pipe.alloc_buffer(false);
 
+        // Start the chunk with its epoch
+        let epoch = write_epoch::<$tusize>(buffer.get_raw());
+
+        // Stamp the start of the chunk if requested, and keep room at the end
+        // of the buffer for the stamp on JIT exit
+        let stamp = if hook.timestamps {
+            write_time::<$tusize>(buffer.get_raw().add(epoch))
+        } else {
+            0
+        };
+
+        // Keep room at the end of the buffer for the copy of the registers
+        // register delta hooks compare against. It follows a flag which is
+        // set once the first register delta hook of the chunk filled it
+        let shadow = size_of::<u64>() + REGISTER_SIZE.load(Ordering::Relaxed);
+
         // Populate `r12`, `r13` and `r14` with:
         //
         // r12 - Pointer to the current free byte in the output buffer
-        // r13 - Pointer to the end of the output buffer
+        // r13 - Pointer to the end of the output buffer, where the register
+        //       shadow starts
         // r14 - Zero, used as scratch in the JIT
         let (r12, r13, r14) = (
-            buffer.get_raw() as usize,
-            buffer.get_raw() as usize + CHUNK_SIZE,
+            buffer.get_raw() as usize + epoch + stamp,
+            buffer.get_raw() as usize + CHUNK_SIZE - stamp - shadow,
             0,
         );
 
+        // Nothing is in the register shadow yet
+        (r13 as *mut u64).write_unaligned(0);
+
         // Store this as the active buffer, also switch the lifetime to static
         hook.active_buffer = Some(
             ManuallyDrop::new(core::mem::transmute(buffer))
@@ -458,9 +1032,16 @@ unsafe extern fn $exit(r12: usize, _r13: usize, _r14: usize) {
         let ab = hook.active_buffer.take()
             .expect("Cannoli: JIT active buffer missing");
 
+        // Stamp the end of the chunk if requested, JIT entry left room for it
+        let stamp = if hook.timestamps {
+            write_time::<$tusize>(r12 as *mut u8)
+        } else {
+            0
+        };
+
         // We allow dropping of the buffer now
         let mut ab = ManuallyDrop::into_inner(ab);
-        let to_send = r12 - ab.get_raw() as usize;
+        let to_send = r12 + stamp - ab.get_raw() as usize;
         ab.send_raw(to_send);
     });
 }
@@ -517,6 +1098,12 @@ unsafe extern fn $memop(pc: $tusize, is_write: i32, data_reg: usize,
         return 0;
     }
 
+    // Stamp the memory event with the host's TSC if asked to, the time hook
+    // goes in front of the memory hook
+    let stamp = lift_time(<$tusize>::BITS == 64, $flush as usize, buf,
+        buf_size);
+    let (buf, buf_size) = (buf.add(stamp), buf_size - stamp);
+
     // Get the start and end of the shellcode for the respective memory hook
     // with these given parameters
     //
@@ -570,7 +1157,7 @@ unsafe extern fn $memop(pc: $tusize, is_write: i32, data_reg: usize,
     patch(tmp, REPLACE_WITH_FLUSH.to_le_bytes(),
         ($flush as usize).to_le_bytes());
 
-    tmp.len()
+    stamp + tmp.len()
 }
 
 /// Called _directly_ from the JIT without preserving any registers. We have
@@ -647,73 +1234,183 @@ unsafe extern fn $flush() {
 unsafe extern fn $mmap(start: $tusize, len: $tusize,
         anon: i32, read: i32, write: i32, exec: i32, path: *mut i8,
         offset: $tusize) {
-    // Make sure the hook state is thread-local
-    with_hook(|mut hook| {
-        // Shouldn't have an active buffer
-        assert!(hook.active_buffer.is_none(), "mmap from inside the JIT?");
+    // Convert the path into bytes
+    let path: &[u8] = if path.is_null() {
+        &[]
+    } else {
+        CStr::from_ptr(path).to_bytes()
+    };
 
-        // Allocate a new blocking buffer in our pipe
-        let buffer = hook.pipe.alloc_buffer(true);
+    send_event::<$tusize>("mmap", |enc| {
+        enc.mmap(start as u64, len as u64, anon != 0, read != 0, write != 0,
+            exec != 0, path, offset as u64);
+    });
+}
 
-        // Convert the path into bytes
-        let path: &[u8] = if path.is_null() {
-            &[]
-        } else {
-            CStr::from_ptr(path).to_bytes()
-        };
+/// Called on successful mappings
+#[no_mangle]
+unsafe extern fn $munmap(start: $tusize, len: $tusize) {
+    send_event::<$tusize>("munmap", |enc| {
+        enc.munmap(start as u64, len as u64);
+    });
+}
 
-        // Temporary vector for building packet
-        let mut tmp = Vec::new();
+/// Called on successful permission changes
+#[no_mangle]
+unsafe extern fn $mprotect(start: $tusize, len: $tusize,
+        read: i32, write: i32, exec: i32) {
+    send_event::<$tusize>("mprotect", |enc| {
+        enc.mprotect(start as u64, len as u64, read != 0, write != 0,
+            exec != 0);
+    });
+}
 
-        // Opcode
-        tmp.push(if <$tusize>::BITS == 64 { 0xb0 } else { 0x30 });
+/// Called when the program break moved
+#[no_mangle]
+unsafe extern fn $brk(old: $tusize, new: $tusize) {
+    send_event::<$tusize>("brk", |enc| {
+        enc.brk(old as u64, new as u64);
+    });
+}
 
-        // Parameters
-        tmp.extend_from_slice(&start.to_le_bytes());
-        tmp.extend_from_slice(&len.to_le_bytes());
-        tmp.push(anon  as u8);
-        tmp.push(read  as u8);
-        tmp.push(write as u8);
-        tmp.push(exec  as u8);
-        tmp.extend_from_slice(&(path.len() as u32).to_le_bytes());
-        tmp.extend_from_slice(&offset.to_le_bytes());
-        tmp.extend_from_slice(path);
+/// Called in the parent on successful creation of a thread or process
+#[no_mangle]
+unsafe extern fn $clone(child: i32, flags: u64) {
+    // New threads share our memory, let them know who created them
+    if flags & libc::CLONE_THREAD as u64 != 0 {
+        PARENTS.lock().unwrap().insert(child, libc::gettid());
+    }
 
-        // Send the payload
-        buffer.send(tmp);
+    send_event::<$tusize>("clone", |enc| {
+        enc.clone_event(child, flags);
     });
 }
 
-/// Called on successful mappings
+/// Called when the target is about to execve()
 #[no_mangle]
-unsafe extern fn $munmap(start: $tusize, len: $tusize) {
-    // Make sure the hook state is thread-local
-    with_hook(|mut hook| {
-        // Shouldn't have an active buffer
-        assert!(hook.active_buffer.is_none(), "munmap from inside the JIT?");
+unsafe extern fn $execve(path: *const i8) {
+    // Convert the path into bytes
+    let path: &[u8] = if path.is_null() {
+        &[]
+    } else {
+        CStr::from_ptr(path).to_bytes()
+    };
 
-        // Allocate a new blocking buffer in our pipe
-        let buffer = hook.pipe.alloc_buffer(true);
+    // This is sent right away, so it makes it out before we're replaced
+    send_event::<$tusize>("execve", |enc| {
+        enc.execve(path);
+    });
+}
+
+/// Called when a syscall is made, before QEMU handles it
+#[no_mangle]
+unsafe extern fn $syscall_entry(pc: $tusize, num: i32, args: *const $tusize) {
+    // Do nothing if the user doesn't want to hook this syscall
+    if !hook_syscall(num) {
+        return;
+    }
 
-        // Temporary vector for building packet
-        let mut tmp = Vec::new();
+    // Get the arguments
+    let args = std::slice::from_raw_parts(args, 8);
+    let args: [u64; 8] = std::array::from_fn(|ii| args[ii] as u64);
 
-        // Opcode
-        tmp.push(if <$tusize>::BITS == 64 { 0xb1 } else { 0x31 });
+    // This is sent right away, so it makes it out even if the syscall never
+    // returns
+    send_event::<$tusize>("syscall entry", |enc| {
+        enc.syscall_entry(pc as u64, num, &args);
+    });
+}
 
-        // Parameters
-        tmp.extend_from_slice(&start.to_le_bytes());
-        tmp.extend_from_slice(&len.to_le_bytes());
+/// Called when a syscall returned
+#[no_mangle]
+unsafe extern fn $syscall(pc: $tusize, num: i32, args: *const $tusize,
+        ret: $tisize) {
+    // Do nothing if the user doesn't want to hook this syscall
+    if !hook_syscall(num) {
+        return;
+    }
 
-        // Send the payload
-        buffer.send(tmp);
+    // Get the arguments
+    let args = std::slice::from_raw_parts(args, 8);
+    let args: [u64; 8] = std::array::from_fn(|ii| args[ii] as u64);
+
+    send_event::<$tusize>("syscall", |enc| {
+        enc.syscall(pc as u64, num, &args, ret as i64);
+    });
+}
+
+/// Called when a signal is delivered to a handler
+#[no_mangle]
+unsafe extern fn $signal(sig: i32, pc: $tusize, handler: $tusize) {
+    send_event::<$tusize>("signal", |enc| {
+        enc.signal(sig, pc as u64, handler as u64);
     });
 }
 
+/// Called when a signal handler returned with sigreturn()
+#[no_mangle]
+unsafe extern fn $sigreturn(pc: $tusize) {
+    send_event::<$tusize>("sigreturn", |enc| {
+        enc.sigreturn(pc as u64);
+    });
+}
+
+/// Called once QEMU loaded the guest, before it runs
+#[no_mangle]
+unsafe extern fn $exec_info(exe: *const i8, argv: *const *const i8,
+        envp: *const *const i8, entry: $tusize, phdr: $tusize,
+        base: $tusize) {
+    // Copy everything, QEMU frees some of it right after this
+    let exe = if exe.is_null() {
+        Vec::new()
+    } else {
+        CStr::from_ptr(exe).to_bytes().to_vec()
+    };
+
+    // Save the information, we can connect to the server from now on
+    GUEST_INFO.set(GuestInfo {
+        argv:     join_cstrs(argv),
+        envp:     join_cstrs(envp),
+        at_entry: entry as u64,
+        at_phdr:  phdr  as u64,
+        at_base:  base  as u64,
+        exe,
+    }).expect("Cannoli: Whoa, got the guest information twice!?");
+}
+
 }} // macro_rules!
 
 // ============================================================================
 
+/// Create a trace encoder for a target whose `usize` is `T`
+fn encoder<T>() -> Encoder {
+    if size_of::<T>() == 8 { Encoder::new64() } else { Encoder::new32() }
+}
+
+/// Write an event with a `u64` operand to `ptr`, using `op32` or `op64` for
+/// a target whose `usize` is `T`. Returns the number of bytes written.
+///
+/// This is on the path of every JIT entry and exit, so this doesn't go
+/// through an [`Encoder`]
+unsafe fn write_u64_event<T>(ptr: *mut u8, op32: u8, op64: u8, val: u64)
+        -> usize {
+    ptr.write(if size_of::<T>() == 8 { op64 } else { op32 });
+    ptr.add(1).cast::<[u8; 8]>().write_unaligned(val.to_le_bytes());
+    1 + size_of::<u64>()
+}
+
+/// Write a time event holding the host's TSC to `ptr`, for a target whose
+/// `usize` is `T`. Returns the number of bytes written.
+unsafe fn write_time<T>(ptr: *mut u8) -> usize {
+    write_u64_event::<T>(ptr, TIME32, TIME64, std::arch::x86_64::_rdtsc())
+}
+
+/// Write an epoch event holding the process's next epoch to `ptr`, for a
+/// target whose `usize` is `T`. Returns the number of bytes written.
+unsafe fn write_epoch<T>(ptr: *mut u8) -> usize {
+    write_u64_event::<T>(ptr, EPOCH32, EPOCH64, next_epoch())
+}
+
 // Declare some symbols we defined in our global assembly below
 extern {
     static cannoli_insthook32:          u8;
@@ -724,10 +1421,30 @@ extern {
     static cannoli_insthook32_once_end: u8;
     static cannoli_insthook64_once:     u8;
     static cannoli_insthook64_once_end: u8;
+    static cannoli_timehook32:          u8;
+    static cannoli_timehook32_end:      u8;
+    static cannoli_timehook64:          u8;
+    static cannoli_timehook64_end:      u8;
     static cannoli_reghook32:           u8;
     static cannoli_reghook32_end:       u8;
     static cannoli_reghook64:           u8;
     static cannoli_reghook64_end:       u8;
+    static cannoli_deltahook32_4:       u8;
+    static cannoli_deltahook32_4_end:   u8;
+    static cannoli_deltahook32_8:       u8;
+    static cannoli_deltahook32_8_end:   u8;
+    static cannoli_deltahook64_4:       u8;
+    static cannoli_deltahook64_4_end:   u8;
+    static cannoli_deltahook64_8:       u8;
+    static cannoli_deltahook64_8_end:   u8;
+    static cannoli_grphook32:           u8;
+    static cannoli_grphook32_end:       u8;
+    static cannoli_grphook64:           u8;
+    static cannoli_grphook64_end:       u8;
+    static cannoli_grpsection:          u8;
+    static cannoli_grpsection_end:      u8;
+    static cannoli_grptail:             u8;
+    static cannoli_grptail_end:         u8;
 }
 
 /// Magic value to replace with the address of the respective `flush_buffer`
@@ -743,6 +1460,24 @@ const REPLACE_WITH_REGHOOK_OFFSET: u32 = 0x3fcc88a3;
 /// Magic value to replace with the register state size
 const REPLACE_WITH_REGHOOK_SIZE: u32 = 0x652a1e21;
 
+/// Magic value to replace with the number of registers register delta hooks
+/// compare
+const REPLACE_WITH_DELTA_COUNT: u32 = 0x6b3f5d19;
+
+/// Magic value to replace with the header of a register groups section
+const REPLACE_WITH_SECTION_HEADER: u32 = 0x2d7be915;
+
+/// Magic value to replace with the number of registers in a section
+const REPLACE_WITH_SECTION_COUNT: u32 = 0x5e31c0b7;
+
+/// Magic value to replace with the number of bytes captured of each register
+/// in a section
+const REPLACE_WITH_SECTION_WIDTH: u32 = 0x1b96f24d;
+
+/// Magic value to replace with the number of bytes to skip between the
+/// registers of a section
+const REPLACE_WITH_SECTION_SKIP: u32 = 0x49d0a6e3;
+
 // All of our shellcode is written in this global assembly block, and it is
 // ripped out and placed into the JIT. It's kinda neat. It seems ugly, but I
 // think this is way easier to make tweaks to than some weird assembler at
@@ -834,6 +1569,63 @@ create_insthook 64, 8, _once
 
 // ============================================================================
 
+// Macro invoked when creating a time hook. When per-event timestamps are
+// enabled, this is placed in front of register and memory hooks to log the
+// host's TSC right before their event
+//
+// bits - The bitness of the emulated target, either 32 or 64
+.macro create_timehook bits
+
+.global cannoli_timehook\bits\()
+cannoli_timehook\bits\():
+    // r12 - Pointer to trace buffer
+    // r13 - Pointer to end of trace buffer
+    // r14 - For memory reads, this holds the address, so it's preserved
+
+    // Allocate room in the buffer (we have to preserve r14 here)
+    lea r12, [r12 + 9]
+    cmp r12, r13
+    lea r12, [r12 - 9]
+    jbe 2f
+
+    // We're out of space, flush to get a new r12, r13, and r14
+    mov  r13, {REPLACE_WITH_FLUSH}
+    call r13
+
+2:
+.if \bits == 32
+    // Opcode
+    mov byte ptr [r12], 0x40
+.elseif \bits == 64
+    // Opcode
+    mov byte ptr [r12], 0xc0
+.else
+.error "Invalid bitness passed to create_timehook"
+.endif
+
+    // Read the TSC, `rdtsc` clobbers `rax` and `rdx` which may hold the
+    // data of a memory access, so preserve them
+    push rax
+    push rdx
+    rdtsc
+    mov dword ptr [r12 + 1], eax
+    mov dword ptr [r12 + 5], edx
+    pop rdx
+    pop rax
+
+    // Advance buffer
+    add r12, 9
+
+.global cannoli_timehook\bits\()_end
+cannoli_timehook\bits\()_end:
+
+.endm // create_timehook
+
+create_timehook 32
+create_timehook 64
+
+// ============================================================================
+
 // Okay. This macro is gnarly. This defines the shellcode we use for our memory
 // hooks. Unlike the PC shellcode, we actually have 2 register inputs from
 // QEMU's JIT. These registers could be "any" register that is scheduled to the
@@ -979,6 +1771,236 @@ cannoli_reghook\bits\()_end:
 create_reghook 32, 4
 create_reghook 64, 8
 
+// Macro invoked when creating a register delta hook. The registers are compared
+// against the shadow copy of them JIT entry reserved after the end of the
+// buffer, at `r13`, and only the ones which changed are logged and updated in
+// the shadow. The shadow starts with a flag, clear until the first register
+// delta hook of the chunk logged every register.
+//
+// bits  - The bitness of the emulated target, either 32 or 64
+// width - The bitness divided by eight (number of bytes per target usize)
+// gpr   - The width of each register in bytes, either 4 or 8
+.macro create_deltahook bits, width, gpr
+
+.global cannoli_deltahook\bits\()_\gpr\()
+cannoli_deltahook\bits\()_\gpr\():
+    // Determine size required for register delta metadata, if every register
+    // changed
+    lea r14, [r12 + 1 + 4 + \width + 8]
+    add r14, {REPLACE_WITH_REGHOOK_SIZE}
+
+    // Make sure we're in bounds
+    cmp r14, r13
+    jbe 2f
+
+    // We're out of space, flush to get a new r12, r13, and r14. This also
+    // gets us a new, empty, register shadow
+    mov  r13, {REPLACE_WITH_FLUSH}
+    call r13
+
+2:
+.if \bits == 32
+    // Opcode
+    mov byte ptr [r12], 0x3b
+
+    // PC, directly put into memory from an immediate
+    mov dword ptr [r12 + 1 + 4], {REPLACE_WITH_PC}
+.elseif \bits == 64
+    // Opcode
+    mov byte ptr [r12], 0xbb
+
+    // Move PC into a register so we can use imm64 encoding
+    mov r14, {REPLACE_WITH_PC}
+    mov qword ptr [r12 + 1 + 4], r14
+.else
+.error "Invalid bitness passed to cannoli_deltahook"
+.endif
+
+    push rax
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+
+    // rsi - The next register
+    // rdx - The shadow of the next register
+    // rdi - Where the next changed register is logged
+    // rax - Mask of the changed registers
+    // rcx - Index of the next register
+    lea rsi, [rbp + {REPLACE_WITH_REGHOOK_OFFSET}]
+    lea rdx, [r13 + 8]
+    lea rdi, [r12 + 1 + 4 + \width + 8]
+    xor eax, eax
+    xor ecx, ecx
+
+    // If the shadow is empty, log every register. r14 is set in that case
+    xor r14d, r14d
+    cmp qword ptr [r13], 0
+    jne 3f
+    mov qword ptr [r13], 1
+    inc r14d
+
+3:
+    // Compare the register to its shadow, unless we log every register
+.if \gpr == 4
+    mov r8d, dword ptr [rsi]
+    test r14d, r14d
+    jnz 4f
+    cmp r8d, dword ptr [rdx]
+    je 5f
+4:
+    mov dword ptr [rdx], r8d
+    mov dword ptr [rdi], r8d
+.elseif \gpr == 8
+    mov r8, qword ptr [rsi]
+    test r14d, r14d
+    jnz 4f
+    cmp r8, qword ptr [rdx]
+    je 5f
+4:
+    mov qword ptr [rdx], r8
+    mov qword ptr [rdi], r8
+.else
+.error "Invalid register width passed to cannoli_deltahook"
+.endif
+
+    // The register changed, log it
+    add rdi, \gpr
+    bts rax, rcx
+
+5:
+    // Go to the next register
+    add rsi, \gpr
+    add rdx, \gpr
+    inc ecx
+    cmp ecx, {REPLACE_WITH_DELTA_COUNT}
+    jb 3b
+
+    // Fill in the mask, and the size of the changed registers
+    mov qword ptr [r12 + 1 + 4 + \width], rax
+    mov rcx, rdi
+    sub rcx, r12
+    sub rcx, 1 + 4 + \width + 8
+    mov dword ptr [r12 + 1], ecx
+
+    // Advance buffer past the changed registers
+    mov r12, rdi
+
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rax
+
+.global cannoli_deltahook\bits\()_\gpr\()_end
+cannoli_deltahook\bits\()_\gpr\()_end:
+
+.endm // create_deltahook
+
+create_deltahook 32, 4, 4
+create_deltahook 32, 4, 8
+create_deltahook 64, 8, 4
+create_deltahook 64, 8, 8
+
+// Macro invoked when creating the head of a register groups hook. When lifting
+// an instruction, a section for each captured group, and the tail, are placed
+// after it
+//
+// bits  - The bitness of the emulated target, either 32 or 64
+// width - The bitness divided by eight (number of bytes per target usize)
+.macro create_grphook bits, width
+
+.global cannoli_grphook\bits\()
+cannoli_grphook\bits\():
+    // Determine size required for the event
+    lea r14, [r12 + 1 + 4 + \width]
+    add r14, {REPLACE_WITH_REGHOOK_SIZE}
+
+    // Make sure we're in bounds
+    cmp r14, r13
+    jbe 2f
+
+    // We're out of space, flush to get a new r12, r13, and r14
+    mov  r13, {REPLACE_WITH_FLUSH}
+    call r13
+
+2:
+.if \bits == 32
+    // Opcode
+    mov byte ptr [r12], 0x3a
+
+    // Size of payload
+    mov dword ptr [r12 + 1], {REPLACE_WITH_REGHOOK_SIZE}
+
+    // PC, directly put into memory from an immediate
+    mov dword ptr [r12 + 1 + 4], {REPLACE_WITH_PC}
+.elseif \bits == 64
+    // Opcode
+    mov byte ptr [r12], 0xba
+
+    // Size of payload
+    mov dword ptr [r12 + 1], {REPLACE_WITH_REGHOOK_SIZE}
+
+    // Move PC into a register so we can use imm64 encoding
+    mov r14, {REPLACE_WITH_PC}
+    mov qword ptr [r12 + 1 + 4], r14
+.else
+.error "Invalid bitness passed to cannoli_grphook"
+.endif
+
+    // Save the registers the sections use, they're restored by the tail
+    push rdi
+    push rsi
+    push rcx
+
+    // Advance buffer to the first section
+    add r12, \width + 1 + 4
+
+.global cannoli_grphook\bits\()_end
+cannoli_grphook\bits\()_end:
+
+.endm // create_grphook
+
+create_grphook 32, 4
+create_grphook 64, 8
+
+// A section of a register groups hook, copying one group of registers out of
+// the CPU state. Registers aren't always packed, so they are copied one at a
+// time, skipping over the bytes between them
+.global cannoli_grpsection
+cannoli_grpsection:
+    // Section header
+    mov dword ptr [r12], {REPLACE_WITH_SECTION_HEADER}
+
+    // Copy the registers
+    lea rdi, [r12 + 4]
+    lea rsi, [rbp + {REPLACE_WITH_REGHOOK_OFFSET}]
+    mov r14d, {REPLACE_WITH_SECTION_COUNT}
+3:
+    mov ecx, {REPLACE_WITH_SECTION_WIDTH}
+    rep movsb
+    add rsi, {REPLACE_WITH_SECTION_SKIP}
+    dec r14d
+    jnz 3b
+
+    // Advance buffer past the section
+    mov r12, rdi
+
+.global cannoli_grpsection_end
+cannoli_grpsection_end:
+
+// The tail of a register groups hook
+.global cannoli_grptail
+cannoli_grptail:
+    pop rcx
+    pop rsi
+    pop rdi
+
+.global cannoli_grptail_end
+cannoli_grptail_end:
+
 // ===========================================================================
 // !!! WARNING !!!
 //
@@ -1045,19 +2067,32 @@ multiple_create_memhook64 8, rax, rcx, rdx, rbx, rsp, rbp, rsi, rdi, r8, r9, r10
 
     REPLACE_WITH_REGHOOK_SIZE   = const REPLACE_WITH_REGHOOK_SIZE,
     REPLACE_WITH_REGHOOK_OFFSET = const REPLACE_WITH_REGHOOK_OFFSET,
+
+    REPLACE_WITH_DELTA_COUNT    = const REPLACE_WITH_DELTA_COUNT,
+
+    REPLACE_WITH_SECTION_HEADER = const REPLACE_WITH_SECTION_HEADER,
+    REPLACE_WITH_SECTION_COUNT  = const REPLACE_WITH_SECTION_COUNT,
+    REPLACE_WITH_SECTION_WIDTH  = const REPLACE_WITH_SECTION_WIDTH,
+    REPLACE_WITH_SECTION_SKIP   = const REPLACE_WITH_SECTION_SKIP,
 );
 
 // Create the 32-bit Cannoli implementation
 create_bitness!(
-    u32, Cannoli32, init_cannoli32, lift_instruction32, jit_entry32,
+    u32, i32, Cannoli32, init_cannoli32, lift_instruction32, jit_entry32,
     jit_exit32, cannoli_flush_buffer32, lift_memop32,
-    cannoli_mmap32, cannoli_munmap32
+    cannoli_mmap32, cannoli_munmap32, cannoli_mprotect32, cannoli_brk32,
+    cannoli_clone32, cannoli_execve32, cannoli_syscall_entry32,
+    cannoli_syscall32, cannoli_signal32, cannoli_sigreturn32,
+    cannoli_exec_info32
 );
 
 // Create the 64-bit Cannoli implementation
 create_bitness!(
-    u64, Cannoli64, init_cannoli64, lift_instruction64, jit_entry64,
+    u64, i64, Cannoli64, init_cannoli64, lift_instruction64, jit_entry64,
     jit_exit64, cannoli_flush_buffer64, lift_memop64,
-    cannoli_mmap64, cannoli_munmap64
+    cannoli_mmap64, cannoli_munmap64, cannoli_mprotect64, cannoli_brk64,
+    cannoli_clone64, cannoli_execve64, cannoli_syscall_entry64,
+    cannoli_syscall64, cannoli_signal64, cannoli_sigreturn64,
+    cannoli_exec_info64
 );
 
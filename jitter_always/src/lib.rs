@@ -20,3 +20,12 @@ fn hook_mem(_pc: u64, _write: bool, _size: usize) -> bool {
     true
 }
 
+/// Called when a syscall is made, and again when it returns. Returning `true`
+/// will cause the syscall, its arguments, and its return value to be reported
+/// in the trace.
+///
+/// This may be called from multiple threads
+#[no_mangle]
+fn hook_syscall(_num: i32) -> bool {
+    true
+}
@@ -68,6 +68,10 @@ pub enum Error {
     /// Failed to open shared memory file
     ShmOpen(std::io::Error),
 
+    #[cfg(target_os = "linux")]
+    /// Failed to create an anonymous memory file with `memfd_create`
+    MemfdCreate(std::io::Error),
+
     #[cfg(target_family = "unix")]
     /// Failed to [`libc::ftruncate`] shared memory when it was created
     SetMemorySize(std::io::Error),
@@ -148,6 +152,12 @@ pub struct SendPipe<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize> {
 
     /// Reference to the memory pipe
     mem_pipe: *const RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>,
+
+    /// File descriptor of the anonymous memory backing this pipe, if it was
+    /// created with [`SendPipe::create_memfd`]. Named pipes close their file
+    /// descriptor as soon as the memory is mapped.
+    #[cfg(target_family = "unix")]
+    memfd: Option<i32>,
 }
 
 /// Get the filename for a given `uid`
@@ -192,38 +202,13 @@ impl<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize>
             };
             if shm == -1 { return Err(Error::ShmOpen(errno())); }
 
-            // Set the shared memory size
-            if unsafe { libc::ftruncate(shm,
-                    size_of::<RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>>() as i64) }
-                        == -1 {
-                // Save error
-                let ret = Error::SetMemorySize(errno());
-
-                // Close the file
-                unsafe { libc::close(shm); }
-
-                // Return the error
-                return Err(ret);
-            }
-
-            // Map the shared memory
-            let mapped = unsafe {
-                libc::mmap(core::ptr::null_mut(),
-                    size_of::<RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>>(),
-                    libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
-                    shm, 0) as *mut RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>
-            };
-            let map_err = errno();
+            // Size and map the shared memory
+            let mapped = Self::map_new(shm);
 
             // Close the FD as we no longer need it
             unsafe { libc::close(shm); }
 
-            // Make sure mapping was successful
-            if mapped as usize == libc::MAP_FAILED as usize {
-                return Err(Error::MapMemory(map_err));
-            }
-
-            (mapped, uid)
+            (mapped?, uid)
         };
         
         #[cfg(target_family = "sushi_roll")]
@@ -245,26 +230,115 @@ impl<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize>
         };
 
         // Initialize the memory
-        unsafe {
-            addr_of_mut!((*mapped).magic).write(MEMPIPE_MAGIC);
-            addr_of_mut!((*mapped).chunk_size).write(CHUNK_SIZE as u64);
-            addr_of_mut!((*mapped).num_buffers).write(NUM_BUFFERS as u64);
-            addr_of_mut!((*mapped).usize_size)
-                .write(size_of::<usize>() as u64);
-            addr_of_mut!((*mapped).uid).write(uid);
-            addr_of_mut!((*mapped).client_owned)
-                .write([const { AtomicBool::new(false) }; NUM_BUFFERS]);
-            addr_of_mut!((*mapped).client_len)
-                .write([const { AtomicUsize::new(0) }; NUM_BUFFERS]);
-            addr_of_mut!((*mapped).client_seq)
-                .write([const { AtomicU64::new(0) }; NUM_BUFFERS]);
-            addr_of_mut!((*mapped).cur_seq).write(AtomicU64::new(0));
-
-            // Chunks are left uninitialized, which is okay as they are marked
-            // as [`MaybeUninit`]
+        unsafe { Self::init(mapped, uid); }
+
+        Ok(Self {
+            mem_pipe: mapped,
+            uid,
+            #[cfg(target_family = "unix")]
+            memfd: None,
+        })
+    }
+
+    /// Create a pipe backed by an anonymous `memfd_create` file rather than
+    /// named shared memory. No name for this pipe ever exists, thus it can
+    /// only be opened by a process which is given the file descriptor from
+    /// [`SendPipe::fd`] (eg. over a Unix domain socket with `SCM_RIGHTS`), and
+    /// nothing is leaked in `/dev/shm` if we crash.
+    #[cfg(target_os = "linux")]
+    pub fn create_memfd() -> Result<Self> {
+        // Make sure settings are sane
+        if NUM_BUFFERS == 0 || CHUNK_SIZE == 0 {
+            return Err(Error::InvalidPipeConfiguration);
         }
 
-        Ok(Self { mem_pipe: mapped, uid })
+        // Generate a random UID, this is only used to validate the pipe when
+        // it is opened
+        let uid = rand::random::<u64>();
+
+        // Create the anonymous memory, we don't want it leaking into any
+        // program we `execve()`
+        let fd = unsafe {
+            libc::memfd_create(b"cannoli\0".as_ptr() as *const _,
+                libc::MFD_CLOEXEC)
+        };
+        if fd == -1 { return Err(Error::MemfdCreate(errno())); }
+
+        // Size and map the memory, we have to hold on to the FD so we can
+        // send it to the receiver
+        let mapped = match Self::map_new(fd) {
+            Ok(mapped) => mapped,
+            Err(err) => {
+                unsafe { libc::close(fd); }
+                return Err(err);
+            }
+        };
+
+        // Initialize the memory
+        unsafe { Self::init(mapped, uid); }
+
+        Ok(Self { mem_pipe: mapped, uid, memfd: Some(fd) })
+    }
+
+    /// Set the size of a newly created shared memory file `fd` to hold a
+    /// [`RawMemPipe`] and map it. This does not close `fd`
+    #[cfg(target_family = "unix")]
+    fn map_new(fd: i32) -> Result<*mut RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>> {
+        // Set the shared memory size
+        if unsafe { libc::ftruncate(fd,
+                size_of::<RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>>() as i64) }
+                    == -1 {
+            return Err(Error::SetMemorySize(errno()));
+        }
+
+        // Map the shared memory
+        let mapped = unsafe {
+            libc::mmap(core::ptr::null_mut(),
+                size_of::<RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>>(),
+                libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
+                fd, 0) as *mut RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>
+        };
+
+        // Make sure mapping was successful
+        if mapped as usize == libc::MAP_FAILED as usize {
+            return Err(Error::MapMemory(errno()));
+        }
+
+        Ok(mapped)
+    }
+
+    /// Initialize the header of a newly allocated memory pipe
+    ///
+    /// # Safety
+    ///
+    /// `mapped` must point to writable memory large enough to hold a
+    /// [`RawMemPipe`] which nobody else is accessing yet
+    unsafe fn init(mapped: *mut RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>,
+            uid: u64) {
+        addr_of_mut!((*mapped).magic).write(MEMPIPE_MAGIC);
+        addr_of_mut!((*mapped).chunk_size).write(CHUNK_SIZE as u64);
+        addr_of_mut!((*mapped).num_buffers).write(NUM_BUFFERS as u64);
+        addr_of_mut!((*mapped).usize_size)
+            .write(size_of::<usize>() as u64);
+        addr_of_mut!((*mapped).uid).write(uid);
+        addr_of_mut!((*mapped).client_owned)
+            .write([const { AtomicBool::new(false) }; NUM_BUFFERS]);
+        addr_of_mut!((*mapped).client_len)
+            .write([const { AtomicUsize::new(0) }; NUM_BUFFERS]);
+        addr_of_mut!((*mapped).client_seq)
+            .write([const { AtomicU64::new(0) }; NUM_BUFFERS]);
+        addr_of_mut!((*mapped).cur_seq).write(AtomicU64::new(0));
+
+        // Chunks are left uninitialized, which is okay as they are marked
+        // as [`MaybeUninit`]
+    }
+
+    /// Get the file descriptor for pipes created with
+    /// [`SendPipe::create_memfd`]. This remains owned by the pipe, and is
+    /// closed when the pipe is dropped
+    #[cfg(target_family = "unix")]
+    pub fn fd(&self) -> Option<i32> {
+        self.memfd
     }
 
     /// Get the raw backing memory pointer for the pipe
@@ -291,31 +365,52 @@ impl<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize>
     #[inline]
     pub fn alloc_buffer(&mut self, blocking: bool)
             -> ChunkWriter<CHUNK_SIZE, NUM_BUFFERS> {
-        // Get the pipe
-        let pipe = unsafe { &*self.mem_pipe };
-
         // Outer loop, look through buffers forever
         loop {
-            // Check all buffers
-            for ii in 0..NUM_BUFFERS {
-                // See if this buffer is available for use (not client owned)
-                if !pipe.client_owned[ii].load(Ordering::Acquire) {
-                    // Woo, we own this buffer, return it!
-                    return ChunkWriter {
-                        mem_pipe: pipe,
-                        idx:      ii,
-                        written:  0,
-                        blocking,
-
-                        // Construct a raw pointer to the first byte
-                        bytes: UnsafeCell::raw_get(
-                            pipe.chunks[ii].0[0].as_ptr()
-                        ),
-                    };
-                }
+            if let Some(idx) = self.free_buffer() {
+                return self.writer(idx, blocking);
             }
         }
     }
+
+    /// Attempt to get a buffer like [`SendPipe::alloc_buffer`], returning
+    /// `None` rather than waiting if all buffers are owned by the receiver
+    #[inline]
+    pub fn try_alloc_buffer(&mut self, blocking: bool)
+            -> Option<ChunkWriter<CHUNK_SIZE, NUM_BUFFERS>> {
+        self.free_buffer().map(|idx| self.writer(idx, blocking))
+    }
+
+    /// Find a buffer which is available for use
+    #[inline]
+    fn free_buffer(&self) -> Option<usize> {
+        // Get the pipe
+        let pipe = unsafe { &*self.mem_pipe };
+
+        // Check all buffers, looking for one which is not client owned
+        (0..NUM_BUFFERS).find(|&ii| {
+            !pipe.client_owned[ii].load(Ordering::Acquire)
+        })
+    }
+
+    /// Create a writer for the buffer at `idx`, which must be available
+    #[inline]
+    fn writer(&mut self, idx: usize, blocking: bool)
+            -> ChunkWriter<CHUNK_SIZE, NUM_BUFFERS> {
+        // Get the pipe
+        let pipe = unsafe { &*self.mem_pipe };
+
+        // Woo, we own this buffer, return it!
+        ChunkWriter {
+            mem_pipe: pipe,
+            idx,
+            written:  0,
+            blocking,
+
+            // Construct a raw pointer to the first byte
+            bytes: UnsafeCell::raw_get(pipe.chunks[idx].0[0].as_ptr()),
+        }
+    }
 }
 
 impl<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize>
@@ -323,10 +418,15 @@ impl<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize>
     #[cfg(target_family = "unix")]
     fn drop(&mut self) {
         unsafe {
-            // Delete the file we created
-            let cs = filename_from_uid(self.uid)
-                .expect("Failed to get filename for pipe");
-            libc::shm_unlink(cs.as_ptr());
+            if let Some(fd) = self.memfd {
+                // Anonymous memory has no name, we just close our handle
+                libc::close(fd);
+            } else {
+                // Delete the file we created
+                let cs = filename_from_uid(self.uid)
+                    .expect("Failed to get filename for pipe");
+                libc::shm_unlink(cs.as_ptr());
+            }
 
             // Unmap the memory we mapped
             assert!(libc::munmap(self.mem_pipe as *mut _,
@@ -442,6 +542,13 @@ impl<'a, const CHUNK_SIZE: usize, const NUM_BUFFERS: usize> Drop for
 /// operation
 pub struct Ticket(u64);
 
+impl Ticket {
+    /// Get the sequence number of the buffer this ticket will receive
+    pub fn seq(&self) -> u64 {
+        self.0
+    }
+}
+
 /// The receiving side of a pipe, this will allow you to read sequenced data
 /// as it was sent from a `SendPipe`
 pub struct RecvPipe<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize> {
@@ -481,15 +588,41 @@ impl<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize>
         // This also gives us the lowest possible chance of leaking the shm
         unsafe { libc::shm_unlink(cs.as_ptr()); }
 
+        // Validate and map the pipe
+        Self::map_existing(shm, uid)
+    }
+
+    /// Open a pipe from a file descriptor to its backing memory, as created
+    /// by [`SendPipe::create_memfd`] and passed to us by the sender. `uid`
+    /// must match [`SendPipe::uid`] of the sender.
+    ///
+    /// This takes ownership of `fd`, it is closed before returning
+    #[cfg(target_family = "unix")]
+    pub fn open_fd(fd: i32, uid: u64) -> Result<Self> {
+        // Make sure settings are sane
+        if NUM_BUFFERS == 0 || CHUNK_SIZE == 0 {
+            unsafe { libc::close(fd); }
+            return Err(Error::InvalidPipeConfiguration);
+        }
+
+        // Validate and map the pipe
+        Self::map_existing(fd, uid)
+    }
+
+    /// Make sure the pipe in the shared memory file `fd` matches our
+    /// configuration and `uid`, and map it. This closes `fd`
+    #[cfg(target_family = "unix")]
+    fn map_existing(fd: i32, uid: u64) -> Result<Self> {
         // Read the configuration of the chunk
         unsafe {
             let mut tmp = [0u64; 5];
 
             // Read the header, which we can use to verify this pipe matches
-            // what we expect
-            if libc::read(shm, tmp.as_mut_ptr() as *mut _,
-                    size_of_val(&tmp)) as usize != size_of_val(&tmp) {
-                libc::close(shm);
+            // what we expect. We use `pread` as the file offset may be shared
+            // with the sender if the FD was passed to us.
+            if libc::pread(fd, tmp.as_mut_ptr() as *mut _,
+                    size_of_val(&tmp), 0) as usize != size_of_val(&tmp) {
+                libc::close(fd);
                 return Err(Error::PipeMismatch);
             }
 
@@ -499,7 +632,7 @@ impl<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize>
                     tmp[2] != CHUNK_SIZE  as u64 ||
                     tmp[3] != NUM_BUFFERS as u64 ||
                     tmp[4] != uid {
-                libc::close(shm);
+                libc::close(fd);
                 return Err(Error::PipeMismatch);
             }
         }
@@ -509,12 +642,12 @@ impl<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize>
             libc::mmap(core::ptr::null_mut(),
                 size_of::<RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>>(),
                 libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
-                shm, 0) as *mut RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>
+                fd, 0) as *mut RawMemPipe<CHUNK_SIZE, NUM_BUFFERS>
         };
         let map_err = errno();
 
         // Close the FD we opened
-        unsafe { libc::close(shm); }
+        unsafe { libc::close(fd); }
 
         // Make sure mapping as successful
         if mapped as usize == libc::MAP_FAILED as usize {
@@ -551,6 +684,17 @@ impl<const CHUNK_SIZE: usize, const NUM_BUFFERS: usize>
         Ticket(self.seq.fetch_add(1, Ordering::Relaxed))
     }
 
+    /// Get the number of buffers the sender has sent so far
+    ///
+    /// Once the sender is done sending, every [`Ticket`] with a sequence
+    /// number below this has a buffer waiting for it, which can be used to
+    /// drain the pipe
+    pub fn sent(&self) -> u64 {
+        // Get the pipe
+        let pipe = unsafe { &*self.mem_pipe };
+        pipe.cur_seq.load(Ordering::Acquire)
+    }
+
     /// Attempt to receive data from the pipe, invoking the closure only if
     /// data was ready
     ///
@@ -651,6 +795,30 @@ fn pipe_config() -> Result<()> {
     RecvPipe::<1, 2>::open(pipe.uid()).map(|_| ())
 }
 
+#[test]
+#[cfg(target_os = "linux")]
+fn memfd_config() -> Result<()> {
+    // The memfd must be `dup()`ed as opening it consumes the FD, just like
+    // receiving it over a socket would give us our own copy
+    let dup = |pipe: &SendPipe<1, 2>| unsafe { libc::dup(pipe.fd().unwrap()) };
+
+    // We should fail to attach with a different UID
+    let pipe = SendPipe::<1, 2>::create_memfd()?;
+    assert!(matches!(RecvPipe::<1, 2>::open_fd(dup(&pipe), !pipe.uid()),
+        Err(Error::PipeMismatch)),
+        "Whoa, was able to attach to a pipe with the wrong UID");
+
+    // We should fail to attach with a mismatched size
+    let pipe = SendPipe::<1, 2>::create_memfd()?;
+    assert!(matches!(RecvPipe::<3, 2>::open_fd(dup(&pipe), pipe.uid()),
+        Err(Error::PipeMismatch)),
+        "Whoa, was able to attach to a pipe with the wrong size");
+
+    // The correct settings should work
+    let pipe = SendPipe::<1, 2>::create_memfd()?;
+    RecvPipe::<1, 2>::open_fd(dup(&pipe), pipe.uid()).map(|_| ())
+}
+
 #[test]
 fn large_stack_config() -> Result<()> {
     let _pipe = SendPipe::< { 1024 * 1024 * 1024 }, 2>::create()?;